@@ -85,6 +85,37 @@ class QsMeasurement {
         return samples
     }
     
+    public func info() -> QsMeasurementInfo? {
+        var info = QsMeasurementInfo()
+        guard qs_measurement_info(self.rs_id, &info) else {
+            LOGGER.error("Failed to get info for QsMeasurement \(self.rs_id)")
+            LOGGER.error("QS_LIB error message: \(String(describing: QS_LIB.getError()))")
+            return nil
+        }
+        return info
+    }
+
+    public func calibration(channel: UInt8) -> QsCalibration? {
+        var calibration = QsCalibration()
+        guard qs_get_calibration(self.rs_id, channel, &calibration) else {
+            // Channels without a calibration are reported in raw counts
+            _ = QS_LIB.getError()
+            return nil
+        }
+        return calibration
+    }
+
+    public func accelCalibration(channel: UInt8) -> ([UInt8], QsAccelCalibration)? {
+        var channels = [UInt8](repeating: 0, count: 3)
+        var calibration = QsAccelCalibration()
+        guard qs_get_accel_calibration(self.rs_id, channel, &channels, &calibration) else {
+            // Channels outside an accelerometer calibration are described by calibration(channel:)
+            _ = QS_LIB.getError()
+            return nil
+        }
+        return (channels, calibration)
+    }
+
     public func interpretTimestamps(hz: Float32, rateScaler: Float32, targetCardinality: UInt64?) -> (UInt32, [Double])? {
         LOGGER.trace("Interpretting timestamps for QsMeasurement \(self.rs_id) with \(hz) Hz and \(rateScaler) scaler")
         
@@ -101,7 +132,10 @@ class QsMeasurement {
             downsampleThreshold = UInt32(UInt64(downsampleScale) * targetCardinality / samples)
         }
         
-        let bufSize = UInt32(min(UInt64(pow(2, ceil(log(Double(self.sampleCount)) / log(2)))), UInt64(UINT32_MAX)))
+        guard let info = self.info() else {
+            return nil
+        }
+        let bufSize = max(info.samples_per_channel, 1)
         let timestamps = UnsafeMutablePointer<Double>.allocate(capacity: Int(bufSize))
         let numTimestamps = UnsafeMutablePointer<UInt32>.allocate(capacity: 1)
         numTimestamps[0] = bufSize
@@ -143,7 +177,10 @@ class QsMeasurement {
             downsampleThreshold = UInt32(UInt64(downsampleScale) * targetCardinality / samples)
         }
         
-        let bufSize = UInt32(min(UInt64(pow(2, ceil(log(Double(self.sampleCount)) / log(2)))), UInt64(UINT32_MAX)))
+        guard let info = self.info() else {
+            return nil
+        }
+        let bufSize = max(info.samples_per_channel, 1)
         
         let channelData = UnsafeMutablePointer<UnsafeMutablePointer<Double>?>.allocate(capacity: Int(self.signalChannels))
         for i in 0..<self.signalChannels {
@@ -181,6 +218,58 @@ class QsMeasurement {
         }
     }
     
+    public func exportSignals(channelMask: UInt32, targetCardinality: UInt64?) -> (UInt32, [[Double]])? {
+        LOGGER.trace("Exporting signals \(channelMask) for QsMeasurement \(self.rs_id)")
+
+        guard self.sampleCount < UINT32_MAX else {
+            LOGGER.error("Sample count too large to export signals")
+            return nil
+        }
+
+        var options = QsExportOptions()
+        qs_default_export_options(&options)
+        if let targetCardinality = targetCardinality {
+            let samples = self.sampleCount > targetCardinality ? self.sampleCount : targetCardinality
+            options.downsample_seed = 0xDEADBEEF
+            options.downsample_scale = 1024 * 1024
+            options.downsample_threshold = UInt32(UInt64(options.downsample_scale) * targetCardinality / samples)
+        }
+
+        guard let info = self.info() else {
+            return nil
+        }
+        let bufSize = max(info.samples_per_channel, 1)
+
+        // One buffer for each selected channel, physical or derived
+        let numChannels = channelMask.nonzeroBitCount
+        let channelData = UnsafeMutablePointer<UnsafeMutableRawPointer?>.allocate(capacity: max(numChannels, 1))
+        for i in 0..<numChannels {
+            channelData[i] = UnsafeMutableRawPointer(UnsafeMutablePointer<Double>.allocate(capacity: Int(bufSize)))
+        }
+        let numSamplesPerChannel = UnsafeMutablePointer<UInt32>.allocate(capacity: 1)
+        numSamplesPerChannel[0] = bufSize
+        defer {
+            for i in 0..<numChannels {
+                channelData[i]!.deallocate()
+            }
+            channelData.deallocate()
+            numSamplesPerChannel.deallocate()
+        }
+
+        let success = qs_export_signals(self.rs_id, channelMask, UInt8(QS_SAMPLE_TYPE_F64.rawValue), UInt8(QS_LAYOUT_PLANAR.rawValue), &options, channelData, numSamplesPerChannel)
+        guard success else {
+            LOGGER.error("Failed to export signals for QsMeasurement \(self.rs_id)")
+            LOGGER.error("QS_LIB error message: \(String(describing: QS_LIB.getError()))")
+            return nil
+        }
+
+        let num = numSamplesPerChannel[0]
+        let channels: [[Double]] = (0..<numChannels).map { i in
+            [Double](UnsafeBufferPointer(start: channelData[i]!.assumingMemoryBound(to: Double.self), count: Int(num)))
+        }
+        return (num, channels)
+    }
+
     public func archive(hz: Float, rateScaler: Float) -> URL? {
         LOGGER.debug("Archiving QsMeasurement \(self.rs_id) ...")
         
@@ -215,6 +304,36 @@ class QsMeasurement {
         try? archive.addEntry(with: "channels.csv", type: .file, uncompressedSize: UInt32(uncompressedData.count), modificationDate: Date(), permissions: nil, compressionMethod: .deflate, bufferSize: 4096, provider: { (position, size) -> Data in
             uncompressedData.subdata(in: position..<position+size)
         })
+
+        // Describe how to convert the raw counts of each channel into physical units. Accelerometer
+        // axes are scaled by their gain and offset, then y += yx * x and z += zx * x + zy * y with the
+        // misalignment of the listed channels.
+        let calibrationCsv = "Channel,Unit,Gain,Offset,Coefficients,AccelChannels,Misalignment\n" + (0..<archivableSignals.count)
+            .map { channel in
+                if let (channels, accel) = self.accelCalibration(channel: UInt8(channel)) {
+                    let axis = channels.firstIndex(of: UInt8(channel))!
+                    let gain = withUnsafeBytes(of: accel.gain) { Array($0.bindMemory(to: Double.self)) }[axis]
+                    let offset = withUnsafeBytes(of: accel.offset) { Array($0.bindMemory(to: Double.self)) }[axis]
+                    let misalignment = withUnsafeBytes(of: accel.misalignment) {
+                        $0.bindMemory(to: Double.self).map { String($0) }.joined(separator: " ")
+                    }
+                    let axes = channels.map { "Channel\($0)" }.joined(separator: " ")
+                    return "Channel\(channel),g,\(gain),\(-gain * offset),,\(axes),\(misalignment)"
+                }
+                guard let calibration = self.calibration(channel: UInt8(channel)) else {
+                    return "Channel\(channel),counts,1,0,,,"
+                }
+                let unit = withUnsafeBytes(of: calibration.unit) { String(cString: $0.bindMemory(to: CChar.self).baseAddress!) }
+                let coefficients = withUnsafeBytes(of: calibration.coefficients) {
+                    $0.bindMemory(to: Double.self).prefix(Int(calibration.num_coefficients)).map { String($0) }.joined(separator: " ")
+                }
+                return "Channel\(channel),\(unit),\(calibration.gain),\(calibration.offset),\(coefficients),,"
+            }
+            .joined(separator: "\n")
+        let calibrationData = calibrationCsv.data(using: String.Encoding.utf8)!
+        try? archive.addEntry(with: "calibration.csv", type: .file, uncompressedSize: UInt32(calibrationData.count), modificationDate: Date(), permissions: nil, compressionMethod: .deflate, bufferSize: 4096, provider: { (position, size) -> Data in
+            calibrationData.subdata(in: position..<position+size)
+        })
         
         
         // Write the zip to a temporary file
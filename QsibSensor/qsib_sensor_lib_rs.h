@@ -29,6 +29,20 @@ void qs_errors_drop(const char *free_me_please);
 uint32_t qs_create_measurement(uint8_t signal_channels);
 bool qs_drop_measurement(uint32_t measurement_id);
 
+/*!
+ * Configures the nominal sampling rate of a measurement, matching the
+ * arguments given to qs_interpret_timestamps. Processing and analysis
+ * that work in Hz require the rate to be set. Changing the rate rebuilds any
+ * triggers for the new rate, discarding their captures in progress, and fails
+ * without changing the rate when a trigger band does not fit below its Nyquist rate.
+ *
+ * @param[in] hz          The rate of sampling in Hz (1 second period)
+ * @param[in] rate_scaler The multiplier on the Hz period (ie 1 second * rate_scaler)
+ *
+ * @return success or failure
+ */
+bool qs_set_sample_rate(uint32_t measurement_id, float hz, float rate_scaler);
+
 /*!
  * Ingests a signal notification from a QSIB sensor
  * with validity checks. Failures are due to invalid
@@ -43,6 +57,32 @@ bool qs_drop_measurement(uint32_t measurement_id);
  */
 uint32_t qs_add_signals(uint32_t measurement_id, const uint8_t *buf, uint16_t len);
 
+typedef struct {
+    uint32_t payloads;
+    uint32_t samples_per_channel;
+    uint8_t active_channels;
+    uint64_t first_counter;
+    uint64_t last_counter;
+    uint32_t gaps;
+    uint32_t duplicates_dropped;
+    uint64_t bytes_used;
+} QsMeasurementInfo;
+
+/*!
+ * Describes the payloads currently held by a measurement so that
+ * callers can allocate exact buffers for timestamps and signals.
+ *
+ * Gaps are the number of discontinuities in the notification counter
+ * sequence. Duplicate notifications are dropped during ingestion.
+ *
+ * Similarly thread-safe to measurement allocation.
+ *
+ * @param[out] info The summary of the measurement
+ *
+ * @return success or failure
+ */
+bool qs_measurement_info(uint32_t measurement_id, QsMeasurementInfo *info);
+
 /*!
  * Using the input sampling rate, we infer using the
  * notification counters and known samples per payload
@@ -71,6 +111,8 @@ bool qs_interpret_timestamps(uint32_t measurement_id, float hz, float rate_scale
 
 /*!
  * Places each channel's data in continguous buffers and updates the examct number of samples per channel.
+ * Only the active physical channels are copied, so channel_data needs one buffer for each of the
+ * signal channels of the measurement. Derived channels are exported with qs_export_signals.
  *
  * Error messages may be popped with the error
  * messaging API with a limit of 16 pending messages.
@@ -87,3 +129,1159 @@ bool qs_interpret_timestamps(uint32_t measurement_id, float hz, float rate_scale
  * @return success or failure
  */
 bool qs_copy_signals(uint32_t measurement_id, uint64_t downsample_seed, uint32_t downsample_threshold, uint32_t downsample_scale, double **channel_data, uint32_t *num_samples_per_channel);
+
+typedef enum {
+    QS_SAMPLE_TYPE_I16 = 0,
+    QS_SAMPLE_TYPE_F32 = 1,
+    QS_SAMPLE_TYPE_F64 = 2,
+} QsSampleType;
+
+typedef enum {
+    QS_LAYOUT_PLANAR = 0,
+    QS_LAYOUT_INTERLEAVED = 1,
+} QsLayout;
+
+typedef enum {
+    QS_DESPIKE_METHOD_HAMPEL = 0,
+    QS_DESPIKE_METHOD_RUNNING_MEDIAN = 1,
+} QsDespikeMethod;
+
+/*!
+ * Spike removal where each sample is compared with the median of the half_window
+ * samples on either side and replaced by that median when it is too far away.
+ * Hampel filtering allows threshold scaled median absolute deviations of the window,
+ * running median replacement allows threshold counts. Hampel filtering never replaces
+ * samples within min_deviation of the median, as flat quantized stretches have no deviation.
+ */
+typedef struct {
+    uint8_t method;
+    uint32_t half_window;
+    double threshold;
+    double min_deviation;
+} QsDespikeConfig;
+
+/*!
+ * Fills the configuration for Hampel filtering over 3 samples on either side at 3 deviations,
+ * replacing only samples more than 1 count from the median.
+ */
+bool qs_default_despike_config(QsDespikeConfig *config);
+
+/*!
+ * Optional processing and downsampling of a signal export.
+ *
+ * Downsampling draws the same random values as the timestamp interpretation, keeping
+ * samples whose draw mod downsample_scale is at most downsample_threshold. The pipeline
+ * with pipeline_id is applied from a fresh state for this export only, 0 for none.
+ * Calibrated exports convert channels with a calibration to physical units before any
+ * processing. Despiking with despike_config is applied to the samples first when despike
+ * is set, and num_despiked, when not NULL, receives the number of samples replaced in
+ * each exported channel in ascending channel order.
+ */
+typedef struct {
+    uint64_t downsample_seed;
+    uint32_t downsample_threshold;
+    uint32_t downsample_scale;
+    uint32_t pipeline_id;
+    bool calibrated;
+    bool despike;
+    QsDespikeConfig despike_config;
+    uint32_t *num_despiked;
+} QsExportOptions;
+
+/*!
+ * Fills the options for exporting every sample in raw counts without an extra
+ * pipeline or despiking, with the default despike_config ready to enable.
+ */
+bool qs_default_export_options(QsExportOptions *options);
+
+/*!
+ * Copies a subset of channels into caller buffers using the requested sample type and layout.
+ *
+ * Channels are processed by the pipeline attached to the measurement, if any,
+ * and then by the pipeline of the options before being downsampled.
+ * Despiking is applied to the samples first, and the attached pipeline is then
+ * rerun from a fresh state on the despiked samples.
+ *
+ * With a planar layout, channel_data holds one buffer per selected channel in ascending channel order.
+ * With an interleaved layout, channel_data[0] is a single row-major buffer where each
+ * sample is a row holding the selected channels in ascending channel order.
+ *
+ * Error messages may be popped with the error
+ * messaging API with a limit of 16 pending messages.
+ *
+ * Similarly thread-safe to measurement allocation.
+ *
+ * @param[in] channel_mask The bitmask of channels to export, bit 0 is channel 0
+ * @param[in] sample_type The QsSampleType written into the buffers
+ * @param[in] layout The QsLayout of the buffers
+ * @param[in] options The downsampling and processing options or NULL for the defaults
+ * @param[out] channel_data The buffers as described by the layout
+ * @param[in|out] num_samples_per_channel The number of samples that each channel has in the buffer. (Capacity before call, Actual number after)
+ *
+ * @return success or failure
+ */
+bool qs_export_signals(uint32_t measurement_id, uint32_t channel_mask, uint8_t sample_type, uint8_t layout, const QsExportOptions *options, void **channel_data, uint32_t *num_samples_per_channel);
+
+typedef enum {
+    QS_FILTER_LOW_PASS = 0,
+    QS_FILTER_HIGH_PASS = 1,
+    QS_FILTER_BAND_PASS = 2,
+    QS_FILTER_NOTCH = 3,
+} QsFilterKind;
+
+/*!
+ * Create or drop a processing pipeline that is tracked by the
+ * uint32_t id produced during creation. Ids start at 1 so that
+ * 0 may be passed wherever a pipeline is optional.
+ *
+ * Interactions with pipelines are threadsafe.
+ */
+uint32_t qs_create_pipeline();
+bool qs_drop_pipeline(uint32_t pipeline_id);
+
+/*!
+ * Appends a biquad section to the channels selected by the mask.
+ * Coefficients are designed with the sample rate of the measurement
+ * the pipeline is applied to. Band-pass and notch sections use the
+ * cutoff as their center frequency.
+ *
+ * @param[in] channel_mask The bitmask of channels to filter, bit 0 is channel 0
+ * @param[in] kind The QsFilterKind of the section
+ * @param[in] cutoff_hz The cutoff or center frequency in Hz
+ * @param[in] q The quality factor, 0.707 gives a Butterworth response
+ *
+ * @return success or failure
+ */
+bool qs_pipeline_add_filter(uint32_t pipeline_id, uint32_t channel_mask, uint8_t kind, float cutoff_hz, float q);
+
+/*!
+ * Attaches a copy of a pipeline to a measurement so that every export of
+ * the measurement is processed by it. Filter state is kept between exports
+ * so that samples are only processed once as the measurement grows, and the
+ * result matches processing the whole measurement in one pass. Payloads
+ * arriving out of order restart processing from the first sample. Detrend and
+ * denoise stages need the whole series, so they and any stages added after them
+ * are rerun over the whole series each time. Stages added to the pipeline after it
+ * is attached are picked up on the next export, which restarts processing, while
+ * dropping the pipeline leaves the attached copy in place.
+ *
+ * @param[in] pipeline_id The pipeline to attach or 0 to detach
+ *
+ * @return success or failure
+ */
+bool qs_attach_pipeline(uint32_t measurement_id, uint32_t pipeline_id);
+
+/*!
+ * Adds a virtual channel computed from the calibrated physical channels of a measurement.
+ * Derived channels are numbered from 8 in the order they are added, up to 8 per
+ * measurement, and may be selected anywhere a channel number or channel_mask is
+ * accepted except qs_copy_signals. They are computed
+ * when read so they stay consistent as payloads arrive and calibrations change.
+ * Their values are already in physical units, so exports that skip calibration
+ * only skip a calibration set on the derived channel itself.
+ *
+ * Magnitude is the euclidean norm of the channels in channel_mask, such as the
+ * magnitude of a 3-axis accelerometer. Sum weights physical channel i by weights[i].
+ * Difference subtracts the negative channel from the positive channel.
+ *
+ * @return the new channel number or 0 on failure
+ */
+uint8_t qs_add_derived_magnitude(uint32_t measurement_id, uint32_t channel_mask);
+uint8_t qs_add_derived_sum(uint32_t measurement_id, const double *weights, uint8_t num_weights);
+uint8_t qs_add_derived_difference(uint32_t measurement_id, uint8_t positive, uint8_t negative);
+
+/*!
+ * Removes all derived channels from a measurement so they may be numbered from 8 again
+ *
+ * @return success or failure
+ */
+bool qs_clear_derived(uint32_t measurement_id);
+
+typedef enum {
+    QS_WINDOW_RECTANGULAR = 0,
+    QS_WINDOW_HANN = 1,
+    QS_WINDOW_HAMMING = 2,
+    QS_WINDOW_BLACKMAN = 3,
+} QsWindow;
+
+/*!
+ * Analysis routines operate on one channel of a measurement over the time range
+ * [start_s, end_s) in seconds, where the first sample is at 0 seconds using the
+ * rate from qs_set_sample_rate. Pass INFINITY as end_s to include every sample.
+ * Channels are processed by the attached pipeline, if any, before analysis.
+ *
+ * Results are written into caller buffers where the length argument is the
+ * capacity before the call and the number of results after. When the buffers
+ * are too small the call fails and the length is set to the required capacity.
+ *
+ * Error messages may be popped with the error
+ * messaging API with a limit of 16 pending messages.
+ */
+
+/*!
+ * Computes the one-sided power spectral density in units^2/Hz with Welch's method,
+ * averaging the periodograms of mean-removed and windowed segments.
+ *
+ * @param[in] window The QsWindow applied to each segment
+ * @param[in] segment_length The number of samples per segment, a power of two
+ * @param[in] overlap The number of samples shared by consecutive segments
+ * @param[out] frequencies The frequency of each bin in Hz
+ * @param[out] power The power spectral density of each bin
+ * @param[in|out] num_bins The number of bins, segment_length / 2 + 1 on success
+ *
+ * @return success or failure
+ */
+bool qs_welch_psd(uint32_t measurement_id, uint8_t channel, double start_s, double end_s, uint8_t window, uint32_t segment_length, uint32_t overlap, double *frequencies, double *power, uint32_t *num_bins);
+
+/*!
+ * Computes the single-sided amplitude spectrum with one FFT of the first
+ * fft_length samples in the time range, zero padding shorter ranges.
+ *
+ * @param[in] window The QsWindow applied before the FFT
+ * @param[in] fft_length The FFT length, a power of two up to 65536
+ * @param[out] frequencies The frequency of each bin in Hz
+ * @param[out] amplitude The amplitude of each bin in the channel's units
+ * @param[in|out] num_bins The number of bins, fft_length / 2 + 1 on success
+ *
+ * @return success or failure
+ */
+bool qs_amplitude_spectrum(uint32_t measurement_id, uint8_t channel, double start_s, double end_s, uint8_t window, uint32_t fft_length, double *frequencies, double *amplitude, uint32_t *num_bins);
+
+/*!
+ * Computes a spectrogram as the magnitude of a short-time Fourier transform.
+ * Frames start every hop_length samples and are timestamped at their centers.
+ * With num_mels > 0 the FFT bins are combined by triangular filters spaced
+ * evenly on the mel scale up to Nyquist, reported at their center frequencies.
+ *
+ * @param[in] window The QsWindow applied to each frame
+ * @param[in] window_length The number of samples per frame, a power of two
+ * @param[in] hop_length The number of samples between consecutive frames
+ * @param[in] num_mels The number of mel bands or 0 for linear FFT bins
+ * @param[in] decibels Whether magnitudes are reported as 20 * log10(magnitude)
+ * @param[out] times The time of each frame in seconds
+ * @param[out] frequencies The frequency of each bin in Hz
+ * @param[out] magnitudes The row-major [frame][bin] matrix, holding at least num_frames * num_bins capacity
+ * @param[in|out] num_frames The number of frames
+ * @param[in|out] num_bins The number of bins per frame, which is also the row stride of magnitudes
+ *
+ * @return success or failure
+ */
+bool qs_spectrogram(uint32_t measurement_id, uint8_t channel, double start_s, double end_s, uint8_t window, uint32_t window_length, uint32_t hop_length, uint32_t num_mels, bool decibels, double *times, double *frequencies, double *magnitudes, uint32_t *num_frames, uint32_t *num_bins);
+
+typedef struct {
+    double start_s;
+    double mean;
+    double rms;
+    double min;
+    double max;
+    double std_dev;
+    double zero_crossing_rate;
+    uint32_t samples;
+} QsWindowStats;
+
+/*!
+ * Enables statistics over consecutive windows of window_samples samples
+ * that are updated as payloads are added, in the order they arrive.
+ * Passing 0 disables live statistics.
+ *
+ * Live statistics summarize the raw counts of the physical channels, ignoring
+ * calibrations and pipelines, and windows follow the arrival order rather than
+ * the counter order. Use qs_window_stats for calibrated and processed values.
+ *
+ * @return success or failure
+ */
+bool qs_enable_live_stats(uint32_t measurement_id, uint32_t window_samples);
+
+/*!
+ * Reads the statistics of the most recently completed live window of a channel,
+ * in raw counts. The window start is NaN when the sample rate is not set. Zero-crossing
+ * rate is the fraction of consecutive sample pairs that change sign.
+ *
+ * @param[out] stats The latest window statistics
+ *
+ * @return success or failure, failing until the first window completes
+ */
+bool qs_live_stats(uint32_t measurement_id, uint8_t channel, QsWindowStats *stats);
+
+/*!
+ * Computes statistics over consecutive non-overlapping windows of window_s seconds
+ * within the time range, dropping a trailing partial window.
+ *
+ * @param[in] window_s The window length in seconds
+ * @param[out] stats The statistics of each window
+ * @param[in|out] num_windows The number of windows
+ *
+ * @return success or failure
+ */
+bool qs_window_stats(uint32_t measurement_id, uint8_t channel, double start_s, double end_s, double window_s, QsWindowStats *stats, uint32_t *num_windows);
+
+typedef enum {
+    QS_ARTIFACT_CLIPPING = 0,
+    QS_ARTIFACT_FLATLINE = 1,
+    QS_ARTIFACT_NOISE = 2,
+    QS_ARTIFACT_DROPOUT = 3,
+} QsArtifactKind;
+
+typedef struct {
+    int16_t clip_low;
+    int16_t clip_high;
+    uint32_t clip_min_samples;
+    double flat_tolerance;
+    double flat_min_s;
+    double noise_window_s;
+    double noise_threshold;
+} QsArtifactThresholds;
+
+typedef struct {
+    double start_s;
+    double end_s;
+    double value;
+    uint8_t kind;
+    uint8_t channel;
+} QsArtifact;
+
+/*!
+ * Fills the thresholds used when none are given to qs_detect_artifacts,
+ * so callers may adjust only the limits that differ for a deployment.
+ */
+bool qs_default_artifact_thresholds(QsArtifactThresholds *thresholds);
+
+/*!
+ * Flags quality problems in the raw ADC counts of a channel as time intervals,
+ * sorted by start time. Attached pipelines are not applied.
+ *
+ * - Clipping: at least clip_min_samples consecutive samples <= clip_low or >= clip_high,
+ *   value is the number of samples
+ * - Flatline: at least flat_min_s seconds of samples within flat_tolerance of each other,
+ *   value is the duration in seconds
+ * - Noise: consecutive windows of noise_window_s where the RMS of the sample to sample
+ *   difference exceeds noise_threshold, value is the largest RMS difference
+ * - Dropout: missing notifications, reported as an empty interval where the
+ *   payloads are joined, value is the number of missing payloads
+ *
+ * @param[in] thresholds The limits for flagging artifacts or NULL for the defaults
+ * @param[out] artifacts The flagged intervals
+ * @param[in|out] num_artifacts The number of artifacts
+ *
+ * @return success or failure
+ */
+bool qs_detect_artifacts(uint32_t measurement_id, uint8_t channel, double start_s, double end_s, const QsArtifactThresholds *thresholds, QsArtifact *artifacts, uint32_t *num_artifacts);
+
+typedef struct {
+    double window_s;
+    double signal_low_hz;
+    double signal_high_hz;
+    double motion_high_hz;
+    double snr_good_db;
+    double kurtosis_max;
+} QsQualityConfig;
+
+typedef struct {
+    double start_s;
+    double snr_db;
+    double kurtosis;
+    double motion_energy;
+    double gap_fraction;
+    double score;
+} QsQualityWindow;
+
+/*!
+ * Fills the configuration used when none is given to qs_signal_quality.
+ */
+bool qs_default_quality_config(QsQualityConfig *config);
+
+/*!
+ * Scores consecutive non-overlapping windows of window_s seconds within the time range.
+ *
+ * - snr_db: power within [signal_low_hz, signal_high_hz] against all other non-DC power
+ * - kurtosis: fourth standardized moment, 3 for Gaussian noise and 1.5 for a sinusoid
+ * - motion_energy: fraction of non-DC power at or below motion_high_hz
+ * - gap_fraction: fraction of the window's expected samples lost to dropped notifications
+ *
+ * The score is the product of min(snr_db / snr_good_db, 1) clamped at 0,
+ * min(kurtosis_max / kurtosis, 1), 1 - motion_energy and 1 - gap_fraction.
+ *
+ * @param[in] config The quality settings or NULL for the defaults
+ * @param[out] windows The quality of each window
+ * @param[in|out] num_windows The number of windows
+ *
+ * @return success or failure
+ */
+bool qs_signal_quality(uint32_t measurement_id, uint8_t channel, double start_s, double end_s, const QsQualityConfig *config, QsQualityWindow *windows, uint32_t *num_windows);
+
+typedef struct {
+    double band_low_hz;
+    double band_high_hz;
+    double envelope_s;
+    double refractory_s;
+    double threshold;
+} QsBeatConfig;
+
+typedef struct {
+    uint32_t beats;
+    double mean_hr_bpm;
+    double sdnn_ms;
+    double rmssd_ms;
+} QsHeartRateSummary;
+
+/*!
+ * Fills the configuration used when none is given to qs_detect_beats.
+ */
+bool qs_default_beat_config(QsBeatConfig *config);
+
+/*!
+ * Detects heart beats on an ECG or seismocardiogram channel within the time range.
+ *
+ * The channel is band-passed without phase shift, squared and smoothed over envelope_s
+ * seconds. Envelope peaks above a threshold placed between running noise and signal
+ * peak levels are beats, keeping the largest peak within refractory_s seconds.
+ *
+ * The summary is written even when the buffers are too small so that
+ * num_beats may be used to size them. Fewer than 3 beats are too few to
+ * summarize, so the call fails after writing the beats found and leaves the
+ * summary unchanged.
+ *
+ * @param[in] config The detection settings or NULL for the defaults
+ * @param[out] beat_times The time of each beat in seconds
+ * @param[out] heart_rate_bpm The instantaneous rate from the previous beat, NaN for the first beat
+ * @param[in|out] num_beats The number of beats
+ * @param[out] summary The mean rate and the SDNN and RMSSD variability of the beat intervals
+ *
+ * @return success or failure
+ */
+bool qs_detect_beats(uint32_t measurement_id, uint8_t channel, double start_s, double end_s, const QsBeatConfig *config, double *beat_times, double *heart_rate_bpm, uint32_t *num_beats, QsHeartRateSummary *summary);
+
+typedef enum {
+    QS_RESPIRATION_METHOD_SPECTRAL = 0,
+    QS_RESPIRATION_METHOD_PEAK_COUNTING = 1,
+} QsRespirationMethod;
+
+typedef struct {
+    double window_s;
+    double step_s;
+    double low_hz;
+    double high_hz;
+    uint8_t method;
+} QsRespirationConfig;
+
+typedef struct {
+    double start_s;
+    double rate_bpm;
+    double confidence;
+} QsRespirationWindow;
+
+/*!
+ * Fills the configuration used when none is given to qs_respiration_rate.
+ */
+bool qs_default_respiration_config(QsRespirationConfig *config);
+
+/*!
+ * Estimates breaths per minute over windows of window_s seconds starting every
+ * step_s seconds within the time range, using breathing frequencies in [low_hz, high_hz].
+ *
+ * - Spectral: the strongest peak of the power spectra summed over the selected channels,
+ *   confidence is the fraction of in-band power within the main lobe of the peak
+ * - Peak counting: breaths counted on the band-passed channel with the most power,
+ *   confidence is one minus the coefficient of variation of the breath intervals
+ *
+ * Windows without breathing report a NaN rate and zero confidence.
+ *
+ * @param[in] channel_mask The bitmask of channels carrying chest motion, bit 0 is channel 0
+ * @param[in] config The estimation settings or NULL for the defaults
+ * @param[out] windows The rate and confidence of each window
+ * @param[in|out] num_windows The number of windows
+ *
+ * @return success or failure
+ */
+bool qs_respiration_rate(uint32_t measurement_id, uint32_t channel_mask, double start_s, double end_s, const QsRespirationConfig *config, QsRespirationWindow *windows, uint32_t *num_windows);
+
+typedef enum {
+    QS_EVENT_CLASS_COUGH = 0,
+    QS_EVENT_CLASS_SWALLOW = 1,
+} QsEventClass;
+
+typedef struct {
+    double band_low_hz;
+    double band_high_hz;
+    double frame_s;
+    double threshold_db;
+    double merge_gap_s;
+    double split_hz;
+    double cough_min_s;
+    double cough_max_s;
+    double swallow_min_s;
+    double swallow_max_s;
+} QsEventThresholds;
+
+typedef struct {
+    double start_s;
+    double end_s;
+    double score;
+    uint8_t class;
+} QsEvent;
+
+/*!
+ * Fills the thresholds used when none are given to qs_detect_events.
+ */
+bool qs_default_event_thresholds(QsEventThresholds *thresholds);
+
+/*!
+ * Finds cough and swallow candidates on a mechano-acoustic channel within the time range.
+ *
+ * The channel is band-passed to [band_low_hz, band_high_hz], with the upper edge limited
+ * to 0.45 of the sample rate, and split into frames of frame_s seconds. Runs of frames whose
+ * RMS is threshold_db above the median frame RMS are events, merging runs separated by
+ * less than merge_gap_s. Events with a spectral centroid at or above split_hz are coughs
+ * and the rest are swallows. Events outside the duration limits of their class are dropped.
+ *
+ * The score is 1 - median RMS / peak RMS of the event, so that louder events score closer to 1.
+ *
+ * @param[in] thresholds The detection limits or NULL for the defaults
+ * @param[out] events The events in order of start time
+ * @param[in|out] num_events The number of events
+ *
+ * @return success or failure
+ */
+bool qs_detect_events(uint32_t measurement_id, uint8_t channel, double start_s, double end_s, const QsEventThresholds *thresholds, QsEvent *events, uint32_t *num_events);
+
+typedef enum {
+    QS_ACTIVITY_LEVEL_SEDENTARY = 0,
+    QS_ACTIVITY_LEVEL_LIGHT = 1,
+    QS_ACTIVITY_LEVEL_VIGOROUS = 2,
+} QsActivityLevel;
+
+typedef enum {
+    QS_POSTURE_UPRIGHT = 0,
+    QS_POSTURE_SUPINE = 1,
+    QS_POSTURE_PRONE = 2,
+    QS_POSTURE_LEFT = 3,
+    QS_POSTURE_RIGHT = 4,
+    QS_POSTURE_UNKNOWN = 5,
+} QsPosture;
+
+/*!
+ * Maps accelerometer channels onto body axes. The vertical axis points toward the head,
+ * the lateral axis toward the patient's left and the anterior axis out of the chest.
+ * A sign of -1 marks a channel that measures the opposite direction. Channels are
+ * read in physical units, so counts_per_g is 1 for channels calibrated in g.
+ */
+typedef struct {
+    uint8_t vertical;
+    uint8_t lateral;
+    uint8_t anterior;
+    int8_t vertical_sign;
+    int8_t lateral_sign;
+    int8_t anterior_sign;
+    double counts_per_g;
+} QsAxisMapping;
+
+typedef struct {
+    double epoch_s;
+    double light_mg;
+    double vigorous_mg;
+    double step_threshold_g;
+    double step_min_interval_s;
+    double upright_angle_deg;
+} QsActivityConfig;
+
+typedef struct {
+    double start_s;
+    double enmo_mg;
+    uint32_t steps;
+    uint8_t activity;
+    uint8_t posture;
+} QsActivityEpoch;
+
+typedef struct {
+    uint32_t steps;
+    double activity_s[3];
+    double posture_s[6];
+} QsActivitySummary;
+
+/*!
+ * Fills the configuration used when none is given to qs_classify_activity.
+ */
+bool qs_default_activity_config(QsActivityConfig *config);
+
+/*!
+ * Classifies consecutive epochs of epoch_s seconds, rounded to whole samples, within the time range,
+ * dropping a trailing partial epoch.
+ *
+ * - Activity: the mean ENMO (acceleration magnitude minus 1 g, clamped at 0) is compared against light_mg and vigorous_mg
+ * - Steps: peaks of the 0.5 to 3 Hz band-passed magnitude above step_threshold_g and at least step_min_interval_s apart,
+ *   or none when the sample rate is 6 Hz or lower
+ * - Posture: upright when the mean gravity direction is within upright_angle_deg of the vertical axis,
+ *   otherwise the lying posture of the dominant anterior or lateral direction
+ *
+ * The summary totals the steps and the seconds spent at each QsActivityLevel and QsPosture
+ * over the classified epochs. It is written even when the epoch buffer is too small.
+ *
+ * @param[in] mapping The body axes of the accelerometer channels
+ * @param[in] config The classification settings or NULL for the defaults
+ * @param[out] epochs The classification of each epoch
+ * @param[in|out] num_epochs The number of epochs
+ * @param[out] summary The totals over all epochs
+ *
+ * @return success or failure
+ */
+bool qs_classify_activity(uint32_t measurement_id, const QsAxisMapping *mapping, double start_s, double end_s, const QsActivityConfig *config, QsActivityEpoch *epochs, uint32_t *num_epochs, QsActivitySummary *summary);
+
+typedef enum {
+    QS_FUSION_ALGORITHM_MADGWICK = 0,
+    QS_FUSION_ALGORITHM_MAHONY = 1,
+} QsFusionAlgorithm;
+
+typedef enum {
+    QS_ORIENTATION_OUTPUT_QUATERNION = 0,
+    QS_ORIENTATION_OUTPUT_EULER = 1,
+} QsOrientationOutput;
+
+/*!
+ * Accelerometer and gyroscope channels of the x, y and z axes and the full-scale
+ * ranges reached at the largest 16-bit count. Madgwick fusion corrects toward gravity
+ * with gain beta, Mahony fusion with proportional gain kp and integral gain ki.
+ */
+typedef struct {
+    uint8_t accel[3];
+    uint8_t gyro[3];
+    double accel_full_scale_g;
+    double gyro_full_scale_dps;
+    uint8_t algorithm;
+    double beta;
+    double kp;
+    double ki;
+} QsFusionConfig;
+
+/*!
+ * Fills the configuration used when none is given to qs_add_derived_orientation.
+ */
+bool qs_default_fusion_config(QsFusionConfig *config);
+
+/*!
+ * Adds derived channels holding the orientation fused from accelerometer and gyroscope
+ * channels at the sample rate. The orientation starts level with gravity at zero yaw.
+ *
+ * Quaternion output adds four channels holding w, x, y and z. Euler output adds three
+ * channels holding roll, pitch and yaw in degrees. The channels are NaN while the
+ * measurement has no sample rate.
+ *
+ * @param[in] config The fusion settings or NULL for the defaults
+ * @param[in] output The QsOrientationOutput to add
+ *
+ * @return the first of the new consecutive channel numbers or 0 on failure
+ */
+uint8_t qs_add_derived_orientation(uint32_t measurement_id, const QsFusionConfig *config, uint8_t output);
+
+/*!
+ * Converts one channel from raw counts to physical units as
+ * gain * raw + offset. When num_coefficients is not 0, the polynomial
+ * coefficients[0] + coefficients[1] x + coefficients[2] x^2 ... is then
+ * applied to that linear value x. The unit is a nul-terminated string.
+ */
+typedef struct {
+    double gain;
+    double offset;
+    double coefficients[6];
+    uint8_t num_coefficients;
+    char unit[16];
+} QsCalibration;
+
+/*!
+ * Creates a measurement with one calibration for each signal channel.
+ *
+ * @param[in] calibrations The signal_channels calibrations in channel order
+ *
+ * @return the measurement id or 0 on failure
+ */
+uint32_t qs_create_calibrated_measurement(uint8_t signal_channels, const QsCalibration *calibrations);
+
+/*!
+ * Sets, replaces or removes the calibration of an active physical or derived channel.
+ * The raw counts are converted before any pipeline or despiking runs. Signals are
+ * exported in physical units when qs_export_signals is asked for calibrated values,
+ * and analyses always read calibrated channels in physical units.
+ *
+ * @param[in] calibration The calibration or NULL to remove it
+ *
+ * @return success or failure
+ */
+bool qs_set_calibration(uint32_t measurement_id, uint8_t channel, const QsCalibration *calibration);
+
+/*!
+ * Copies the calibration of a channel, including its unit. Channels converted by an
+ * accelerometer calibration are reported by qs_get_accel_calibration instead.
+ *
+ * @param[out] calibration The calibration of the channel
+ *
+ * @return success or failure when no calibration is set
+ */
+bool qs_get_calibration(uint32_t measurement_id, uint8_t channel, QsCalibration *calibration);
+
+/*!
+ * Accelerometer calibration fitted over static poses. Acceleration in g is
+ * T * diag(gain) * (raw - offset), where T is lower triangular with ones on the
+ * diagonal and misalignment holding the sensitivity of y to x, z to x and z to y.
+ * The misalignment is zero unless it was fitted.
+ */
+typedef struct {
+    double offset[3];
+    double gain[3];
+    double misalignment[3];
+    double residual_g;
+} QsAccelCalibration;
+
+/*!
+ * Fits an accelerometer so the mean raw reading of each static pose has a magnitude
+ * of 1 g by least squares. The poses should point each axis up and down and include
+ * some oblique orientations. At least 6 poses are needed, or 9 with misalignment.
+ *
+ * The profiles hold the offset and gain of each axis in g and can be applied to
+ * later measurements with qs_set_calibration. They do not include the misalignment,
+ * so a calibration with misalignment is applied with qs_set_accel_calibration instead.
+ *
+ * @param[in] channels The x, y and z accelerometer channels
+ * @param[in] pose_start_s The start of each static pose
+ * @param[in] pose_end_s The end of each static pose
+ * @param[in] misalignment Whether to fit the cross-axis misalignment
+ * @param[out] calibration The fitted calibration and its residual
+ * @param[out] profiles Three calibrations in channel order or NULL
+ *
+ * @return success or failure
+ */
+bool qs_calibrate_accelerometer(uint32_t measurement_id, const uint8_t *channels, const double *pose_start_s, const double *pose_end_s, uint32_t num_poses, bool misalignment, QsAccelCalibration *calibration, QsCalibration *profiles);
+
+/*!
+ * Sets, replaces or removes the calibration of an accelerometer over three channels,
+ * including its misalignment, so that each axis is converted to g from the raw counts
+ * of all three. It replaces any accelerometer calibration sharing one of the channels,
+ * and the channels may not also have a channel calibration from qs_set_calibration.
+ *
+ * @param[in] channels The x, y and z accelerometer channels
+ * @param[in] calibration The calibration or NULL to remove the one on the channels
+ *
+ * @return success or failure
+ */
+bool qs_set_accel_calibration(uint32_t measurement_id, const uint8_t *channels, const QsAccelCalibration *calibration);
+
+/*!
+ * Copies the accelerometer calibration that converts a channel to g.
+ *
+ * @param[in] channel Any of the three accelerometer channels
+ * @param[out] channels The x, y and z channels of the calibration
+ * @param[out] calibration The accelerometer calibration
+ *
+ * @return success or failure when no accelerometer calibration covers the channel
+ */
+bool qs_get_accel_calibration(uint32_t measurement_id, uint8_t channel, uint8_t *channels, QsAccelCalibration *calibration);
+
+typedef enum {
+    QS_DETREND_METHOD_POLYNOMIAL = 0,
+    QS_DETREND_METHOD_MEDIAN = 1,
+    QS_DETREND_METHOD_HIGH_PASS = 2,
+} QsDetrendMethod;
+
+/*!
+ * Drift removal over the time range from start_s to end_s, samples outside of
+ * it are left unchanged.
+ *
+ * - Polynomial: the least squares polynomial of the given order (at most 5) is removed from
+ *   consecutive windows of about window_s seconds, or from the whole range when window_s is not positive
+ * - Median: the running median over window_s seconds is removed as the baseline
+ * - High pass: a Butterworth high-pass at cutoff_hz is run forward and backward for zero phase shift
+ */
+typedef struct {
+    uint8_t method;
+    double window_s;
+    uint8_t order;
+    double cutoff_hz;
+    double start_s;
+    double end_s;
+} QsDetrendConfig;
+
+/*!
+ * Fills the configuration used when none is given to qs_pipeline_add_detrend.
+ */
+bool qs_default_detrend_config(QsDetrendConfig *config);
+
+/*!
+ * Appends a detrend stage to the channels selected by the mask. Stages run in
+ * the order they were added, so attached pipelines and export pipelines give the
+ * same result.
+ *
+ * @param[in] channel_mask The bitmask of channels to detrend, bit 0 is channel 0
+ * @param[in] config The detrend settings or NULL for the defaults
+ *
+ * @return success or failure
+ */
+bool qs_pipeline_add_detrend(uint32_t pipeline_id, uint32_t channel_mask, const QsDetrendConfig *config);
+
+typedef enum {
+    QS_THRESHOLD_RULE_SOFT = 0,
+    QS_THRESHOLD_RULE_HARD = 1,
+} QsThresholdRule;
+
+/*!
+ * Discrete wavelet denoising with a Daubechies wavelet of 1 to 4 vanishing moments,
+ * where order 1 is the Haar wavelet. Detail coefficients of each level are thresholded
+ * at threshold_scale times the universal threshold sigma * sqrt(2 ln n), with the noise
+ * sigma estimated from the median absolute deviation of the finest level. The number
+ * of levels is reduced for channels too short to decompose that far.
+ */
+typedef struct {
+    uint8_t order;
+    uint8_t levels;
+    double threshold_scale;
+    uint8_t rule;
+} QsWaveletConfig;
+
+/*!
+ * Fills the configuration for soft thresholding over 5 levels of the db4 wavelet.
+ */
+bool qs_default_wavelet_config(QsWaveletConfig *config);
+
+/*!
+ * Appends a wavelet denoise stage to the channels selected by the mask. It runs
+ * over the whole series, after the stages added before it and before those added after it.
+ *
+ * @param[in] channel_mask The bitmask of channels to denoise, bit 0 is channel 0
+ * @param[in] config The denoise settings or NULL for the defaults
+ *
+ * @return success or failure
+ */
+bool qs_pipeline_add_wavelet_denoise(uint32_t pipeline_id, uint32_t channel_mask, const QsWaveletConfig *config);
+
+/*!
+ * Groups of features, combined as bit flags.
+ *
+ * - Statistics: mean, std_dev, rms, min, max, zero_crossing_rate
+ * - Band powers: band_<low>_<high>_hz, the power between consecutive band edges so bands sum to the variance
+ * - Spectral: spectral_centroid_hz, dominant_hz and spectral_entropy normalized to 0 to 1
+ * - Autocorrelation: acf_peak_lag_s and acf_peak, the first autocorrelation maximum after it turns negative,
+ *   or NaN when the window has no periodicity
+ */
+typedef enum {
+    QS_FEATURE_SET_STATISTICS = 1,
+    QS_FEATURE_SET_BAND_POWERS = 2,
+    QS_FEATURE_SET_SPECTRAL = 4,
+    QS_FEATURE_SET_AUTOCORRELATION = 8,
+} QsFeatureSet;
+
+/*!
+ * Windows of window_s seconds every hop_s seconds and the QsFeatureSet flags to extract.
+ * Band powers use the first num_bands + 1 of up to 9 increasing band edges.
+ */
+typedef struct {
+    double window_s;
+    double hop_s;
+    uint32_t feature_sets;
+    double band_edges_hz[9];
+    uint8_t num_bands;
+} QsFeatureConfig;
+
+typedef struct {
+    char name[48];
+} QsFeatureName;
+
+/*!
+ * Fills the configuration used when none is given to feature extraction.
+ */
+bool qs_default_feature_config(QsFeatureConfig *config);
+
+/*!
+ * Names the columns of the feature matrix as ch<channel>_<feature>, ordered by
+ * ascending channel and then by feature set. Names depend only on the channels
+ * and the configuration, so they stay the same across measurements.
+ *
+ * @param[in] channel_mask The bitmask of channels, bit 0 is channel 0
+ * @param[in] config The feature settings or NULL for the defaults
+ * @param[out] names The nul-terminated feature names
+ * @param[in|out] num_features The number of features
+ *
+ * @return success or failure
+ */
+bool qs_feature_names(uint32_t channel_mask, const QsFeatureConfig *config, QsFeatureName *names, uint32_t *num_features);
+
+/*!
+ * Hashes the layout of the feature matrix for a model header. The hash is the 32-bit
+ * FNV-1a of the names given by qs_feature_names, each followed by its nul terminator.
+ *
+ * @param[in] channel_mask The bitmask of channels, bit 0 is channel 0
+ * @param[in] config The feature settings or NULL for the defaults
+ * @param[out] hash The feature layout hash
+ *
+ * @return success or failure
+ */
+bool qs_feature_layout_hash(uint32_t channel_mask, const QsFeatureConfig *config, uint32_t *hash);
+
+/*!
+ * Extracts features from every complete window within the time range. Channels are
+ * processed by the attached pipeline, if any, before extraction.
+ *
+ * The features buffer is a row-major matrix with one row per window and one column
+ * for each name given by qs_feature_names with the same channels and configuration.
+ *
+ * @param[in] channel_mask The bitmask of channels, bit 0 is channel 0
+ * @param[in] config The feature settings or NULL for the defaults
+ * @param[out] window_start_s The start of each window
+ * @param[out] features The feature matrix
+ * @param[in|out] num_windows The number of windows
+ *
+ * @return success or failure
+ */
+bool qs_extract_features(uint32_t measurement_id, uint32_t channel_mask, double start_s, double end_s, const QsFeatureConfig *config, double *window_start_s, double *features, uint32_t *num_windows);
+
+typedef enum {
+    QS_MODEL_KIND_TREE_ENSEMBLE = 0,
+    QS_MODEL_KIND_QUANTIZED_MLP = 1,
+} QsModelKind;
+
+typedef struct {
+    uint8_t kind;
+    uint32_t num_features;
+    uint32_t num_classes;
+    uint32_t feature_hash;
+} QsModelInfo;
+
+/*!
+ * Loads a classifier from the little-endian model format so that it is tracked by the
+ * uint32_t id produced. Ids start at 1. Loading copies the data and validates it fully,
+ * so inference never reads outside the model. Interactions with models are threadsafe.
+ *
+ * Header: "QSML", version u8 = 1, kind u8, num_features u16, num_classes u8, feature_hash u32
+ * where feature_hash is the qs_feature_layout_hash of the features the model was trained on.
+ *
+ * Tree ensemble: num_trees u8, then for each tree
+ * - num_nodes u16, then each node as feature u16, threshold f32, left u16, right u16.
+ *   Node 0 is the root and children must come after their parent. Samples go left when
+ *   the feature is at most the threshold, and NaN features go right. A feature of 0xFFFF
+ *   marks a leaf, whose left is its leaf index.
+ * - num_leaves u16, then num_classes f32 class probabilities for each leaf
+ * The probabilities of the trees are averaged.
+ *
+ * Quantized MLP: num_features f32 input offsets, num_features f32 input scales, num_layers u8,
+ * then for each layer inputs u16, outputs u16, weight_scale f32, inputs * outputs i8 weights
+ * with a row of inputs per output, and outputs f32 biases. Inputs are (x - offset) * scale,
+ * each layer computes weight_scale * W x + b with ReLU between layers, and the output layer
+ * of num_classes is normalized by softmax. Inference runs in single precision.
+ *
+ * @return the model id or 0 on failure
+ */
+uint32_t qs_load_model(const uint8_t *data, uint32_t len);
+bool qs_drop_model(uint32_t model_id);
+bool qs_model_info(uint32_t model_id, QsModelInfo *info);
+
+/*!
+ * Classifies every complete feature window within the time range. The features are
+ * extracted as by qs_extract_features with the same channels and configuration, whose
+ * layout hash must match the feature_hash of the model.
+ *
+ * @param[in] channel_mask The bitmask of channels, bit 0 is channel 0
+ * @param[in] config The feature settings or NULL for the defaults
+ * @param[out] window_start_s The start of each window
+ * @param[out] probabilities A row-major matrix with num_classes class probabilities per window
+ * @param[in|out] num_windows The number of windows
+ *
+ * @return success or failure
+ */
+bool qs_classify_windows(uint32_t measurement_id, uint32_t model_id, uint32_t channel_mask, double start_s, double end_s, const QsFeatureConfig *config, double *window_start_s, double *probabilities, uint32_t *num_windows);
+
+typedef struct {
+    double penalty_scale;
+    uint32_t min_segment_windows;
+} QsChangePointConfig;
+
+typedef struct {
+    double time_s;
+    double score;
+} QsChangePoint;
+
+/*!
+ * Fills the configuration that charges the BIC penalty (2 * features + 1) * ln(windows)
+ * per change and keeps segments to at least 4 windows.
+ */
+bool qs_default_change_point_config(QsChangePointConfig *config);
+
+/*!
+ * Segments the feature windows within the time range where the mean or variance of the
+ * features shifts, by PELT with a gaussian cost on each feature standardized over the
+ * range. Features that are constant or missing in any window are ignored. A change is
+ * placed midway between the centers of the windows either side of it, and its score is
+ * the reduction in cost, twice the negative log-likelihood, from splitting there.
+ *
+ * @param[in] channel_mask The bitmask of channels, bit 0 is channel 0
+ * @param[in] feature_config The feature settings or NULL for the defaults
+ * @param[in] config The segmentation settings or NULL for the defaults
+ * @param[out] change_points The change points in time order
+ * @param[in|out] num_change_points The number of change points
+ *
+ * @return success or failure
+ */
+bool qs_detect_change_points(uint32_t measurement_id, uint32_t channel_mask, double start_s, double end_s, const QsFeatureConfig *feature_config, const QsChangePointConfig *config, QsChangePoint *change_points, uint32_t *num_change_points);
+
+typedef struct {
+    double lag_s;
+    double correlation;
+} QsLagEstimate;
+
+/*!
+ * Normalized cross-correlation between two channels, which may belong to different
+ * measurements sharing a sample rate, such as two patches for pulse transit time. Each
+ * channel is read after any attached pipeline, channel a over the time range and channel b
+ * over the same range moved by offset_b_s on its own timeline. At each lag the Pearson correlation of a(t) with b(t + lag) is computed over the
+ * samples where both exist, so a positive lag means channel b is delayed behind a.
+ *
+ * The estimate holds the lag of the highest correlation, refined between samples by a
+ * parabola through its neighbours, and the correlation at the sampled peak.
+ *
+ * @param[in] offset_b_s The time of measurement b at time zero of measurement a, 0 when they start together
+ * @param[in] max_lag_s The largest lag in either direction, rounded to whole samples, which
+ *                      may not exceed half of the shorter channel's range so every lag is
+ *                      correlated over at least half of it
+ * @param[out] lags_s The lag of each correlation from -max_lag_s to max_lag_s
+ * @param[out] correlation The correlation at each lag, or 0 where either channel is flat
+ * @param[in|out] num_lags The number of lags
+ * @param[out] estimate The best lag and its correlation, written only on success
+ *
+ * @return success or failure
+ */
+bool qs_cross_correlate(uint32_t measurement_id_a, uint8_t channel_a, uint32_t measurement_id_b, uint8_t channel_b, double start_s, double end_s, double offset_b_s, double max_lag_s, double *lags_s, double *correlation, uint32_t *num_lags, QsLagEstimate *estimate);
+
+typedef enum {
+    QS_TRIGGER_KIND_RISING = 0,
+    QS_TRIGGER_KIND_FALLING = 1,
+    QS_TRIGGER_KIND_BAND_RMS = 2,
+} QsTriggerKind;
+
+/*!
+ * Level triggers fire when a physical channel crosses the level in counts. Band triggers
+ * fire when the moving RMS over rms_window_s of the channel, filtered between band_low_hz
+ * and band_high_hz, rises through the level.
+ */
+typedef struct {
+    uint8_t kind;
+    uint8_t channel;
+    double level;
+    double band_low_hz;
+    double band_high_hz;
+    double rms_window_s;
+    double pre_s;
+    double post_s;
+    double holdoff_s;
+} QsTriggerConfig;
+
+typedef struct {
+    uint32_t snapshot_id;
+    uint32_t trigger_id;
+    double trigger_s;
+    double start_s;
+    double value;
+    uint64_t trigger_counter;
+    uint32_t samples_per_channel;
+    uint8_t channels;
+} QsSnapshot;
+
+typedef void (*QsTriggerCallback)(uint32_t measurement_id, const QsSnapshot *snapshot, void *user_data);
+
+/*!
+ * Fills the configuration for a rising level trigger on channel 0 with 2 s of context on
+ * either side, whose band settings select 1 to 10 Hz over 0.5 s if the kind is changed.
+ */
+bool qs_default_trigger_config(QsTriggerConfig *config);
+
+/*!
+ * Adds a trigger that is evaluated on each payload as it arrives, so the sample rate must
+ * be set first. Snapshot times are on the measurement timeline of samples in counter order,
+ * and move later when a payload with a lower counter arrives afterwards. trigger_counter is
+ * the notification counter of the payload holding the triggering sample. Conditions, context
+ * and holdoffs follow the samples in arrival order, as for live statistics.
+ *
+ * When the condition is met, pre_s of context before the triggering sample and post_s from
+ * it on are captured from every physical channel as raw counts. The snapshot completes once
+ * the post-trigger samples arrive, and the trigger re-arms holdoff_s later. A measurement
+ * keeps its 32 most recent snapshots.
+ *
+ * The callback is called from qs_add_signals for each completed snapshot once the
+ * measurement is unlocked, so it may query snapshots from within. The snapshot pointer is
+ * only valid during the call.
+ *
+ * @param[in] config The trigger settings or NULL for the defaults
+ * @param[in] callback Called with each completed snapshot or NULL
+ * @param[in] user_data Passed back to the callback unchanged
+ *
+ * @return the trigger id or 0 on failure
+ */
+uint32_t qs_add_trigger(uint32_t measurement_id, const QsTriggerConfig *config, QsTriggerCallback callback, void *user_data);
+
+/*!
+ * Removes a trigger and any capture in progress. Its completed snapshots are kept.
+ */
+bool qs_remove_trigger(uint32_t measurement_id, uint32_t trigger_id);
+
+/*!
+ * Describes the stored snapshots from oldest to newest.
+ *
+ * @param[out] snapshots The snapshot descriptions
+ * @param[in|out] num_snapshots The number of snapshots
+ *
+ * @return success or failure
+ */
+bool qs_snapshots(uint32_t measurement_id, QsSnapshot *snapshots, uint32_t *num_snapshots);
+
+/*!
+ * Copies the channels of a snapshot in channel order.
+ *
+ * @param[out] channel_data A buffer for each of the snapshot channels
+ * @param[in|out] num_samples_per_channel The number of samples in each channel
+ *
+ * @return success or failure
+ */
+bool qs_snapshot_signals(uint32_t measurement_id, uint32_t snapshot_id, double **channel_data, uint32_t *num_samples_per_channel);
+bool qs_clear_snapshots(uint32_t measurement_id);
+
+typedef enum {
+    QS_PERIOD_METHOD_AUTOCORRELATION = 0,
+    QS_PERIOD_METHOD_HARMONIC_PRODUCT = 1,
+} QsPeriodMethod;
+
+typedef struct {
+    double window_s;
+    double step_s;
+    double min_period_s;
+    double max_period_s;
+    uint8_t harmonics;
+    uint8_t method;
+} QsPeriodicityConfig;
+
+typedef struct {
+    double start_s;
+    double period_s;
+    double frequency_hz;
+    double confidence;
+} QsPeriodWindow;
+
+/*!
+ * Fills the configuration that tracks periods of 0.25 to 2 s by autocorrelation over 10 s
+ * windows every second, with 3 harmonics if the harmonic product spectrum is selected.
+ */
+bool qs_default_periodicity_config(QsPeriodicityConfig *config);
+
+/*!
+ * Estimates the dominant period of a channel after any attached pipeline in each window
+ * of window_s started every step_s within the time range. The longest period may be at
+ * most half the window.
+ *
+ * Autocorrelation picks the shortest autocorrelation peak within the period range that
+ * reaches 0.8 of the highest, so multiples of the period are not reported, and its
+ * confidence is the peak correlation corrected for the overlap at that lag. The harmonic
+ * product spectrum multiplies the Hann windowed power at each candidate frequency and its
+ * harmonics, and its confidence is the share of power within a bin of those frequencies.
+ * Both refine the peak with a parabola. Windows without a period have a NaN period and
+ * frequency and 0 confidence.
+ *
+ * @param[in] config The tracking settings or NULL for the defaults
+ * @param[out] windows The period of each window
+ * @param[in|out] num_windows The number of windows
+ *
+ * @return success or failure
+ */
+bool qs_track_period(uint32_t measurement_id, uint8_t channel, double start_s, double end_s, const QsPeriodicityConfig *config, QsPeriodWindow *windows, uint32_t *num_windows);
@@ -0,0 +1,415 @@
+use super::*;
+use crate::filter::{filtfilt, Biquad, QsFilterKind};
+
+/// Band of step frequencies passed before peak picking
+const STEP_LOW_HZ: f64 = 0.5;
+const STEP_HIGH_HZ: f64 = 3.0;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsActivityLevel {
+    Sedentary = 0,
+    Light = 1,
+    Vigorous = 2,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsPosture {
+    Upright = 0,
+    Supine = 1,
+    Prone = 2,
+    /// Lying on the left side
+    Left = 3,
+    /// Lying on the right side
+    Right = 4,
+    /// Inverted or without a gravity reading
+    Unknown = 5,
+}
+
+/// Which accelerometer channels measure each body axis
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsAxisMapping {
+    /// Channel measuring acceleration toward the head
+    pub vertical: u8,
+    /// Channel measuring acceleration toward the patient's left
+    pub lateral: u8,
+    /// Channel measuring acceleration out of the chest
+    pub anterior: u8,
+    /// -1 where a channel measures the opposite direction, otherwise 1
+    pub vertical_sign: i8,
+    pub lateral_sign: i8,
+    pub anterior_sign: i8,
+    pub counts_per_g: f64,
+}
+
+/// Settings for per-epoch activity, step and posture classification
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsActivityConfig {
+    pub epoch_s: f64,
+    /// Mean ENMO at which an epoch becomes light and then vigorous activity
+    pub light_mg: f64,
+    pub vigorous_mg: f64,
+    /// Peak band-passed acceleration magnitude counted as a step
+    pub step_threshold_g: f64,
+    pub step_min_interval_s: f64,
+    /// Largest tilt of the vertical axis from gravity that is still upright
+    pub upright_angle_deg: f64,
+}
+
+impl Default for QsActivityConfig {
+    fn default() -> Self {
+        QsActivityConfig {
+            epoch_s: 30.0,
+            light_mg: 45.0,
+            vigorous_mg: 100.0,
+            step_threshold_g: 0.1,
+            step_min_interval_s: 0.3,
+            upright_angle_deg: 45.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QsActivityEpoch {
+    pub start_s: f64,
+    /// Mean Euclidean norm minus one g, clamped at zero, in milli-g
+    pub enmo_mg: f64,
+    pub steps: u32,
+    pub activity: u8,
+    pub posture: u8,
+}
+
+/// Totals over all classified epochs
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QsActivitySummary {
+    pub steps: u32,
+    /// Seconds spent at each QsActivityLevel
+    pub activity_s: [f64; 3],
+    /// Seconds spent in each QsPosture
+    pub posture_s: [f64; 6],
+}
+
+/// Sample indices of steps, as peaks of the band-passed acceleration magnitude.
+/// None are found when the sample rate is too low to resolve the step band.
+pub(crate) fn detect_steps(
+    magnitude: &[f64],
+    sample_rate: f64,
+    config: &QsActivityConfig,
+) -> Result<Vec<usize>, &'static str> {
+    if STEP_HIGH_HZ >= sample_rate / 2.0 {
+        return Ok(Vec::new());
+    }
+    let sections = [
+        Biquad::design(QsFilterKind::HighPass, sample_rate, STEP_LOW_HZ, 0.707)?,
+        Biquad::design(QsFilterKind::LowPass, sample_rate, STEP_HIGH_HZ, 0.707)?,
+    ];
+    let filtered = filtfilt(&sections, magnitude);
+    let min_interval = libm::round(config.step_min_interval_s * sample_rate) as usize;
+    let mut steps: Vec<usize> = Vec::new();
+    for i in 1..filtered.len().saturating_sub(1) {
+        let peak = filtered[i];
+        if peak < config.step_threshold_g || peak <= filtered[i - 1] || peak < filtered[i + 1] {
+            continue;
+        }
+        match steps.last_mut() {
+            Some(last) if i - *last < min_interval => {
+                if peak > filtered[*last] {
+                    *last = i;
+                }
+            }
+            _ => steps.push(i),
+        }
+    }
+    Ok(steps)
+}
+
+/// Posture from the mean gravity direction in body axes
+pub(crate) fn posture(
+    vertical: f64,
+    lateral: f64,
+    anterior: f64,
+    upright_angle_deg: f64,
+) -> QsPosture {
+    let norm = libm::sqrt(vertical * vertical + lateral * lateral + anterior * anterior);
+    let upright_cos = libm::cos(upright_angle_deg * core::f64::consts::PI / 180.0);
+    if norm == 0.0 || vertical / norm <= -upright_cos {
+        QsPosture::Unknown
+    } else if vertical / norm >= upright_cos {
+        QsPosture::Upright
+    } else if libm::fabs(anterior) >= libm::fabs(lateral) {
+        match anterior > 0.0 {
+            true => QsPosture::Supine,
+            false => QsPosture::Prone,
+        }
+    } else {
+        // At rest the accelerometer reads up, so lying on the left reads toward the right
+        match lateral > 0.0 {
+            true => QsPosture::Right,
+            false => QsPosture::Left,
+        }
+    }
+}
+
+/// Classifies consecutive epochs of body axis accelerations in g, dropping a trailing partial epoch
+pub(crate) fn classify_epochs(
+    axes: [&[f64]; 3],
+    sample_rate: f64,
+    config: &QsActivityConfig,
+) -> Result<Vec<(Range<usize>, QsActivityEpoch)>, &'static str> {
+    let epoch = libm::round(config.epoch_s * sample_rate) as usize;
+    if epoch == 0 {
+        return Err("Epoch is shorter than one sample");
+    }
+    let [vertical, lateral, anterior] = axes;
+    let magnitude = (0..vertical.len())
+        .map(|i| {
+            libm::sqrt(
+                vertical[i] * vertical[i] + lateral[i] * lateral[i] + anterior[i] * anterior[i],
+            )
+        })
+        .collect::<Vec<_>>();
+    let steps = detect_steps(&magnitude, sample_rate, config)?;
+    let mean = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+
+    Ok((0..magnitude.len() / epoch)
+        .map(|e| {
+            let range = e * epoch..(e + 1) * epoch;
+            let enmo_mg = 1000.0
+                * mean(
+                    &magnitude[range.clone()]
+                        .iter()
+                        .map(|m| (m - 1.0).max(0.0))
+                        .collect::<Vec<_>>(),
+                );
+            let activity = if enmo_mg >= config.vigorous_mg {
+                QsActivityLevel::Vigorous
+            } else if enmo_mg >= config.light_mg {
+                QsActivityLevel::Light
+            } else {
+                QsActivityLevel::Sedentary
+            };
+            let posture = posture(
+                mean(&vertical[range.clone()]),
+                mean(&lateral[range.clone()]),
+                mean(&anterior[range.clone()]),
+                config.upright_angle_deg,
+            );
+            let epoch = QsActivityEpoch {
+                start_s: 0.0,
+                enmo_mg,
+                steps: steps.iter().filter(|s| range.contains(*s)).count() as u32,
+                activity: activity as u8,
+                posture: posture as u8,
+            };
+            (range, epoch)
+        })
+        .collect())
+}
+
+#[no_mangle]
+pub extern "C" fn qs_default_activity_config(config: *mut QsActivityConfig) -> bool {
+    if config.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    unsafe {
+        core::ptr::write(config, QsActivityConfig::default());
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_classify_activity(
+    measurement_id: u32,
+    mapping: *const QsAxisMapping,
+    start_s: f64,
+    end_s: f64,
+    config: *const QsActivityConfig,
+    epochs: *mut QsActivityEpoch,
+    num_epochs: *mut u32,
+    summary: *mut QsActivitySummary,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        let mapping = unsafe { mapping.as_ref() }.ok_or("Null buffer passed to the library")?;
+        if summary.is_null() {
+            return Err("Null buffer passed to the library");
+        }
+        let config = match unsafe { config.as_ref() } {
+            Some(config) => *config,
+            None => QsActivityConfig::default(),
+        };
+        if mapping.counts_per_g <= 0.0 || mapping.counts_per_g.is_nan() {
+            return Err("Axis mapping counts per g must be positive");
+        }
+        let sample_rate = measurement.sample_rate()?;
+        let range = measurement.range_indices(start_s, end_s)?;
+        let mut axes = Vec::new();
+        for (channel, sign) in [
+            (mapping.vertical, mapping.vertical_sign),
+            (mapping.lateral, mapping.lateral_sign),
+            (mapping.anterior, mapping.anterior_sign),
+        ]
+        .iter()
+        {
+            let scale = if *sign < 0 { -1.0 } else { 1.0 } / mapping.counts_per_g;
+            let values = measurement.channel_range(*channel, start_s, end_s)?;
+            axes.push(values.into_iter().map(|v| v * scale).collect::<Vec<_>>());
+        }
+
+        let classified = classify_epochs([&axes[0], &axes[1], &axes[2]], sample_rate, &config)?;
+        let mut totals = QsActivitySummary::default();
+        for (samples, epoch) in classified.iter() {
+            let seconds = (samples.end - samples.start) as f64 / sample_rate;
+            totals.steps += epoch.steps;
+            totals.activity_s[epoch.activity as usize] += seconds;
+            totals.posture_s[epoch.posture as usize] += seconds;
+        }
+        let classified = classified
+            .into_iter()
+            .map(|(samples, epoch)| QsActivityEpoch {
+                start_s: (range.start + samples.start) as f64 / sample_rate,
+                ..epoch
+            })
+            .collect::<Vec<_>>();
+        unsafe {
+            core::ptr::write(summary, totals);
+        }
+        write_buffers(&[(&classified[..], epochs)], num_epochs)
+    });
+    report(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::measurement_with_samples;
+    use core::f64::consts::PI;
+
+    /// Ten seconds each of lying on the back, walking at two steps per second and lying on the left
+    fn day(sample_rate: f64) -> Vec<[f64; 3]> {
+        (0..(30.0 * sample_rate) as usize)
+            .map(|i| {
+                let t = i as f64 / sample_rate;
+                match (t / 10.0) as usize {
+                    0 => [0.0, 0.0, 1.0],
+                    1 => [1.0 + 0.5 * libm::sin(2.0 * PI * 2.0 * t), 0.05, 0.1],
+                    _ => [0.1, -1.0, 0.0],
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn classifies_activity_steps_and_posture() {
+        let sample_rate = 50.0;
+        let samples = day(sample_rate);
+        let axis = |a: usize| samples.iter().map(|s| s[a]).collect::<Vec<_>>();
+        let (vertical, lateral, anterior) = (axis(0), axis(1), axis(2));
+        let config = QsActivityConfig {
+            epoch_s: 10.0,
+            ..Default::default()
+        };
+        let epochs =
+            classify_epochs([&vertical, &lateral, &anterior], sample_rate, &config).unwrap();
+        assert_eq!(epochs.len(), 3);
+
+        let postures = epochs.iter().map(|(_, e)| e.posture).collect::<Vec<_>>();
+        assert_eq!(
+            postures,
+            vec![
+                QsPosture::Supine as u8,
+                QsPosture::Upright as u8,
+                QsPosture::Left as u8
+            ]
+        );
+        let activity = epochs.iter().map(|(_, e)| e.activity).collect::<Vec<_>>();
+        assert_eq!(
+            activity,
+            vec![
+                QsActivityLevel::Sedentary as u8,
+                QsActivityLevel::Vigorous as u8,
+                QsActivityLevel::Sedentary as u8
+            ]
+        );
+        let steps = epochs.iter().map(|(_, e)| e.steps).collect::<Vec<_>>();
+        assert_eq!(steps[0], 0);
+        assert!((19..=21).contains(&steps[1]));
+        assert_eq!(steps[2], 0);
+
+        // Too slow a rate for the step band still classifies, without steps
+        let samples = day(5.0);
+        let axis = |a: usize| samples.iter().map(|s| s[a]).collect::<Vec<_>>();
+        let epochs = classify_epochs([&axis(0), &axis(1), &axis(2)], 5.0, &config).unwrap();
+        assert_eq!(epochs.len(), 3);
+        assert!(epochs.iter().all(|(_, e)| e.steps == 0));
+        assert_eq!(epochs[1].1.posture, QsPosture::Upright as u8);
+    }
+
+    #[test]
+    fn axis_mapping_and_totals_through_ffi() {
+        // The sensor is mounted with its first channel pointing out of the chest
+        // and its third channel pointing toward the feet
+        let samples = day(50.0)
+            .iter()
+            .map(|[v, l, a]| {
+                vec![
+                    (a * 1000.0) as i16,
+                    (l * 1000.0) as i16,
+                    (-v * 1000.0) as i16,
+                ]
+            })
+            .collect::<Vec<_>>();
+        let measurement_id = measurement_with_samples(&samples, 50.0);
+
+        let mapping = QsAxisMapping {
+            vertical: 2,
+            lateral: 1,
+            anterior: 0,
+            vertical_sign: -1,
+            lateral_sign: 1,
+            anterior_sign: 1,
+            counts_per_g: 1000.0,
+        };
+        let mut config = QsActivityConfig::default();
+        assert!(qs_default_activity_config(&mut config));
+        // Totals count the 250 samples each epoch is rounded to
+        config.epoch_s = 5.004;
+        let mut epochs = [QsActivityEpoch::default(); 8];
+        let mut num_epochs = 8;
+        let mut summary = QsActivitySummary::default();
+        assert!(qs_classify_activity(
+            measurement_id,
+            &mapping,
+            0.0,
+            f64::INFINITY,
+            &config,
+            epochs.as_mut_ptr(),
+            &mut num_epochs,
+            &mut summary,
+        ));
+        assert_eq!(num_epochs, 6);
+        assert_eq!(epochs[2].start_s, 10.0);
+        assert_eq!(epochs[2].posture, QsPosture::Upright as u8);
+        assert!((19..=21).contains(&summary.steps));
+        assert_eq!(summary.activity_s, [20.0, 0.0, 10.0]);
+        assert_eq!(summary.posture_s, [10.0, 10.0, 0.0, 10.0, 0.0, 0.0]);
+
+        assert!(!qs_classify_activity(
+            measurement_id,
+            core::ptr::null(),
+            0.0,
+            f64::INFINITY,
+            &config,
+            epochs.as_mut_ptr(),
+            &mut num_epochs,
+            &mut summary,
+        ));
+
+        qs_drop_measurement(measurement_id);
+    }
+}
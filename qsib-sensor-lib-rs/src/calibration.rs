@@ -0,0 +1,834 @@
+use super::*;
+
+const MAX_COEFFICIENTS: usize = 6;
+
+/// Conversion of one channel from raw counts to physical units
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QsCalibration {
+    pub gain: f64,
+    pub offset: f64,
+    /// Polynomial c0 + c1 x + c2 x^2 ... applied to the linear value x, unused when num_coefficients is 0
+    pub coefficients: [f64; MAX_COEFFICIENTS],
+    pub num_coefficients: u8,
+    /// Nul-terminated unit such as "g", "mV" or "Pa"
+    pub unit: [c_char; 16],
+}
+
+impl QsCalibration {
+    pub(crate) fn apply(&self, raw: f64) -> f64 {
+        let linear = self.gain * raw + self.offset;
+        match self.num_coefficients as usize {
+            0 => linear,
+            n => self.coefficients[..n]
+                .iter()
+                .rev()
+                .fold(0.0, |value, c| value * linear + c),
+        }
+    }
+
+    fn validate(&self) -> Result<(), &'static str> {
+        if !self.gain.is_finite() || !self.offset.is_finite() {
+            return Err("Calibration gain and offset must be finite");
+        }
+        if self.num_coefficients as usize > MAX_COEFFICIENTS {
+            return Err("Calibration polynomial has too many coefficients");
+        }
+        if !self.unit.contains(&0) {
+            return Err("Calibration unit must be nul-terminated");
+        }
+        Ok(())
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn qs_create_calibrated_measurement(
+    signal_channels: u8,
+    calibrations: *const QsCalibration,
+) -> u32 {
+    if calibrations.is_null() {
+        push_error("Null buffer passed to the library");
+        return 0;
+    }
+    let calibrations =
+        unsafe { core::slice::from_raw_parts(calibrations, signal_channels as usize) };
+    if let Err(err) = calibrations.iter().try_for_each(|c| c.validate()) {
+        push_error(err);
+        return 0;
+    }
+    let measurement_id = qs_create_measurement(signal_channels);
+    for (channel, calibration) in calibrations.iter().enumerate() {
+        if !qs_set_calibration(measurement_id, channel as u8, calibration) {
+            qs_drop_measurement(measurement_id);
+            return 0;
+        }
+    }
+    measurement_id
+}
+
+#[no_mangle]
+pub extern "C" fn qs_set_calibration(
+    measurement_id: u32,
+    channel: u8,
+    calibration: *const QsCalibration,
+) -> bool {
+    let rw_measurement = match find_measurement_by_id(measurement_id) {
+        Some(rwm) => rwm,
+        None => {
+            push_error("No measurement exists for the given id");
+            return false;
+        }
+    };
+    let mut measurement_guard = rw_measurement.measurement.write();
+    if !(*measurement_guard).has_channel(channel) {
+        push_error("Channel is not active in the measurement");
+        return false;
+    }
+    if (*measurement_guard)
+        .accel_calibration(channel as usize)
+        .is_some()
+    {
+        push_error("Channel is calibrated as part of an accelerometer");
+        return false;
+    }
+    let calibration = match unsafe { calibration.as_ref() } {
+        Some(calibration) => match calibration.validate() {
+            Ok(()) => Some(*calibration),
+            Err(err) => {
+                push_error(err);
+                return false;
+            }
+        },
+        None => None,
+    };
+    measurement_guard.calibrations[channel as usize] = calibration;
+    // Restart attached pipelines, which process calibrated values
+    measurement_guard.generation += 1;
+
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_get_calibration(
+    measurement_id: u32,
+    channel: u8,
+    calibration: *mut QsCalibration,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        if calibration.is_null() {
+            return Err("Null buffer passed to the library");
+        }
+        let found = measurement
+            .calibrations
+            .get(channel as usize)
+            .and_then(|c| *c)
+            .ok_or("No calibration is set for the channel")?;
+        unsafe {
+            core::ptr::write(calibration, found);
+        }
+        Ok(())
+    });
+    report(result)
+}
+
+impl Measurement {
+    /// The accelerometer calibration covering a channel and the axis of the channel
+    fn accel_calibration(&self, channel: usize) -> Option<(&QsAccelCalibration, [u8; 3], usize)> {
+        self.accel_calibrations
+            .iter()
+            .find_map(|(channels, calibration)| {
+                let axis = channels.iter().position(|c| *c as usize == channel)?;
+                Some((calibration, *channels, axis))
+            })
+    }
+
+    pub(crate) fn is_calibrated(&self, channel: usize) -> bool {
+        self.calibrations[channel].is_some() || self.accel_calibration(channel).is_some()
+    }
+
+    /// Raw samples of a channel from sample `start` on, converted to physical
+    /// units when the channel has a calibration. Accelerometer axes are converted
+    /// together so the misalignment between them is removed.
+    pub(crate) fn calibrated_values_from(&self, channel: usize, start: usize) -> Vec<f64> {
+        if let Some((calibration, channels, axis)) = self.accel_calibration(channel) {
+            let [x, y, z] = channels;
+            return self
+                .channel_values_from(x as usize, start)
+                .into_iter()
+                .zip(self.channel_values_from(y as usize, start))
+                .zip(self.channel_values_from(z as usize, start))
+                .map(|((x, y), z)| calibration.apply([x, y, z])[axis])
+                .collect();
+        }
+        let mut values = self.channel_values_from(channel, start);
+        if let Some(calibration) = &self.calibrations[channel] {
+            values.iter_mut().for_each(|v| *v = calibration.apply(*v));
+        }
+        values
+    }
+}
+
+/// Result of fitting an accelerometer to 1 g over several static poses, where
+/// acceleration in g is T * diag(gain) * (raw - offset) and T is lower triangular
+/// with ones on the diagonal and the misalignment terms below it
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QsAccelCalibration {
+    pub offset: [f64; 3],
+    pub gain: [f64; 3],
+    /// Sensitivity of y to x, z to x and z to y, zero unless fitted
+    pub misalignment: [f64; 3],
+    /// RMS difference of the calibrated pose magnitudes from 1 g
+    pub residual_g: f64,
+}
+
+impl QsAccelCalibration {
+    fn from_parameters(p: &[f64]) -> QsAccelCalibration {
+        QsAccelCalibration {
+            offset: [p[0], p[1], p[2]],
+            gain: [p[3], p[4], p[5]],
+            misalignment: match p.len() {
+                9 => [p[6], p[7], p[8]],
+                _ => [0.0; 3],
+            },
+            residual_g: 0.0,
+        }
+    }
+
+    pub(crate) fn apply(&self, raw: [f64; 3]) -> [f64; 3] {
+        let scaled = [
+            self.gain[0] * (raw[0] - self.offset[0]),
+            self.gain[1] * (raw[1] - self.offset[1]),
+            self.gain[2] * (raw[2] - self.offset[2]),
+        ];
+        let [yx, zx, zy] = self.misalignment;
+        [
+            scaled[0],
+            yx * scaled[0] + scaled[1],
+            zx * scaled[0] + zy * scaled[1] + scaled[2],
+        ]
+    }
+
+    fn validate(&self) -> Result<(), &'static str> {
+        let terms = self.offset.iter().chain(&self.gain);
+        if terms.chain(&self.misalignment).any(|v| !v.is_finite()) {
+            return Err("Accelerometer calibration terms must be finite");
+        }
+        Ok(())
+    }
+
+    /// Per-channel profiles in g holding the offset and gain of each axis
+    pub(crate) fn profiles(&self) -> [QsCalibration; 3] {
+        let mut unit = [0; 16];
+        unit[0] = b'g' as c_char;
+        let profile = |axis: usize| QsCalibration {
+            gain: self.gain[axis],
+            offset: -self.gain[axis] * self.offset[axis],
+            unit,
+            ..Default::default()
+        };
+        [profile(0), profile(1), profile(2)]
+    }
+}
+
+fn magnitude_residuals(parameters: &[f64], poses: &[[f64; 3]]) -> Vec<f64> {
+    let calibration = QsAccelCalibration::from_parameters(parameters);
+    poses
+        .iter()
+        .map(|pose| {
+            let a = calibration.apply(*pose);
+            libm::sqrt(a[0] * a[0] + a[1] * a[1] + a[2] * a[2]) - 1.0
+        })
+        .collect()
+}
+
+/// Solves `a x = b` by Gaussian elimination with partial pivoting, or None when
+/// the system is singular or not finite
+pub(crate) fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot =
+            (col..n).max_by(|i, j| libm::fabs(a[*i][col]).total_cmp(&libm::fabs(a[*j][col])))?;
+        if a[pivot][col] == 0.0 || !a[pivot][col].is_finite() {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let (upper, lower) = a.split_at_mut(col + 1);
+        for (offset, row) in lower.iter_mut().enumerate() {
+            let factor = row[col] / upper[col][col];
+            for (x, p) in row[col..].iter_mut().zip(&upper[col][col..]) {
+                *x -= factor * p;
+            }
+            b[col + 1 + offset] -= factor * b[col];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum = (row + 1..n).map(|k| a[row][k] * x[k]).sum::<f64>();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+/// Fits offsets, gains and optionally misalignment to the mean raw reading of each
+/// static pose by Levenberg-Marquardt on the difference of each magnitude from 1 g
+pub(crate) fn fit_accelerometer(
+    poses: &[[f64; 3]],
+    misalignment: bool,
+) -> Result<QsAccelCalibration, &'static str> {
+    let num_parameters = if misalignment { 9 } else { 6 };
+    if poses.len() < num_parameters {
+        return Err("Calibration needs a static pose for each fitted parameter");
+    }
+
+    // Start from the center and half range of the readings on each axis
+    let mut parameters = vec![0.0; num_parameters];
+    for axis in 0..3 {
+        let low = poses.iter().fold(f64::INFINITY, |m, p| m.min(p[axis]));
+        let high = poses.iter().fold(f64::NEG_INFINITY, |m, p| m.max(p[axis]));
+        if high <= low {
+            return Err("Calibration poses must point each axis up and down");
+        }
+        parameters[axis] = (high + low) / 2.0;
+        parameters[3 + axis] = 2.0 / (high - low);
+    }
+
+    let cost = |p: &[f64]| {
+        magnitude_residuals(p, poses)
+            .iter()
+            .map(|r| r * r)
+            .sum::<f64>()
+    };
+    let mut lambda = 1e-3;
+    for _ in 0..200 {
+        let residuals = magnitude_residuals(&parameters, poses);
+        let jacobian = (0..num_parameters)
+            .map(|j| {
+                let step = 1e-6 * libm::fabs(parameters[j]).max(1e-6);
+                let mut forward = parameters.clone();
+                let mut backward = parameters.clone();
+                forward[j] += step;
+                backward[j] -= step;
+                magnitude_residuals(&forward, poses)
+                    .iter()
+                    .zip(magnitude_residuals(&backward, poses))
+                    .map(|(f, b)| (f - b) / (2.0 * step))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let normal = (0..num_parameters)
+            .map(|i| {
+                (0..num_parameters)
+                    .map(|j| {
+                        jacobian[i]
+                            .iter()
+                            .zip(jacobian[j].iter())
+                            .map(|(a, b)| a * b)
+                            .sum()
+                    })
+                    .collect::<Vec<f64>>()
+            })
+            .collect::<Vec<_>>();
+        let gradient = jacobian
+            .iter()
+            .map(|column| {
+                -column
+                    .iter()
+                    .zip(residuals.iter())
+                    .map(|(j, r)| j * r)
+                    .sum::<f64>()
+            })
+            .collect::<Vec<_>>();
+
+        let current = cost(&parameters);
+        let mut improved = false;
+        while lambda < 1e12 {
+            let mut damped = normal.clone();
+            for i in 0..num_parameters {
+                damped[i][i] += lambda * normal[i][i].max(1e-12);
+            }
+            if let Some(delta) = solve(damped, gradient.clone()) {
+                let candidate = parameters
+                    .iter()
+                    .zip(delta.iter())
+                    .map(|(p, d)| p + d)
+                    .collect::<Vec<_>>();
+                if cost(&candidate) < current {
+                    let converged = delta
+                        .iter()
+                        .zip(parameters.iter())
+                        .all(|(d, p)| libm::fabs(*d) <= 1e-12 * libm::fabs(*p).max(1e-12));
+                    parameters = candidate;
+                    lambda = (lambda / 10.0).max(1e-12);
+                    improved = !converged;
+                    break;
+                }
+            }
+            lambda *= 10.0;
+        }
+        if !improved {
+            break;
+        }
+    }
+
+    let mut calibration = QsAccelCalibration::from_parameters(&parameters);
+    calibration.residual_g = libm::sqrt(cost(&parameters) / poses.len() as f64);
+    Ok(calibration)
+}
+
+#[no_mangle]
+pub extern "C" fn qs_calibrate_accelerometer(
+    measurement_id: u32,
+    channels: *const u8,
+    pose_start_s: *const f64,
+    pose_end_s: *const f64,
+    num_poses: u32,
+    misalignment: bool,
+    calibration: *mut QsAccelCalibration,
+    profiles: *mut QsCalibration,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        if channels.is_null()
+            || pose_start_s.is_null()
+            || pose_end_s.is_null()
+            || calibration.is_null()
+        {
+            return Err("Null buffer passed to the library");
+        }
+        let channels = unsafe { core::slice::from_raw_parts(channels, 3) };
+        let starts = unsafe { core::slice::from_raw_parts(pose_start_s, num_poses as usize) };
+        let ends = unsafe { core::slice::from_raw_parts(pose_end_s, num_poses as usize) };
+
+        let mut poses = Vec::with_capacity(num_poses as usize);
+        for (start_s, end_s) in starts.iter().zip(ends.iter()) {
+            let mut pose = [0.0; 3];
+            for (axis, channel) in channels.iter().enumerate() {
+                let (_, values) = measurement.raw_channel_range(*channel, *start_s, *end_s)?;
+                pose[axis] = values.iter().sum::<f64>() / values.len() as f64;
+            }
+            poses.push(pose);
+        }
+        let fitted = fit_accelerometer(&poses, misalignment)?;
+        unsafe {
+            core::ptr::write(calibration, fitted);
+            if !profiles.is_null() {
+                core::ptr::copy_nonoverlapping(fitted.profiles().as_ptr(), profiles, 3);
+            }
+        }
+        Ok(())
+    });
+    report(result)
+}
+
+#[no_mangle]
+pub extern "C" fn qs_set_accel_calibration(
+    measurement_id: u32,
+    channels: *const u8,
+    calibration: *const QsAccelCalibration,
+) -> bool {
+    let rw_measurement = match find_measurement_by_id(measurement_id) {
+        Some(rwm) => rwm,
+        None => {
+            push_error("No measurement exists for the given id");
+            return false;
+        }
+    };
+    if channels.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    let channels = unsafe { [*channels, *channels.add(1), *channels.add(2)] };
+    let mut measurement_guard = rw_measurement.measurement.write();
+    let measurement = &mut *measurement_guard;
+    let calibration = match unsafe { calibration.as_ref() } {
+        Some(calibration) => {
+            let check = if !channels.iter().all(|c| measurement.has_channel(*c)) {
+                Err("Channel is not active in the measurement")
+            } else if channels[0] == channels[1]
+                || channels[0] == channels[2]
+                || channels[1] == channels[2]
+            {
+                Err("Accelerometer axes must be distinct channels")
+            } else if channels
+                .iter()
+                .any(|c| measurement.calibrations[*c as usize].is_some())
+            {
+                Err("Channel already has a channel calibration")
+            } else {
+                calibration.validate()
+            };
+            if let Err(err) = check {
+                push_error(err);
+                return false;
+            }
+            Some(*calibration)
+        }
+        None => None,
+    };
+    // Replace any accelerometer calibration sharing one of the channels
+    measurement
+        .accel_calibrations
+        .retain(|(existing, _)| !existing.iter().any(|c| channels.contains(c)));
+    if let Some(calibration) = calibration {
+        measurement.accel_calibrations.push((channels, calibration));
+    }
+    // Restart attached pipelines, which process calibrated values
+    measurement.generation += 1;
+
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_get_accel_calibration(
+    measurement_id: u32,
+    channel: u8,
+    channels: *mut u8,
+    calibration: *mut QsAccelCalibration,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        if channels.is_null() || calibration.is_null() {
+            return Err("Null buffer passed to the library");
+        }
+        let (found, axes, _) = measurement
+            .accel_calibration(channel as usize)
+            .ok_or("No accelerometer calibration covers the channel")?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(axes.as_ptr(), channels, 3);
+            core::ptr::write(calibration, *found);
+        }
+        Ok(())
+    });
+    report(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{cascade, Biquad, BiquadState};
+    use crate::tests::encode_payload;
+    use assert_approx_eq::assert_approx_eq;
+
+    fn unit(name: &str) -> [c_char; 16] {
+        let mut unit = [0; 16];
+        for (u, b) in unit.iter_mut().zip(name.bytes()) {
+            *u = b as c_char;
+        }
+        unit
+    }
+
+    #[test]
+    fn linear_and_polynomial_calibration() {
+        let linear = QsCalibration {
+            gain: 0.5,
+            offset: -10.0,
+            ..Default::default()
+        };
+        assert_approx_eq!(linear.apply(100.0), 40.0);
+
+        let polynomial = QsCalibration {
+            num_coefficients: 3,
+            coefficients: [1.0, 2.0, 0.25, 0.0, 0.0, 0.0],
+            ..linear
+        };
+        assert_approx_eq!(polynomial.apply(100.0), 1.0 + 80.0 + 400.0);
+    }
+
+    #[test]
+    fn exports_physical_units_when_requested() {
+        let calibrations = [
+            QsCalibration {
+                gain: 1.0 / 4096.0,
+                unit: unit("g"),
+                ..Default::default()
+            },
+            QsCalibration {
+                gain: 0.002,
+                offset: -1.0,
+                unit: unit("mV"),
+                ..Default::default()
+            },
+        ];
+        let measurement_id = qs_create_calibrated_measurement(2, calibrations.as_ptr());
+        assert_ne!(measurement_id, 0);
+        let raw_payload = encode_payload(0, &[vec![4096, 1000], vec![-2048, 500]]);
+        qs_add_signals(
+            measurement_id,
+            raw_payload.as_ptr(),
+            raw_payload.len() as u16,
+        );
+
+        let mut bufs = [[0.0f64; 2]; 2];
+        let mut channel_data = bufs
+            .iter_mut()
+            .map(|b| b.as_mut_ptr() as *mut core::ffi::c_void)
+            .collect::<Vec<_>>();
+        let mut num_samples = 2;
+        for calibrated in [false, true].iter() {
+            assert!(qs_export_signals(
+                measurement_id,
+                0b11,
+                QsSampleType::F64 as u8,
+                QsLayout::Planar as u8,
+                &QsExportOptions {
+                    calibrated: *calibrated,
+                    ..Default::default()
+                },
+                channel_data.as_mut_ptr(),
+                &mut num_samples,
+            ));
+            if !calibrated {
+                assert_eq!(bufs[0], [4096.0, -2048.0]);
+            }
+        }
+        assert_eq!(bufs[0], [1.0, -0.5]);
+        assert_eq!(bufs[1], [1.0, 0.0]);
+
+        let mut found = QsCalibration::default();
+        assert!(qs_get_calibration(measurement_id, 1, &mut found));
+        assert_eq!(found.unit, unit("mV"));
+        assert!(qs_set_calibration(measurement_id, 1, core::ptr::null()));
+        assert!(!qs_get_calibration(measurement_id, 1, &mut found));
+
+        let unterminated = QsCalibration {
+            unit: [b'x' as c_char; 16],
+            ..Default::default()
+        };
+        assert!(!qs_set_calibration(measurement_id, 0, &unterminated));
+        assert!(!qs_set_calibration(measurement_id, 2, &calibrations[0]));
+
+        // Raw counts are calibrated before any processing, for exports and analyses alike
+        assert!(qs_set_sample_rate(measurement_id, 2.0, 1.0));
+        let squared = QsCalibration {
+            gain: 0.001,
+            num_coefficients: 3,
+            coefficients: [0.0, 0.0, 1.0, 0.0, 0.0, 0.0],
+            unit: unit("Pa"),
+            ..Default::default()
+        };
+        assert!(qs_set_calibration(measurement_id, 1, &squared));
+        let pipeline_id = qs_create_pipeline();
+        assert!(qs_pipeline_add_filter(
+            pipeline_id,
+            0b10,
+            QsFilterKind::LowPass as u8,
+            0.5,
+            0.707
+        ));
+        assert!(qs_attach_pipeline(measurement_id, pipeline_id));
+        let mut expected = vec![1.0, 0.25];
+        let low_pass = Biquad::design(QsFilterKind::LowPass, 2.0, 0.5, 0.707f32 as f64).unwrap();
+        cascade(&[low_pass], &mut [BiquadState::default()], &mut expected);
+        assert!(qs_export_signals(
+            measurement_id,
+            0b11,
+            QsSampleType::F64 as u8,
+            QsLayout::Planar as u8,
+            &QsExportOptions {
+                calibrated: true,
+                ..Default::default()
+            },
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+        assert_eq!(bufs[1][..], expected[..]);
+        let mut stats = [QsWindowStats::default(); 1];
+        let mut num_windows = 1;
+        assert!(qs_window_stats(
+            measurement_id,
+            1,
+            0.0,
+            f64::INFINITY,
+            1.0,
+            stats.as_mut_ptr(),
+            &mut num_windows,
+        ));
+        assert_approx_eq!(stats[0].mean, (expected[0] + expected[1]) / 2.0);
+        qs_drop_pipeline(pipeline_id);
+
+        qs_drop_measurement(measurement_id);
+    }
+
+    /// Raw readings of a sensor with known errors pointing in each direction
+    fn poses(truth: &QsAccelCalibration) -> Vec<[f64; 3]> {
+        let s = libm::sqrt(0.5);
+        let t = libm::sqrt(1.0 / 3.0);
+        let directions = [
+            [1.0, 0.0, 0.0],
+            [-1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.0, -1.0, 0.0],
+            [0.0, 0.0, 1.0],
+            [0.0, 0.0, -1.0],
+            [s, s, 0.0],
+            [0.0, -s, s],
+            [-s, 0.0, -s],
+            [t, -t, t],
+            [-t, t, t],
+        ];
+        let [yx, zx, zy] = truth.misalignment;
+        directions
+            .iter()
+            .map(|[x, y, z]| {
+                // Invert the lower triangular misalignment, then the gains and offsets
+                let sx = *x;
+                let sy = y - yx * sx;
+                let sz = z - zx * sx - zy * sy;
+                [
+                    sx / truth.gain[0] + truth.offset[0],
+                    sy / truth.gain[1] + truth.offset[1],
+                    sz / truth.gain[2] + truth.offset[2],
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fits_offsets_gains_and_misalignment() {
+        let truth = QsAccelCalibration {
+            offset: [100.0, -50.0, 30.0],
+            gain: [1.0 / 4000.0, 1.0 / 4100.0, 1.0 / 3900.0],
+            misalignment: [0.01, -0.02, 0.015],
+            residual_g: 0.0,
+        };
+        let fitted = fit_accelerometer(&poses(&truth), true).unwrap();
+        for axis in 0..3 {
+            assert_approx_eq!(fitted.offset[axis], truth.offset[axis], 1e-3);
+            assert_approx_eq!(fitted.gain[axis] / truth.gain[axis], 1.0, 1e-6);
+            assert_approx_eq!(fitted.misalignment[axis], truth.misalignment[axis], 1e-6);
+        }
+        assert!(fitted.residual_g < 1e-9);
+
+        // Ignoring the misalignment leaves a residual error
+        let partial = fit_accelerometer(&poses(&truth), false).unwrap();
+        assert!(partial.residual_g > 1e-3);
+        assert!(fit_accelerometer(&poses(&truth)[..8], true).is_err());
+    }
+
+    #[test]
+    fn calibrates_from_static_segments() {
+        let truth = QsAccelCalibration {
+            offset: [100.0, -50.0, 30.0],
+            gain: [1.0 / 4000.0, 1.0 / 4100.0, 1.0 / 3900.0],
+            misalignment: [0.05, -0.04, 0.03],
+            residual_g: 0.0,
+        };
+        let measurement_id = qs_create_measurement(3);
+        assert!(qs_set_sample_rate(measurement_id, 10.0, 1.0));
+        // One second in each pose, with the first half second spent moving
+        let mut rng = XorShiftRng::seed_from_u64(5);
+        for (counter, pose) in poses(&truth).iter().enumerate() {
+            let samples = (0..10)
+                .map(|i| {
+                    pose.iter()
+                        .map(|v| {
+                            let noise = rng.next_u32() as f64 / u32::MAX as f64 - 0.5;
+                            let motion = if i < 5 { 2000.0 } else { 0.0 };
+                            libm::round(v + 4.0 * noise + motion) as i16
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            let raw_payload = encode_payload(counter as u32, &samples);
+            qs_add_signals(
+                measurement_id,
+                raw_payload.as_ptr(),
+                raw_payload.len() as u16,
+            );
+        }
+
+        let starts = (0..11).map(|p| p as f64 + 0.5).collect::<Vec<_>>();
+        let ends = (0..11).map(|p| p as f64 + 1.0).collect::<Vec<_>>();
+        let mut fitted = QsAccelCalibration::default();
+        let mut profiles = [QsCalibration::default(); 3];
+        assert!(qs_calibrate_accelerometer(
+            measurement_id,
+            [0, 1, 2].as_ptr(),
+            starts.as_ptr(),
+            ends.as_ptr(),
+            11,
+            true,
+            &mut fitted,
+            profiles.as_mut_ptr(),
+        ));
+        assert!(fitted.residual_g < 0.002);
+        assert_approx_eq!(fitted.gain[1] / truth.gain[1], 1.0, 0.002);
+
+        // The profiles convert the same readings to 1 g when exported
+        for (channel, profile) in profiles.iter().enumerate() {
+            assert!(qs_set_calibration(measurement_id, channel as u8, profile));
+        }
+        let mut bufs = vec![[0.0f64; 110]; 3];
+        let mut channel_data = bufs
+            .iter_mut()
+            .map(|b| b.as_mut_ptr() as *mut core::ffi::c_void)
+            .collect::<Vec<_>>();
+        let mut num_samples = 110;
+        assert!(qs_export_signals(
+            measurement_id,
+            0b111,
+            QsSampleType::F64 as u8,
+            QsLayout::Planar as u8,
+            &QsExportOptions {
+                calibrated: true,
+                ..Default::default()
+            },
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+        assert_approx_eq!(bufs[0][9], 1.0, 0.01);
+        assert_approx_eq!(bufs[2][59], -1.0, 0.01);
+        // The profiles leave out the misalignment, so an oblique pose is off
+        let s = libm::sqrt(0.5);
+        assert!(libm::fabs(bufs[1][69] - s) > 0.02);
+
+        // The full calibration converts the axes together
+        assert!(!qs_set_accel_calibration(
+            measurement_id,
+            [0, 1, 2].as_ptr(),
+            &fitted
+        ));
+        for channel in 0..3 {
+            assert!(qs_set_calibration(
+                measurement_id,
+                channel,
+                core::ptr::null()
+            ));
+        }
+        assert!(qs_set_accel_calibration(
+            measurement_id,
+            [0, 1, 2].as_ptr(),
+            &fitted
+        ));
+        assert!(!qs_set_calibration(measurement_id, 1, &profiles[1]));
+        let mut axes = [0u8; 3];
+        let mut reported = QsAccelCalibration::default();
+        assert!(qs_get_accel_calibration(
+            measurement_id,
+            1,
+            axes.as_mut_ptr(),
+            &mut reported
+        ));
+        assert_eq!(axes, [0, 1, 2]);
+        assert_eq!(reported, fitted);
+        let mut profile = QsCalibration::default();
+        assert!(!qs_get_calibration(measurement_id, 1, &mut profile));
+        assert!(qs_export_signals(
+            measurement_id,
+            0b111,
+            QsSampleType::F64 as u8,
+            QsLayout::Planar as u8,
+            &QsExportOptions {
+                calibrated: true,
+                ..Default::default()
+            },
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+        assert_approx_eq!(bufs[0][69], s, 0.005);
+        assert_approx_eq!(bufs[1][69], s, 0.005);
+        assert_approx_eq!(bufs[2][59], -1.0, 0.005);
+
+        qs_drop_measurement(measurement_id);
+    }
+}
@@ -0,0 +1,340 @@
+use super::*;
+use crate::filter::{filtfilt, Biquad, QsFilterKind};
+
+/// Settings for beat detection on ECG or seismocardiogram channels
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsBeatConfig {
+    /// Band-pass applied before the envelope is taken
+    pub band_low_hz: f64,
+    pub band_high_hz: f64,
+    /// Length of the moving average that smooths the squared signal
+    pub envelope_s: f64,
+    /// Shortest interval between beats, 0.3 s limits detection to 200 bpm
+    pub refractory_s: f64,
+    /// Position of the detection threshold between the noise and signal peak levels
+    pub threshold: f64,
+}
+
+impl Default for QsBeatConfig {
+    fn default() -> Self {
+        QsBeatConfig {
+            band_low_hz: 5.0,
+            band_high_hz: 30.0,
+            envelope_s: 0.08,
+            refractory_s: 0.3,
+            threshold: 0.4,
+        }
+    }
+}
+
+/// Heart rate and heart rate variability over the detected beats
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QsHeartRateSummary {
+    pub beats: u32,
+    pub mean_hr_bpm: f64,
+    /// Sample standard deviation of the beat to beat intervals
+    pub sdnn_ms: f64,
+    /// Root mean square of successive differences of the beat to beat intervals
+    pub rmssd_ms: f64,
+}
+
+/// Squared signal smoothed by a centered moving average of `window` samples
+fn envelope(values: &[f64], window: usize) -> Vec<f64> {
+    let mut prefix = Vec::with_capacity(values.len() + 1);
+    prefix.push(0.0);
+    for v in values.iter() {
+        prefix.push(prefix[prefix.len() - 1] + v * v);
+    }
+    (0..values.len())
+        .map(|i| {
+            let start = i.saturating_sub(window / 2);
+            let end = min(i + window - window / 2, values.len());
+            (prefix[end] - prefix[start]) / (end - start) as f64
+        })
+        .collect()
+}
+
+/// Sample indices of beats found with an adaptive threshold on the envelope
+/// peaks, where signal and noise peak levels are tracked separately
+pub(crate) fn detect_beats(
+    values: &[f64],
+    sample_rate: f64,
+    config: &QsBeatConfig,
+) -> Result<Vec<usize>, &'static str> {
+    let sections = [
+        Biquad::design(
+            QsFilterKind::HighPass,
+            sample_rate,
+            config.band_low_hz,
+            0.707,
+        )?,
+        Biquad::design(
+            QsFilterKind::LowPass,
+            sample_rate,
+            config.band_high_hz,
+            0.707,
+        )?,
+    ];
+    let window = libm::round(config.envelope_s * sample_rate).max(1.0) as usize;
+    let refractory = libm::round(config.refractory_s * sample_rate) as usize;
+    let envelope = envelope(&filtfilt(&sections, values), window);
+    if envelope.len() < 3 {
+        return Ok(Vec::new());
+    }
+
+    // Learn the initial levels from the first two seconds
+    let learning = &envelope[..min(libm::round(2.0 * sample_rate) as usize, envelope.len())];
+    let mut signal_level = learning.iter().fold(0.0, |m: f64, v| m.max(*v));
+    let mut noise_level = learning.iter().sum::<f64>() / learning.len() as f64;
+
+    let mut beats: Vec<usize> = Vec::new();
+    for i in 1..envelope.len() - 1 {
+        let peak = envelope[i];
+        if !(peak > envelope[i - 1] && peak >= envelope[i + 1]) {
+            continue;
+        }
+        if peak <= noise_level + config.threshold * (signal_level - noise_level) {
+            noise_level = 0.125 * peak + 0.875 * noise_level;
+            continue;
+        }
+        match beats.last_mut() {
+            // Keep the larger of two peaks within the refractory period
+            Some(last) if i - *last < refractory => {
+                if peak > envelope[*last] {
+                    *last = i;
+                }
+            }
+            _ => beats.push(i),
+        }
+        signal_level = 0.125 * peak + 0.875 * signal_level;
+    }
+    Ok(beats)
+}
+
+/// Mean rate and variability of the beat intervals, which need two intervals
+/// for the successive differences of RMSSD
+pub(crate) fn summarize(beat_times: &[f64]) -> Result<QsHeartRateSummary, &'static str> {
+    if beat_times.len() < 3 {
+        return Err("Too few beats to summarize, at least 3 are needed");
+    }
+    let intervals = beat_times
+        .windows(2)
+        .map(|t| t[1] - t[0])
+        .collect::<Vec<_>>();
+    let n = intervals.len() as f64;
+    let mean = intervals.iter().sum::<f64>() / n;
+    let sdnn = libm::sqrt(
+        intervals
+            .iter()
+            .map(|rr| (rr - mean) * (rr - mean))
+            .sum::<f64>()
+            / (n - 1.0),
+    );
+    let rmssd = libm::sqrt(
+        intervals
+            .windows(2)
+            .map(|rr| (rr[1] - rr[0]) * (rr[1] - rr[0]))
+            .sum::<f64>()
+            / (n - 1.0),
+    );
+    Ok(QsHeartRateSummary {
+        beats: beat_times.len() as u32,
+        mean_hr_bpm: 60.0 / mean,
+        sdnn_ms: 1000.0 * sdnn,
+        rmssd_ms: 1000.0 * rmssd,
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn qs_default_beat_config(config: *mut QsBeatConfig) -> bool {
+    if config.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    unsafe {
+        core::ptr::write(config, QsBeatConfig::default());
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_detect_beats(
+    measurement_id: u32,
+    channel: u8,
+    start_s: f64,
+    end_s: f64,
+    config: *const QsBeatConfig,
+    beat_times: *mut f64,
+    heart_rate_bpm: *mut f64,
+    num_beats: *mut u32,
+    summary: *mut QsHeartRateSummary,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        if summary.is_null() {
+            return Err("Null buffer passed to the library");
+        }
+        let config = match unsafe { config.as_ref() } {
+            Some(config) => *config,
+            None => QsBeatConfig::default(),
+        };
+        let sample_rate = measurement.sample_rate()?;
+        let range = measurement.range_indices(start_s, end_s)?;
+        let values = measurement.channel_range(channel, start_s, end_s)?;
+
+        let times = detect_beats(&values, sample_rate, &config)?
+            .into_iter()
+            .map(|i| (range.start + i) as f64 / sample_rate)
+            .collect::<Vec<_>>();
+        let rates = (0..times.len())
+            .map(|i| match i {
+                0 => f64::NAN,
+                i => 60.0 / (times[i] - times[i - 1]),
+            })
+            .collect::<Vec<_>>();
+        // The beats found are written even when there are too few to summarize
+        let result = summarize(&times);
+        if let Ok(result) = result {
+            unsafe {
+                core::ptr::write(summary, result);
+            }
+        }
+        write_buffers(
+            &[(&times[..], beat_times), (&rates[..], heart_rate_bpm)],
+            num_beats,
+        )?;
+        result.map(|_| ())
+    });
+    report(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::measurement_with_samples;
+    use assert_approx_eq::assert_approx_eq;
+    use core::f64::consts::PI;
+
+    /// Narrow QRS-like pulses at `bpm` riding on baseline wander and mains hum
+    fn ecg(bpm: f64, sample_rate: f64, seconds: f64) -> Vec<f64> {
+        let period = 60.0 / bpm;
+        (0..(seconds * sample_rate) as usize)
+            .map(|i| {
+                let t = i as f64 / sample_rate;
+                let phase = (t % period) - period / 2.0;
+                1000.0 * libm::exp(-phase * phase / (2.0 * 0.01 * 0.01))
+                    + 400.0 * libm::sin(2.0 * PI * 0.3 * t)
+                    + 30.0 * libm::sin(2.0 * PI * 50.0 * t)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn detects_ecg_beats_at_known_rate() {
+        let sample_rate = 250.0;
+        let values = ecg(75.0, sample_rate, 20.0);
+        let beats = detect_beats(&values, sample_rate, &QsBeatConfig::default()).unwrap();
+        assert_eq!(beats.len(), 25);
+        // Pulses are centered half a period into each beat
+        assert!((beats[0] as f64 - 0.4 * sample_rate).abs() <= 2.0);
+
+        let times = beats
+            .iter()
+            .map(|i| *i as f64 / sample_rate)
+            .collect::<Vec<_>>();
+        let summary = summarize(&times).unwrap();
+        assert_approx_eq!(summary.mean_hr_bpm, 75.0, 0.1);
+        assert!(summary.sdnn_ms < 5.0);
+        assert!(summary.rmssd_ms < 5.0);
+    }
+
+    #[test]
+    fn seismocardiogram_variability_through_ffi() {
+        let sample_rate = 250.0;
+
+        // Damped 25 Hz vibrations with intervals alternating between 0.75 and 0.85 seconds
+        let mut onsets = vec![0.5];
+        while onsets.len() < 30 {
+            let rr = if onsets.len() % 2 == 0 { 0.85 } else { 0.75 };
+            onsets.push(onsets[onsets.len() - 1] + rr);
+        }
+        let samples = (0..(26.0 * sample_rate) as usize)
+            .map(|i| {
+                let t = i as f64 / sample_rate;
+                let value = onsets
+                    .iter()
+                    .filter(|onset| t >= **onset)
+                    .map(|onset| {
+                        let dt = t - onset;
+                        2000.0 * libm::exp(-dt / 0.02) * libm::sin(2.0 * PI * 25.0 * dt)
+                    })
+                    .sum::<f64>();
+                vec![value as i16]
+            })
+            .collect::<Vec<_>>();
+        let measurement_id = measurement_with_samples(&samples, 250.0);
+
+        let mut config = QsBeatConfig::default();
+        assert!(qs_default_beat_config(&mut config));
+        config.band_low_hz = 10.0;
+        config.band_high_hz = 40.0;
+        let mut times = [0.0; 40];
+        let mut rates = [0.0; 40];
+        let mut num_beats = 40;
+        let mut summary = QsHeartRateSummary::default();
+        assert!(qs_detect_beats(
+            measurement_id,
+            0,
+            0.0,
+            f64::INFINITY,
+            &config,
+            times.as_mut_ptr(),
+            rates.as_mut_ptr(),
+            &mut num_beats,
+            &mut summary,
+        ));
+        let expected = onsets.iter().filter(|t| **t < 25.9).count();
+        assert_eq!(num_beats as usize, expected);
+        assert_eq!(summary.beats, num_beats);
+        assert!(rates[0].is_nan());
+        assert_approx_eq!(rates[1], 80.0, 1.0);
+        assert_approx_eq!(rates[2], 60.0 / 0.85, 1.0);
+        assert_approx_eq!(summary.rmssd_ms, 100.0, 8.0);
+        assert_approx_eq!(summary.sdnn_ms, 50.0, 5.0);
+
+        let mut num_beats = 2;
+        assert!(!qs_detect_beats(
+            measurement_id,
+            0,
+            0.0,
+            f64::INFINITY,
+            core::ptr::null(),
+            times.as_mut_ptr(),
+            rates.as_mut_ptr(),
+            &mut num_beats,
+            &mut summary,
+        ));
+        assert_eq!(num_beats as usize, expected);
+
+        // Two beats have a single interval, too few for the variability
+        let mut num_beats = 40;
+        let mut unchanged = QsHeartRateSummary::default();
+        assert!(!qs_detect_beats(
+            measurement_id,
+            0,
+            0.0,
+            1.6,
+            &config,
+            times.as_mut_ptr(),
+            rates.as_mut_ptr(),
+            &mut num_beats,
+            &mut unchanged,
+        ));
+        assert_eq!(num_beats, 2);
+        assert_eq!(unchanged, QsHeartRateSummary::default());
+        assert!(summarize(&[]).is_err());
+
+        qs_drop_measurement(measurement_id);
+    }
+}
@@ -0,0 +1,290 @@
+use super::*;
+use crate::features::QsFeatureConfig;
+
+/// Smallest segment variance relative to the whole series, so that segments of
+/// identical windows have a finite cost
+const VARIANCE_FLOOR: f64 = 1e-3;
+
+/// Settings for segmenting feature windows where their mean or variance shifts
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsChangePointConfig {
+    /// Multiplier of the BIC penalty (2 * features + 1) * ln(windows) charged per change
+    pub penalty_scale: f64,
+    /// Fewest windows allowed in a segment
+    pub min_segment_windows: u32,
+}
+
+impl Default for QsChangePointConfig {
+    fn default() -> Self {
+        QsChangePointConfig {
+            penalty_scale: 1.0,
+            min_segment_windows: 4,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QsChangePoint {
+    /// Midway between the centers of the windows either side of the change
+    pub time_s: f64,
+    /// Reduction in the segment cost, twice the gaussian negative log-likelihood,
+    /// from splitting at the change
+    pub score: f64,
+}
+
+/// Feature columns scaled to zero mean and unit variance, dropping flat columns
+/// and columns with missing values
+fn standardize(rows: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let columns = rows.first().map_or(0, |r| r.len());
+    (0..columns)
+        .filter_map(|c| {
+            let column = rows.iter().map(|r| r[c]).collect::<Vec<_>>();
+            if column.iter().any(|v| !v.is_finite()) {
+                return None;
+            }
+            let mean = column.iter().sum::<f64>() / column.len() as f64;
+            let spread = libm::sqrt(
+                column.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / column.len() as f64,
+            );
+            if spread > 0.0 {
+                Some(column.iter().map(|v| (v - mean) / spread).collect())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Gaussian cost n ln(variance) of each column of a segment, by prefix sums
+struct SegmentCost {
+    sums: Vec<Vec<f64>>,
+    squares: Vec<Vec<f64>>,
+}
+
+impl SegmentCost {
+    fn new(columns: &[Vec<f64>]) -> SegmentCost {
+        let prefix = |f: &dyn Fn(f64) -> f64| {
+            columns
+                .iter()
+                .map(|column| {
+                    let mut total = 0.0;
+                    let mut sums = vec![0.0];
+                    sums.extend(column.iter().map(|v| {
+                        total += f(*v);
+                        total
+                    }));
+                    sums
+                })
+                .collect()
+        };
+        SegmentCost {
+            sums: prefix(&|v| v),
+            squares: prefix(&|v| v * v),
+        }
+    }
+
+    fn cost(&self, start: usize, end: usize) -> f64 {
+        let n = (end - start) as f64;
+        self.sums
+            .iter()
+            .zip(self.squares.iter())
+            .map(|(sums, squares)| {
+                let mean = (sums[end] - sums[start]) / n;
+                let variance = (squares[end] - squares[start]) / n - mean * mean;
+                n * libm::log(variance.max(VARIANCE_FLOOR))
+            })
+            .sum()
+    }
+}
+
+/// Indices of the first window of each new segment, with their scores, by PELT
+pub(crate) fn segment(
+    rows: &[Vec<f64>],
+    config: &QsChangePointConfig,
+) -> Result<Vec<(usize, f64)>, &'static str> {
+    if config.penalty_scale <= 0.0 || config.penalty_scale.is_nan() {
+        return Err("Change point penalty must be positive");
+    }
+    let min_segment = config.min_segment_windows.max(1) as usize;
+    let columns = standardize(rows);
+    let n = rows.len();
+    if columns.is_empty() || n < 2 * min_segment {
+        return Ok(Vec::new());
+    }
+    let costs = SegmentCost::new(&columns);
+    let penalty = config.penalty_scale * (2 * columns.len() + 1) as f64 * libm::log(n as f64);
+
+    // best[t] is the optimal cost of the first t windows and last[t] its final change
+    let mut best = vec![f64::INFINITY; n + 1];
+    let mut last = vec![0; n + 1];
+    best[0] = -penalty;
+    let mut candidates = vec![0];
+    for t in min_segment..=n {
+        if t >= 2 * min_segment {
+            candidates.push(t - min_segment);
+        }
+        let (start, total) = candidates
+            .iter()
+            .filter(|s| t - **s >= min_segment)
+            .map(|s| (*s, best[*s] + costs.cost(*s, t) + penalty))
+            .fold((0, f64::INFINITY), |m, c| if c.1 < m.1 { c } else { m });
+        best[t] = total;
+        last[t] = start;
+        // Starts that cannot beat this one for any later end are pruned
+        candidates.retain(|s| t - *s < min_segment || best[*s] + costs.cost(*s, t) <= total);
+    }
+
+    let mut boundaries = vec![n];
+    while let Some(&end) = boundaries.last() {
+        if end == 0 {
+            break;
+        }
+        boundaries.push(last[end]);
+    }
+    boundaries.reverse();
+    Ok(boundaries
+        .windows(3)
+        .map(|b| {
+            let merged = costs.cost(b[0], b[2]);
+            (
+                b[1],
+                merged - costs.cost(b[0], b[1]) - costs.cost(b[1], b[2]),
+            )
+        })
+        .collect())
+}
+
+#[no_mangle]
+pub extern "C" fn qs_default_change_point_config(config: *mut QsChangePointConfig) -> bool {
+    if config.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    unsafe {
+        core::ptr::write(config, QsChangePointConfig::default());
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_detect_change_points(
+    measurement_id: u32,
+    channel_mask: u32,
+    start_s: f64,
+    end_s: f64,
+    feature_config: *const QsFeatureConfig,
+    config: *const QsChangePointConfig,
+    change_points: *mut QsChangePoint,
+    num_change_points: *mut u32,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        let feature_config = match unsafe { feature_config.as_ref() } {
+            Some(config) => *config,
+            None => QsFeatureConfig::default(),
+        };
+        let config = match unsafe { config.as_ref() } {
+            Some(config) => *config,
+            None => QsChangePointConfig::default(),
+        };
+        let (starts, rows) = measurement.features(channel_mask, start_s, end_s, &feature_config)?;
+        let found = segment(&rows, &config)?
+            .into_iter()
+            .map(|(window, score)| QsChangePoint {
+                time_s: (starts[window - 1] + starts[window] + feature_config.window_s) / 2.0,
+                score,
+            })
+            .collect::<Vec<_>>();
+        write_buffers(&[(&found[..], change_points)], num_change_points)
+    });
+    report(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::QsFeatureSet;
+    use crate::tests::measurement_with_samples;
+    use assert_approx_eq::assert_approx_eq;
+    use core::f64::consts::PI;
+
+    fn noise(rng: &mut XorShiftRng) -> f64 {
+        rng.next_u32() as f64 / u32::MAX as f64 - 0.5
+    }
+
+    #[test]
+    fn finds_mean_shifts_in_features() {
+        let mut rng = XorShiftRng::seed_from_u64(23);
+        let rows = (0..90)
+            .map(|i| {
+                let level = match i {
+                    0..=29 => 0.0,
+                    30..=59 => 2.0,
+                    _ => -1.0,
+                };
+                vec![level + noise(&mut rng), 5.0 + noise(&mut rng), 7.0]
+            })
+            .collect::<Vec<_>>();
+        let found = segment(&rows, &QsChangePointConfig::default()).unwrap();
+        assert_eq!(
+            found.iter().map(|(w, _)| *w).collect::<Vec<_>>(),
+            vec![30, 60]
+        );
+        assert!(found.iter().all(|(_, score)| *score > 50.0));
+
+        let stationary = rows[..30].to_vec();
+        assert!(segment(&stationary, &QsChangePointConfig::default())
+            .unwrap()
+            .is_empty());
+        let config = QsChangePointConfig {
+            penalty_scale: 0.0,
+            ..Default::default()
+        };
+        assert!(segment(&rows, &config).is_err());
+    }
+
+    #[test]
+    fn segments_a_measurement() {
+        let mut rng = XorShiftRng::seed_from_u64(29);
+        // Quiet, then moving from 40 s, then the patch comes off at 80 s
+        let samples = (0..6000)
+            .map(|i| {
+                let t = i as f64 / 50.0;
+                let v = match t {
+                    t if t < 40.0 => 100.0 * noise(&mut rng),
+                    t if t < 80.0 => 800.0 * libm::sin(2.0 * PI * t) + 100.0 * noise(&mut rng),
+                    _ => 3000.0 + 5.0 * noise(&mut rng),
+                };
+                vec![v as i16]
+            })
+            .collect::<Vec<_>>();
+        let measurement_id = measurement_with_samples(&samples, 50.0);
+
+        let features = QsFeatureConfig {
+            window_s: 2.0,
+            hop_s: 2.0,
+            feature_sets: QsFeatureSet::Statistics as u32,
+            ..Default::default()
+        };
+        let mut config = QsChangePointConfig::default();
+        assert!(qs_default_change_point_config(&mut config));
+        let mut change_points = [QsChangePoint::default(); 8];
+        let mut num_change_points = 8;
+        assert!(qs_detect_change_points(
+            measurement_id,
+            0b1,
+            0.0,
+            f64::INFINITY,
+            &features,
+            &config,
+            change_points.as_mut_ptr(),
+            &mut num_change_points,
+        ));
+        assert_eq!(num_change_points, 2);
+        assert_approx_eq!(change_points[0].time_s, 40.0, 1.0);
+        assert_approx_eq!(change_points[1].time_s, 80.0, 1.0);
+
+        qs_drop_measurement(measurement_id);
+    }
+}
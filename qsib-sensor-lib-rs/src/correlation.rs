@@ -0,0 +1,310 @@
+use super::*;
+use core::cmp::max;
+
+/// Lag at the peak of a cross-correlation
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QsLagEstimate {
+    /// Delay of the second channel behind the first, refined between samples
+    pub lag_s: f64,
+    /// Correlation at the sampled peak, from -1 to 1
+    pub correlation: f64,
+}
+
+/// Running sums of values and their squares, for statistics of any overlap
+fn prefix_sums(values: &[f64]) -> (Vec<f64>, Vec<f64>) {
+    let mut sums = vec![0.0; values.len() + 1];
+    let mut squares = vec![0.0; values.len() + 1];
+    for (i, v) in values.iter().enumerate() {
+        sums[i + 1] = sums[i] + v;
+        squares[i + 1] = squares[i] + v * v;
+    }
+    (sums, squares)
+}
+
+/// Pearson correlation of `a[i]` with `b[i + lag]` over the samples where both
+/// exist, for each lag from -max_lag to max_lag. Lags with fewer than two
+/// overlapping samples or a flat overlap have no correlation.
+pub(crate) fn cross_correlation(a: &[f64], b: &[f64], max_lag: usize) -> Vec<f64> {
+    let (a_sums, a_squares) = prefix_sums(a);
+    let (b_sums, b_squares) = prefix_sums(b);
+    (-(max_lag as isize)..=max_lag as isize)
+        .map(|lag| {
+            let start = max(0, -lag) as usize;
+            let end = min(a.len() as isize, b.len() as isize - lag).max(0) as usize;
+            if end < start + 2 {
+                return 0.0;
+            }
+            let n = (end - start) as f64;
+            let shifted = (start as isize + lag) as usize..(end as isize + lag) as usize;
+            let a_sum = a_sums[end] - a_sums[start];
+            let b_sum = b_sums[shifted.end] - b_sums[shifted.start];
+            let a_variance = a_squares[end] - a_squares[start] - a_sum * a_sum / n;
+            let b_variance = b_squares[shifted.end] - b_squares[shifted.start] - b_sum * b_sum / n;
+            if !(a_variance > 0.0 && b_variance > 0.0) {
+                return 0.0;
+            }
+            let products = a[start..end]
+                .iter()
+                .zip(b[shifted].iter())
+                .map(|(x, y)| x * y)
+                .sum::<f64>();
+            (products - a_sum * b_sum / n) / libm::sqrt(a_variance * b_variance)
+        })
+        .collect()
+}
+
+/// Lag in samples of the highest correlation, refined with a parabola through its neighbours
+pub(crate) fn peak_lag(correlation: &[f64], max_lag: usize) -> (f64, f64) {
+    let peak = (0..correlation.len())
+        .max_by(|a, b| correlation[*a].total_cmp(&correlation[*b]))
+        .unwrap_or(max_lag);
+    let offset = if peak > 0 && peak + 1 < correlation.len() {
+        let (a, b, c) = (
+            correlation[peak - 1],
+            correlation[peak],
+            correlation[peak + 1],
+        );
+        match a - 2.0 * b + c {
+            curvature if curvature < 0.0 => 0.5 * (a - c) / curvature,
+            _ => 0.0,
+        }
+    } else {
+        0.0
+    };
+    (
+        peak as f64 - max_lag as f64 + offset,
+        correlation.get(peak).map_or(0.0, |c| *c),
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn qs_cross_correlate(
+    measurement_id_a: u32,
+    channel_a: u8,
+    measurement_id_b: u32,
+    channel_b: u8,
+    start_s: f64,
+    end_s: f64,
+    offset_b_s: f64,
+    max_lag_s: f64,
+    lags_s: *mut f64,
+    correlation: *mut f64,
+    num_lags: *mut u32,
+    estimate: *mut QsLagEstimate,
+) -> bool {
+    let result = with_measurement(measurement_id_b, |other| {
+        if !offset_b_s.is_finite() {
+            return Err("Offset between measurements must be a number of seconds");
+        }
+        Ok((
+            other.sample_rate()?,
+            other.channel_range(channel_b, start_s + offset_b_s, end_s + offset_b_s)?,
+        ))
+    })
+    .and_then(|(other_rate, b)| {
+        with_measurement(measurement_id_a, |measurement| {
+            if estimate.is_null() {
+                return Err("Null buffer passed to the library");
+            }
+            if !(max_lag_s >= 0.0 && max_lag_s.is_finite()) {
+                return Err("Maximum lag must be a non-negative number of seconds");
+            }
+            let sample_rate = measurement.sample_rate()?;
+            if libm::fabs(sample_rate - other_rate) > 1e-9 * sample_rate {
+                return Err("Channels must share a sample rate to be correlated");
+            }
+            let a = measurement.channel_range(channel_a, start_s, end_s)?;
+
+            let max_lag = libm::round(max_lag_s * sample_rate) as usize;
+            if max_lag > min(a.len(), b.len()) / 2 {
+                return Err("Maximum lag must leave half of the shorter channel overlapping");
+            }
+            let values = cross_correlation(&a, &b, max_lag);
+            let lags = (0..values.len())
+                .map(|k| (k as f64 - max_lag as f64) / sample_rate)
+                .collect::<Vec<_>>();
+            let (lag, peak) = peak_lag(&values, max_lag);
+            write_buffers(&[(&lags[..], lags_s), (&values[..], correlation)], num_lags)?;
+            unsafe {
+                core::ptr::write(
+                    estimate,
+                    QsLagEstimate {
+                        lag_s: lag / sample_rate,
+                        correlation: peak,
+                    },
+                );
+            }
+            Ok(())
+        })
+    });
+    report(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::measurement_with_samples;
+    use assert_approx_eq::assert_approx_eq;
+    use core::f64::consts::PI;
+
+    /// A smooth quasi-periodic pulse wave delayed by `delay_s`
+    fn pulse(t: f64, delay_s: f64) -> f64 {
+        let t = t - delay_s;
+        1000.0 * libm::sin(2.0 * PI * 1.1 * t) + 400.0 * libm::sin(2.0 * PI * 2.3 * t + 0.7)
+            - 250.0 * libm::sin(2.0 * PI * 0.31 * t)
+    }
+
+    #[test]
+    fn finds_fractional_delays() {
+        let a = (0..2000)
+            .map(|i| pulse(i as f64 / 100.0, 0.0))
+            .collect::<Vec<_>>();
+        let b = (0..2000)
+            .map(|i| pulse(i as f64 / 100.0, 0.0734))
+            .collect::<Vec<_>>();
+        let values = cross_correlation(&a, &b, 20);
+        assert_eq!(values.len(), 41);
+        let (lag, peak) = peak_lag(&values, 20);
+        assert_approx_eq!(lag, 7.34, 0.05);
+        assert!(peak > 0.99);
+
+        // Swapping the channels reverses the lag
+        let (lag, _) = peak_lag(&cross_correlation(&b, &a, 20), 20);
+        assert_approx_eq!(lag, -7.34, 0.05);
+
+        // A flat channel correlates with nothing
+        let flat = vec![5.0; 2000];
+        assert!(cross_correlation(&a, &flat, 3).iter().all(|c| *c == 0.0));
+    }
+
+    #[test]
+    fn estimates_transit_time_between_devices() {
+        let mut ids = Vec::new();
+        for delay_s in [0.0, 0.046].iter() {
+            let samples = (0..5000)
+                .map(|i| vec![pulse(i as f64 / 250.0, *delay_s) as i16])
+                .collect::<Vec<_>>();
+            let measurement_id = measurement_with_samples(&samples, 250.0);
+            ids.push(measurement_id);
+        }
+
+        let mut lags_s = [0.0; 51];
+        let mut correlation = [0.0; 51];
+        let mut num_lags = 51;
+        let mut estimate = QsLagEstimate::default();
+        assert!(qs_cross_correlate(
+            ids[0],
+            0,
+            ids[1],
+            0,
+            2.0,
+            18.0,
+            0.0,
+            0.1,
+            lags_s.as_mut_ptr(),
+            correlation.as_mut_ptr(),
+            &mut num_lags,
+            &mut estimate,
+        ));
+        assert_eq!(num_lags, 51);
+        assert_approx_eq!(lags_s[0], -0.1);
+        assert_approx_eq!(lags_s[50], 0.1);
+        assert_approx_eq!(estimate.lag_s, 0.046, 0.001);
+        assert!(estimate.correlation > 0.99);
+
+        // Reading b 20 ms later on its own timeline leaves the remaining delay
+        assert!(qs_cross_correlate(
+            ids[0],
+            0,
+            ids[1],
+            0,
+            2.0,
+            18.0,
+            0.02,
+            0.1,
+            lags_s.as_mut_ptr(),
+            correlation.as_mut_ptr(),
+            &mut num_lags,
+            &mut estimate,
+        ));
+        assert_approx_eq!(estimate.lag_s, 0.026, 0.001);
+
+        // Nothing is written when the buffers are too small
+        let mut num_lags = 50;
+        let mut unchanged = QsLagEstimate::default();
+        assert!(!qs_cross_correlate(
+            ids[0],
+            0,
+            ids[1],
+            0,
+            2.0,
+            18.0,
+            0.0,
+            0.1,
+            lags_s.as_mut_ptr(),
+            correlation.as_mut_ptr(),
+            &mut num_lags,
+            &mut unchanged,
+        ));
+        assert_eq!(num_lags, 51);
+        assert_eq!(unchanged, QsLagEstimate::default());
+
+        // Lags beyond half of the 16 s range would be scored on too few samples
+        let mut lags_s = vec![0.0; 4001];
+        let mut correlation = vec![0.0; 4001];
+        let mut num_lags = 4001;
+        assert!(qs_cross_correlate(
+            ids[0],
+            0,
+            ids[1],
+            0,
+            2.0,
+            18.0,
+            0.0,
+            8.0,
+            lags_s.as_mut_ptr(),
+            correlation.as_mut_ptr(),
+            &mut num_lags,
+            &mut estimate,
+        ));
+        assert_eq!(num_lags, 4001);
+        assert_approx_eq!(estimate.lag_s, 0.046, 0.001);
+        let mut unchanged = QsLagEstimate::default();
+        assert!(!qs_cross_correlate(
+            ids[0],
+            0,
+            ids[1],
+            0,
+            2.0,
+            18.0,
+            0.0,
+            15.0,
+            lags_s.as_mut_ptr(),
+            correlation.as_mut_ptr(),
+            &mut num_lags,
+            &mut unchanged,
+        ));
+        assert_eq!(unchanged, QsLagEstimate::default());
+
+        assert!(qs_set_sample_rate(ids[1], 500.0, 1.0));
+        assert!(!qs_cross_correlate(
+            ids[0],
+            0,
+            ids[1],
+            0,
+            2.0,
+            18.0,
+            0.0,
+            0.1,
+            lags_s.as_mut_ptr(),
+            correlation.as_mut_ptr(),
+            &mut num_lags,
+            &mut estimate,
+        ));
+
+        for measurement_id in ids {
+            qs_drop_measurement(measurement_id);
+        }
+    }
+}
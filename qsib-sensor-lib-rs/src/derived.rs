@@ -0,0 +1,324 @@
+use super::*;
+use crate::fusion::{self, QsFusionConfig, QsOrientationOutput};
+
+/// Index of the first derived channel, following the physical channels
+pub(crate) const FIRST_DERIVED_CHANNEL: usize = 8;
+/// Number of derived channels a measurement can hold after the physical ones
+pub(crate) const MAX_DERIVED_CHANNELS: usize = 8;
+
+/// A virtual channel computed from the physical channels of a measurement
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Derivation {
+    /// Euclidean norm of the selected channels
+    Magnitude { channel_mask: u32 },
+    /// Sum of each physical channel scaled by its weight
+    WeightedSum { weights: Vec<f64> },
+    /// One channel minus another, such as a bipolar lead
+    Difference { positive: usize, negative: usize },
+    /// One component of the orientation fused from accelerometer and gyroscope channels
+    Orientation {
+        config: QsFusionConfig,
+        output: QsOrientationOutput,
+        component: usize,
+    },
+}
+
+/// Quaternions or Euler angles fused for every sample, shared between readers
+type FusedOrientation = Arc<Vec<[f64; 4]>>;
+
+/// Orientations fused for the derived channels of a measurement, valid while
+/// its generation, samples per channel and sample rate match the key
+#[derive(Default)]
+pub(crate) struct OrientationCache {
+    key: (u32, usize, f32, f32),
+    fused: Vec<(QsFusionConfig, QsOrientationOutput, FusedOrientation)>,
+}
+
+impl Measurement {
+    /// Computes a derived channel from the calibrated physical channels, from sample `start` on
+    pub(crate) fn derived_values(&self, derived: usize, start: usize) -> Vec<f64> {
+        let samples = self.samples_per_channel().saturating_sub(start);
+        match &self.derived[derived] {
+            Derivation::Magnitude { channel_mask } => {
+                let mut sum_sq = vec![0.0; samples];
+                for channel in (0..FIRST_DERIVED_CHANNEL).filter(|c| channel_mask & (1 << c) != 0) {
+                    for (s, v) in sum_sq
+                        .iter_mut()
+                        .zip(self.calibrated_values_from(channel, start))
+                    {
+                        *s += v * v;
+                    }
+                }
+                sum_sq.into_iter().map(libm::sqrt).collect()
+            }
+            Derivation::WeightedSum { weights } => {
+                let mut sum = vec![0.0; samples];
+                for (channel, weight) in weights.iter().enumerate().filter(|(_, w)| **w != 0.0) {
+                    for (s, v) in sum
+                        .iter_mut()
+                        .zip(self.calibrated_values_from(channel, start))
+                    {
+                        *s += weight * v;
+                    }
+                }
+                sum
+            }
+            Derivation::Difference { positive, negative } => self
+                .calibrated_values_from(*positive, start)
+                .into_iter()
+                .zip(self.calibrated_values_from(*negative, start))
+                .map(|(p, n)| p - n)
+                .collect(),
+            Derivation::Orientation {
+                config,
+                output,
+                component,
+            } => self
+                .orientation(config, *output)
+                .iter()
+                .skip(start)
+                .map(|o| o[*component])
+                .collect(),
+        }
+    }
+
+    /// Orientation fused once for each generation, length and sample rate of the
+    /// measurement, and shared by the channels reading its components
+    fn orientation(
+        &self,
+        config: &QsFusionConfig,
+        output: QsOrientationOutput,
+    ) -> FusedOrientation {
+        let key = (
+            self.generation,
+            self.samples_per_channel(),
+            self.hz,
+            self.rate_scaler,
+        );
+        let mut cache = self.orientations.lock();
+        if cache.key != key {
+            cache.key = key;
+            cache.fused.clear();
+        }
+        if let Some((_, _, fused)) = cache
+            .fused
+            .iter()
+            .find(|(c, o, _)| c == config && *o == output)
+        {
+            return fused.clone();
+        }
+        let fused = Arc::new(fusion::orientation(self, config, output));
+        cache.fused.push((*config, output, fused.clone()));
+        fused
+    }
+
+    /// Appends derived channels, returning the number of the first
+    fn add_derived(&mut self, derivations: Vec<Derivation>) -> Result<u8, &'static str> {
+        if self.derived.len() + derivations.len() > MAX_DERIVED_CHANNELS {
+            return Err("No more derived channels may be added to the measurement");
+        }
+        let first = FIRST_DERIVED_CHANNEL + self.derived.len();
+        self.derived.extend(derivations);
+        // Restart attached pipelines so they see the new channels
+        self.generation += 1;
+        Ok(first as u8)
+    }
+}
+
+/// Adds the derivations made by `f` while holding a write lock on the
+/// measurement, returning the first new channel or 0 on failure
+pub(crate) fn add_derived(
+    measurement_id: u32,
+    f: impl FnOnce(&Measurement) -> Result<Vec<Derivation>, &'static str>,
+) -> u8 {
+    let rw_measurement = match find_measurement_by_id(measurement_id) {
+        Some(rwm) => rwm,
+        None => {
+            push_error("No measurement exists for the given id");
+            return 0;
+        }
+    };
+    let mut measurement_guard = rw_measurement.measurement.write();
+    match f(&measurement_guard).and_then(|d| (*measurement_guard).add_derived(d)) {
+        Ok(channel) => channel,
+        Err(err) => {
+            push_error(err);
+            0
+        }
+    }
+}
+
+pub(crate) fn physical_mask(measurement: &Measurement) -> u32 {
+    measurement.channel_mask() & ((1 << FIRST_DERIVED_CHANNEL) - 1)
+}
+
+#[no_mangle]
+pub extern "C" fn qs_add_derived_magnitude(measurement_id: u32, channel_mask: u32) -> u8 {
+    add_derived(measurement_id, |measurement| {
+        if channel_mask == 0 || channel_mask & !physical_mask(measurement) != 0 {
+            return Err("Derived channels must be computed from active physical channels");
+        }
+        Ok(vec![Derivation::Magnitude { channel_mask }])
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn qs_add_derived_sum(
+    measurement_id: u32,
+    weights: *const f64,
+    num_weights: u8,
+) -> u8 {
+    add_derived(measurement_id, |measurement| {
+        if weights.is_null() {
+            return Err("Null buffer passed to the library");
+        }
+        if num_weights == 0
+            || ((1u32 << min(num_weights, 31)) - 1) & !physical_mask(measurement) != 0
+        {
+            return Err("Derived channels must be computed from active physical channels");
+        }
+        let weights = unsafe { core::slice::from_raw_parts(weights, num_weights as usize) };
+        Ok(vec![Derivation::WeightedSum {
+            weights: weights.to_vec(),
+        }])
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn qs_add_derived_difference(measurement_id: u32, positive: u8, negative: u8) -> u8 {
+    add_derived(measurement_id, |measurement| {
+        let selected = (1u32 << min(positive, 31)) | (1u32 << min(negative, 31));
+        if selected & !physical_mask(measurement) != 0 {
+            return Err("Derived channels must be computed from active physical channels");
+        }
+        Ok(vec![Derivation::Difference {
+            positive: positive as usize,
+            negative: negative as usize,
+        }])
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn qs_clear_derived(measurement_id: u32) -> bool {
+    let rw_measurement = match find_measurement_by_id(measurement_id) {
+        Some(rwm) => rwm,
+        None => {
+            push_error("No measurement exists for the given id");
+            return false;
+        }
+    };
+    let mut measurement_guard = rw_measurement.measurement.write();
+    measurement_guard.derived.clear();
+    measurement_guard.generation += 1;
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::encode_payload;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn derived_channels_export_like_physical_channels() {
+        let measurement_id = qs_create_measurement(3);
+        let samples = vec![vec![3, 4, 0], vec![-6, 8, 12], vec![0, 0, 5]];
+        let raw_payload = encode_payload(0, &samples);
+        qs_add_signals(
+            measurement_id,
+            raw_payload.as_ptr(),
+            raw_payload.len() as u16,
+        );
+
+        assert_eq!(qs_add_derived_magnitude(measurement_id, 0b011), 8);
+        assert_eq!(
+            qs_add_derived_sum(measurement_id, [0.5, 0.0, 2.0].as_ptr(), 3),
+            9
+        );
+        assert_eq!(qs_add_derived_difference(measurement_id, 2, 0), 10);
+        assert_eq!(qs_add_derived_difference(measurement_id, 3, 0), 0);
+
+        // Derived channels are selected by mask alongside physical channels
+        let mut bufs = [[0.0f64; 3]; 4];
+        let mut channel_data = bufs
+            .iter_mut()
+            .map(|b| b.as_mut_ptr() as *mut core::ffi::c_void)
+            .collect::<Vec<_>>();
+        let mut num_samples = 3;
+        assert!(qs_export_signals(
+            measurement_id,
+            0b111_0000_0001,
+            QsSampleType::F64 as u8,
+            QsLayout::Planar as u8,
+            core::ptr::null(),
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+        assert_eq!(num_samples, 3);
+        assert_eq!(bufs[0], [3.0, -6.0, 0.0]);
+        assert_approx_eq!(bufs[1][0], 5.0);
+        assert_approx_eq!(bufs[1][1], 10.0);
+        assert_eq!(bufs[2], [1.5, 21.0, 10.0]);
+        assert_eq!(bufs[3], [-3.0, 18.0, 5.0]);
+
+        // The original copy API is limited to the physical channels, whose count the caller knows
+        let mut bufs = [[0.0; 3]; 3];
+        let mut channel_data = bufs.iter_mut().map(|b| b.as_mut_ptr()).collect::<Vec<_>>();
+        assert!(qs_copy_signals(
+            measurement_id,
+            0,
+            1,
+            1,
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+        assert_eq!(bufs[2], [0.0, 12.0, 5.0]);
+
+        assert!(qs_clear_derived(measurement_id));
+        assert_eq!(qs_add_derived_difference(measurement_id, 1, 0), 8);
+
+        // Analyses accept derived channels that exist
+        assert!(qs_set_sample_rate(measurement_id, 3.0, 1.0));
+        let mut stats = [QsWindowStats::default(); 1];
+        let mut num_windows = 1;
+        assert!(qs_window_stats(
+            measurement_id,
+            8,
+            0.0,
+            f64::INFINITY,
+            1.0,
+            stats.as_mut_ptr(),
+            &mut num_windows,
+        ));
+        assert_approx_eq!(stats[0].mean, (1.0 + 14.0 + 0.0) / 3.0);
+
+        // Inputs are combined in their calibrated units, so a scaled channel is subtracted as scaled
+        let calibration = QsCalibration {
+            gain: 0.5,
+            ..Default::default()
+        };
+        assert!(qs_set_calibration(measurement_id, 1, &calibration));
+        assert!(qs_window_stats(
+            measurement_id,
+            8,
+            0.0,
+            f64::INFINITY,
+            1.0,
+            stats.as_mut_ptr(),
+            &mut num_windows,
+        ));
+        assert_approx_eq!(stats[0].mean, (-1.0 + 10.0 + 0.0) / 3.0);
+        assert!(!qs_window_stats(
+            measurement_id,
+            9,
+            0.0,
+            f64::INFINITY,
+            1.0,
+            stats.as_mut_ptr(),
+            &mut num_windows,
+        ));
+
+        qs_drop_measurement(measurement_id);
+    }
+}
@@ -0,0 +1,223 @@
+use super::*;
+
+/// Scale of the median absolute deviation to the standard deviation of normal data
+const MAD_SCALE: f64 = 1.4826;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsDespikeMethod {
+    /// Replaces samples more than threshold scaled MADs from the window median
+    Hampel = 0,
+    /// Replaces samples more than threshold counts from the window median
+    RunningMedian = 1,
+}
+
+impl QsDespikeMethod {
+    pub(crate) fn from_u8(value: u8) -> Option<QsDespikeMethod> {
+        match value {
+            0 => Some(QsDespikeMethod::Hampel),
+            1 => Some(QsDespikeMethod::RunningMedian),
+            _ => None,
+        }
+    }
+}
+
+/// Settings for replacing isolated spikes with the median of their neighbours
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsDespikeConfig {
+    pub method: u8,
+    /// Samples on each side of the one being tested
+    pub half_window: u32,
+    pub threshold: f64,
+    /// Smallest deviation Hampel filtering replaces, in the units despiked, so a one
+    /// count step in a quantized flat stretch, where the MAD is zero, is not a spike
+    pub min_deviation: f64,
+}
+
+impl Default for QsDespikeConfig {
+    fn default() -> Self {
+        QsDespikeConfig {
+            method: QsDespikeMethod::Hampel as u8,
+            half_window: 3,
+            threshold: 3.0,
+            min_deviation: 1.0,
+        }
+    }
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    let middle = values.len() / 2;
+    *values.select_nth_unstable_by(middle, f64::total_cmp).1
+}
+
+/// Replaces spikes in place, returning the number of samples changed. Each
+/// sample is tested against the original values so a replacement never
+/// hides a neighbouring spike.
+pub(crate) fn despike(config: &QsDespikeConfig, values: &mut [f64]) -> Result<u32, &'static str> {
+    let method = QsDespikeMethod::from_u8(config.method).ok_or("Unknown despike method")?;
+    if config.half_window == 0 {
+        return Err("Despike window must include neighbouring samples");
+    }
+    if config.threshold <= 0.0 || config.threshold.is_nan() {
+        return Err("Despike threshold must be positive");
+    }
+    if !(config.min_deviation >= 0.0 && config.min_deviation.is_finite()) {
+        return Err("Minimum despike deviation must be a non-negative number");
+    }
+    require_finite(values)?;
+
+    let half = config.half_window as usize;
+    let original = values.to_vec();
+    let mut window = Vec::with_capacity(2 * half + 1);
+    let mut changed = 0;
+    for (i, value) in values.iter_mut().enumerate() {
+        window.clear();
+        window.extend_from_slice(
+            &original[i.saturating_sub(half)..min(i + half + 1, original.len())],
+        );
+        let center = median(&mut window);
+        let limit = match method {
+            QsDespikeMethod::Hampel => {
+                window.iter_mut().for_each(|v| *v = libm::fabs(*v - center));
+                (config.threshold * MAD_SCALE * median(&mut window)).max(config.min_deviation)
+            }
+            QsDespikeMethod::RunningMedian => config.threshold,
+        };
+        if libm::fabs(*value - center) > limit {
+            *value = center;
+            changed += 1;
+        }
+    }
+    Ok(changed)
+}
+
+#[no_mangle]
+pub extern "C" fn qs_default_despike_config(config: *mut QsDespikeConfig) -> bool {
+    if config.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    unsafe {
+        core::ptr::write(config, QsDespikeConfig::default());
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::measurement_with_samples;
+    use core::ffi::c_void;
+
+    fn noisy_sine(samples: usize) -> Vec<f64> {
+        let mut rng = XorShiftRng::seed_from_u64(3);
+        (0..samples)
+            .map(|i| {
+                let noise = rng.next_u32() as f64 / u32::MAX as f64 - 0.5;
+                500.0 * libm::sin(i as f64 / 20.0) + 10.0 * noise
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replaces_only_spikes() {
+        let clean = noisy_sine(1000);
+        let mut values = clean.clone();
+        values[100] += 8000.0;
+        values[101] -= 3000.0;
+        values[600] = -9000.0;
+
+        let changed = despike(&QsDespikeConfig::default(), &mut values).unwrap();
+        assert_eq!(changed, 3);
+        for i in [100, 101, 600].iter() {
+            assert!(libm::fabs(values[*i] - clean[*i]) < 50.0);
+        }
+        assert_eq!(values[..100], clean[..100]);
+        assert_eq!(values[102..600], clean[102..600]);
+
+        // A flat quantized stretch has no MAD, yet a one count step is not a spike
+        let mut values = vec![12.0; 20];
+        values[10..].iter_mut().for_each(|v| *v += 1.0);
+        values[5] += 1.0;
+        assert_eq!(
+            despike(&QsDespikeConfig::default(), &mut values).unwrap(),
+            0
+        );
+
+        // A fixed threshold in counts leaves smaller steps alone
+        let mut values = clean.clone();
+        values[100] += 8000.0;
+        values[600] += 40.0;
+        let config = QsDespikeConfig {
+            method: QsDespikeMethod::RunningMedian as u8,
+            threshold: 1000.0,
+            ..Default::default()
+        };
+        assert_eq!(despike(&config, &mut values).unwrap(), 1);
+        assert_eq!(values[600], clean[600] + 40.0);
+    }
+
+    #[test]
+    fn export_reports_changed_samples_per_channel() {
+        let samples = noisy_sine(500)
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                let spike = if i % 100 == 50 { 20000.0 } else { 0.0 };
+                vec![(v + spike) as i16, *v as i16]
+            })
+            .collect::<Vec<_>>();
+        let measurement_id = measurement_with_samples(&samples, 100.0);
+        let pipeline_id = qs_create_pipeline();
+        assert!(qs_pipeline_add_filter(
+            pipeline_id,
+            0b11,
+            QsFilterKind::LowPass as u8,
+            10.0,
+            0.707
+        ));
+        assert!(qs_attach_pipeline(measurement_id, pipeline_id));
+
+        let mut bufs = vec![[0.0f64; 500]; 2];
+        let mut channel_data = bufs
+            .iter_mut()
+            .map(|b| b.as_mut_ptr() as *mut c_void)
+            .collect::<Vec<_>>();
+        let mut num_samples = 500;
+        let mut num_despiked = [0u32; 2];
+        let mut options = QsExportOptions::default();
+        assert!(qs_default_export_options(&mut options));
+        assert!(qs_default_despike_config(&mut options.despike_config));
+        options.despike = true;
+        options.num_despiked = num_despiked.as_mut_ptr();
+        assert!(qs_export_signals(
+            measurement_id,
+            0b11,
+            QsSampleType::F64 as u8,
+            QsLayout::Planar as u8,
+            &options,
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+        assert_eq!(num_despiked, [5, 0]);
+        // Spikes are removed before the attached filter can spread them
+        for (despiked, clean) in bufs[0].iter().zip(bufs[1].iter()) {
+            assert!(libm::fabs(despiked - clean) < 50.0);
+        }
+
+        options.despike_config.half_window = 0;
+        assert!(!qs_export_signals(
+            measurement_id,
+            0b11,
+            QsSampleType::F64 as u8,
+            QsLayout::Planar as u8,
+            &options,
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+
+        qs_drop_pipeline(pipeline_id);
+        qs_drop_measurement(measurement_id);
+    }
+}
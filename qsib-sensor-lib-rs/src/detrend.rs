@@ -0,0 +1,369 @@
+use super::*;
+use crate::calibration::solve;
+use crate::filter::{filtfilt, Biquad, QsFilterKind};
+use crate::pipeline::{add_stage, Stage};
+
+const MAX_ORDER: u8 = 5;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsDetrendMethod {
+    /// Least squares polynomial of the given order removed from each window
+    Polynomial = 0,
+    /// Running median over the window removed as the baseline
+    Median = 1,
+    /// Butterworth high-pass run forward and backward
+    HighPass = 2,
+}
+
+impl QsDetrendMethod {
+    pub(crate) fn from_u8(value: u8) -> Option<QsDetrendMethod> {
+        match value {
+            0 => Some(QsDetrendMethod::Polynomial),
+            1 => Some(QsDetrendMethod::Median),
+            2 => Some(QsDetrendMethod::HighPass),
+            _ => None,
+        }
+    }
+}
+
+/// Settings for removing slow drift from a time range of a channel
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsDetrendConfig {
+    pub method: u8,
+    /// Polynomial fitting window or median window, polynomials are fit over the
+    /// whole range when this is not positive
+    pub window_s: f64,
+    pub order: u8,
+    pub cutoff_hz: f64,
+    pub start_s: f64,
+    pub end_s: f64,
+}
+
+impl Default for QsDetrendConfig {
+    fn default() -> Self {
+        QsDetrendConfig {
+            method: QsDetrendMethod::Polynomial as u8,
+            window_s: 0.0,
+            order: 1,
+            cutoff_hz: 0.05,
+            start_s: 0.0,
+            end_s: f64::INFINITY,
+        }
+    }
+}
+
+impl QsDetrendConfig {
+    pub(crate) fn validate(&self) -> Result<QsDetrendMethod, &'static str> {
+        let method = QsDetrendMethod::from_u8(self.method).ok_or("Unknown detrend method")?;
+        match method {
+            QsDetrendMethod::Polynomial if self.order > MAX_ORDER => {
+                return Err("Detrend polynomials are limited to order 5")
+            }
+            QsDetrendMethod::Median if self.window_s <= 0.0 || self.window_s.is_nan() => {
+                return Err("Median detrending needs a positive window")
+            }
+            QsDetrendMethod::HighPass => {
+                Biquad::design(QsFilterKind::HighPass, f64::MAX, self.cutoff_hz, 0.707)?;
+            }
+            _ => {}
+        }
+        if self.start_s.partial_cmp(&self.end_s) != Some(core::cmp::Ordering::Less) {
+            return Err("Time range contains no samples");
+        }
+        Ok(method)
+    }
+}
+
+/// Subtracts the least squares polynomial from the values
+fn remove_polynomial(values: &mut [f64], order: usize) {
+    let n = values.len();
+    if n <= order {
+        return;
+    }
+    // Positions scaled to -1..1 keep the normal equations well conditioned
+    let x = |i: usize| match n {
+        1 => 0.0,
+        _ => 2.0 * i as f64 / (n - 1) as f64 - 1.0,
+    };
+    let terms = order + 1;
+    let mut normal = vec![vec![0.0; terms]; terms];
+    let mut rhs = vec![0.0; terms];
+    for (i, v) in values.iter().enumerate() {
+        let powers = (0..terms)
+            .map(|p| libm::pow(x(i), p as f64))
+            .collect::<Vec<_>>();
+        for row in 0..terms {
+            rhs[row] += powers[row] * v;
+            for col in 0..terms {
+                normal[row][col] += powers[row] * powers[col];
+            }
+        }
+    }
+    if let Some(coefficients) = solve(normal, rhs) {
+        for (i, v) in values.iter_mut().enumerate() {
+            *v -= coefficients.iter().rev().fold(0.0, |acc, c| acc * x(i) + c);
+        }
+    }
+}
+
+/// Subtracts a running median, evaluated every eighth of a window and
+/// interpolated between, to keep long windows affordable
+fn remove_median(values: &mut [f64], window: usize) {
+    let n = values.len();
+    let half = window / 2;
+    let hop = (window / 8).max(1);
+    let mut centers = (0..n).step_by(hop).collect::<Vec<_>>();
+    if centers.last() != Some(&(n - 1)) {
+        centers.push(n - 1);
+    }
+    let medians = centers
+        .iter()
+        .map(|c| {
+            let mut neighbours = values[c.saturating_sub(half)..min(c + half + 1, n)].to_vec();
+            let middle = neighbours.len() / 2;
+            *neighbours.select_nth_unstable_by(middle, f64::total_cmp).1
+        })
+        .collect::<Vec<_>>();
+
+    for (pair, medians) in centers.windows(2).zip(medians.windows(2)) {
+        let span = (pair[1] - pair[0]) as f64;
+        for (i, value) in values[pair[0]..pair[1]].iter_mut().enumerate() {
+            let t = i as f64 / span;
+            *value -= medians[0] + t * (medians[1] - medians[0]);
+        }
+    }
+    values[n - 1] -= medians[medians.len() - 1];
+}
+
+/// Removes drift from the samples of the configured time range, leaving the rest unchanged
+pub(crate) fn detrend(
+    config: &QsDetrendConfig,
+    sample_rate: f64,
+    values: &mut [f64],
+) -> Result<(), &'static str> {
+    let method = config.validate()?;
+    require_finite(values)?;
+    let start = min(
+        libm::ceil(config.start_s.max(0.0) * sample_rate) as usize,
+        values.len(),
+    );
+    let end = min(
+        libm::ceil(config.end_s * sample_rate) as usize,
+        values.len(),
+    );
+    if start >= end {
+        return Ok(());
+    }
+    let values = &mut values[start..end];
+    let window = libm::round(config.window_s * sample_rate) as usize;
+
+    match method {
+        QsDetrendMethod::Polynomial => {
+            // Split evenly so no window is left much shorter than the rest
+            let windows = match window {
+                0 => 1,
+                _ => libm::round(values.len() as f64 / window as f64).max(1.0) as usize,
+            };
+            let n = values.len();
+            for w in 0..windows {
+                let segment = w * n / windows..(w + 1) * n / windows;
+                remove_polynomial(&mut values[segment], config.order as usize);
+            }
+        }
+        QsDetrendMethod::Median => remove_median(values, window.max(1)),
+        QsDetrendMethod::HighPass => {
+            let section =
+                Biquad::design(QsFilterKind::HighPass, sample_rate, config.cutoff_hz, 0.707)?;
+            let filtered = filtfilt(&[section], values);
+            values.copy_from_slice(&filtered);
+        }
+    }
+    Ok(())
+}
+
+#[no_mangle]
+pub extern "C" fn qs_default_detrend_config(config: *mut QsDetrendConfig) -> bool {
+    if config.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    unsafe {
+        core::ptr::write(config, QsDetrendConfig::default());
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_pipeline_add_detrend(
+    pipeline_id: u32,
+    channel_mask: u32,
+    config: *const QsDetrendConfig,
+) -> bool {
+    let config = match unsafe { config.as_ref() } {
+        Some(config) => *config,
+        None => QsDetrendConfig::default(),
+    };
+    if let Err(err) = config.validate() {
+        push_error(err);
+        return false;
+    }
+    add_stage(
+        pipeline_id,
+        Stage::Detrend {
+            channel_mask,
+            config,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{cascade, BiquadState};
+    use crate::tests::measurement_with_samples;
+    use core::f64::consts::PI;
+
+    /// A 1 Hz sine riding on a slow quadratic drift
+    fn drifting(sample_rate: f64, seconds: f64) -> (Vec<f64>, Vec<f64>) {
+        let samples = (seconds * sample_rate) as usize;
+        let signal = (0..samples)
+            .map(|i| 100.0 * libm::sin(2.0 * PI * i as f64 / sample_rate))
+            .collect::<Vec<_>>();
+        let drifted = signal
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                let t = i as f64 / sample_rate;
+                v + 500.0 + 40.0 * t - 0.1 * t * t
+            })
+            .collect();
+        (signal, drifted)
+    }
+
+    fn worst_error(a: &[f64], b: &[f64]) -> f64 {
+        a.iter()
+            .zip(b.iter())
+            .fold(0.0, |m: f64, (x, y)| m.max(libm::fabs(x - y)))
+    }
+
+    #[test]
+    fn each_method_removes_drift() {
+        let sample_rate = 50.0;
+        let (signal, drifted) = drifting(sample_rate, 60.0);
+        let methods = [
+            QsDetrendConfig {
+                order: 2,
+                ..Default::default()
+            },
+            QsDetrendConfig {
+                window_s: 10.0,
+                ..Default::default()
+            },
+            QsDetrendConfig {
+                method: QsDetrendMethod::Median as u8,
+                window_s: 5.0,
+                ..Default::default()
+            },
+            QsDetrendConfig {
+                method: QsDetrendMethod::HighPass as u8,
+                cutoff_hz: 0.1,
+                ..Default::default()
+            },
+        ];
+        for config in methods.iter() {
+            let mut values = drifted.clone();
+            detrend(config, sample_rate, &mut values).unwrap();
+            // Skip the ends, where windows and filters see one side only. Short
+            // polynomial windows also absorb a little of the signal itself.
+            let error = worst_error(&values[500..2500], &signal[500..2500]);
+            assert!(error < 15.0, "{:?} left {}", config, error);
+        }
+
+        // Only the configured range changes
+        let mut values = drifted.clone();
+        let config = QsDetrendConfig {
+            start_s: 10.0,
+            end_s: 20.0,
+            ..Default::default()
+        };
+        detrend(&config, sample_rate, &mut values).unwrap();
+        assert_eq!(values[..500], drifted[..500]);
+        assert_eq!(values[1000..], drifted[1000..]);
+        assert!(worst_error(&values[500..1000], &signal[500..1000]) < 15.0);
+    }
+
+    #[test]
+    fn attached_detrend_matches_export_per_channel() {
+        let (_, drifted) = drifting(50.0, 60.0);
+        let samples = drifted
+            .iter()
+            .map(|v| vec![*v as i16, *v as i16])
+            .collect::<Vec<_>>();
+        let measurement_id = measurement_with_samples(&samples, 50.0);
+
+        let mut config = QsDetrendConfig::default();
+        assert!(qs_default_detrend_config(&mut config));
+        config.method = QsDetrendMethod::Median as u8;
+        config.window_s = 5.0;
+        let pipeline_id = qs_create_pipeline();
+        assert!(qs_pipeline_add_filter(
+            pipeline_id,
+            0b01,
+            QsFilterKind::LowPass as u8,
+            10.0,
+            0.707
+        ));
+        assert!(qs_pipeline_add_detrend(pipeline_id, 0b01, &config));
+        assert!(qs_pipeline_add_filter(
+            pipeline_id,
+            0b01,
+            QsFilterKind::HighPass as u8,
+            0.5,
+            0.707
+        ));
+        let mut expected = drifted.iter().map(|v| *v as i16 as f64).collect::<Vec<_>>();
+        let low_pass = Biquad::design(QsFilterKind::LowPass, 50.0, 10.0, 0.707f32 as f64).unwrap();
+        cascade(&[low_pass], &mut [BiquadState::default()], &mut expected);
+        detrend(&config, 50.0, &mut expected).unwrap();
+        let high_pass = Biquad::design(QsFilterKind::HighPass, 50.0, 0.5, 0.707f32 as f64).unwrap();
+        cascade(&[high_pass], &mut [BiquadState::default()], &mut expected);
+        config.method = 7;
+        assert!(!qs_pipeline_add_detrend(pipeline_id, 0b01, &config));
+
+        let export = |pipeline_id| {
+            let mut bufs = vec![[0.0f64; 3000]; 2];
+            let mut channel_data = bufs
+                .iter_mut()
+                .map(|b| b.as_mut_ptr() as *mut core::ffi::c_void)
+                .collect::<Vec<_>>();
+            let mut num_samples = 3000;
+            assert!(qs_export_signals(
+                measurement_id,
+                0b11,
+                QsSampleType::F64 as u8,
+                QsLayout::Planar as u8,
+                &QsExportOptions {
+                    pipeline_id,
+                    ..Default::default()
+                },
+                channel_data.as_mut_ptr(),
+                &mut num_samples,
+            ));
+            bufs
+        };
+        // Stages run in the order they were added, attached or not
+        let offline = export(pipeline_id);
+        assert_eq!(offline[0][..], expected[..]);
+        assert!(qs_attach_pipeline(measurement_id, pipeline_id));
+        let attached = export(0);
+        assert_eq!(offline[0][..], attached[0][..]);
+        assert!(libm::fabs(offline[0][1500]) < 110.0);
+        // The unselected channel keeps its drift
+        assert_eq!(offline[1][1500], drifted[1500] as i16 as f64);
+
+        qs_drop_pipeline(pipeline_id);
+        qs_drop_measurement(measurement_id);
+    }
+}
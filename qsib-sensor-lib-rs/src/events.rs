@@ -0,0 +1,293 @@
+use super::*;
+use crate::filter::{filtfilt, Biquad, QsFilterKind};
+use crate::spectral::{bin_frequencies, padded_power, QsWindow};
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsEventClass {
+    /// Short explosive bursts dominated by energy above the split frequency
+    Cough = 0,
+    /// Longer bursts dominated by energy below the split frequency
+    Swallow = 1,
+}
+
+/// Limits used to find and classify mechano-acoustic events
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsEventThresholds {
+    /// Band-pass applied before framing, the upper edge is limited to 0.45 of the sample rate
+    pub band_low_hz: f64,
+    pub band_high_hz: f64,
+    pub frame_s: f64,
+    /// Frame RMS above the median frame RMS that starts an event
+    pub threshold_db: f64,
+    /// Events separated by less than this are merged
+    pub merge_gap_s: f64,
+    /// Spectral centroid separating coughs from swallows
+    pub split_hz: f64,
+    pub cough_min_s: f64,
+    pub cough_max_s: f64,
+    pub swallow_min_s: f64,
+    pub swallow_max_s: f64,
+}
+
+impl Default for QsEventThresholds {
+    fn default() -> Self {
+        QsEventThresholds {
+            band_low_hz: 10.0,
+            band_high_hz: 800.0,
+            frame_s: 0.02,
+            threshold_db: 12.0,
+            merge_gap_s: 0.1,
+            split_hz: 150.0,
+            cough_min_s: 0.15,
+            cough_max_s: 0.8,
+            swallow_min_s: 0.3,
+            swallow_max_s: 2.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QsEvent {
+    pub start_s: f64,
+    pub end_s: f64,
+    /// Fraction of the peak frame RMS above the background, from 0 to 1
+    pub score: f64,
+    pub class: u8,
+}
+
+/// Power weighted mean frequency of a segment, or 0 Hz when it has no power
+fn spectral_centroid(values: &[f64], sample_rate: f64) -> f64 {
+    let (power, fft_length) = padded_power(values, QsWindow::Hann);
+    let frequencies = bin_frequencies(sample_rate, fft_length);
+    let total = power.iter().sum::<f64>();
+    if total <= 0.0 {
+        return 0.0;
+    }
+    power
+        .iter()
+        .zip(frequencies.iter())
+        .map(|(p, f)| p * f)
+        .sum::<f64>()
+        / total
+}
+
+/// An event's class, sample range and score
+type Event = (QsEventClass, Range<usize>, f64);
+
+/// Events in order of start
+pub(crate) fn detect_events(
+    values: &[f64],
+    sample_rate: f64,
+    thresholds: &QsEventThresholds,
+) -> Result<Vec<Event>, &'static str> {
+    let band_high_hz = thresholds.band_high_hz.min(0.45 * sample_rate);
+    let sections = [
+        Biquad::design(
+            QsFilterKind::HighPass,
+            sample_rate,
+            thresholds.band_low_hz,
+            0.707,
+        )?,
+        Biquad::design(QsFilterKind::LowPass, sample_rate, band_high_hz, 0.707)?,
+    ];
+    let frame = libm::round(thresholds.frame_s * sample_rate) as usize;
+    if frame == 0 {
+        return Err("Event frames are shorter than one sample");
+    }
+    let filtered = filtfilt(&sections, values);
+    let rms = filtered
+        .chunks(frame)
+        .map(|f| libm::sqrt(f.iter().map(|v| v * v).sum::<f64>() / f.len() as f64))
+        .collect::<Vec<_>>();
+    if rms.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut sorted = rms.clone();
+    sorted.sort_by(f64::total_cmp);
+    let background = sorted[sorted.len() / 2];
+    let threshold = background * libm::pow(10.0, thresholds.threshold_db / 20.0);
+
+    // Runs of loud frames, merging runs separated by short quiet gaps
+    let merge_gap = libm::round(thresholds.merge_gap_s * sample_rate / frame as f64) as usize;
+    let mut runs: Vec<Range<usize>> = Vec::new();
+    for (i, _) in rms.iter().enumerate().filter(|(_, r)| **r > threshold) {
+        match runs.last_mut() {
+            Some(run) if i - run.end <= merge_gap => run.end = i + 1,
+            _ => runs.push(i..i + 1),
+        }
+    }
+
+    let mut events = Vec::new();
+    for run in runs {
+        let samples = run.start * frame..min(run.end * frame, values.len());
+        let duration = (samples.end - samples.start) as f64 / sample_rate;
+        let class =
+            if spectral_centroid(&filtered[samples.clone()], sample_rate) >= thresholds.split_hz {
+                QsEventClass::Cough
+            } else {
+                QsEventClass::Swallow
+            };
+        let (min_s, max_s) = match class {
+            QsEventClass::Cough => (thresholds.cough_min_s, thresholds.cough_max_s),
+            QsEventClass::Swallow => (thresholds.swallow_min_s, thresholds.swallow_max_s),
+        };
+        if duration < min_s || duration > max_s {
+            continue;
+        }
+        let peak = rms[run].iter().fold(0.0, |m: f64, r| m.max(*r));
+        events.push((class, samples, 1.0 - background / peak));
+    }
+    Ok(events)
+}
+
+#[no_mangle]
+pub extern "C" fn qs_default_event_thresholds(thresholds: *mut QsEventThresholds) -> bool {
+    if thresholds.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    unsafe {
+        core::ptr::write(thresholds, QsEventThresholds::default());
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_detect_events(
+    measurement_id: u32,
+    channel: u8,
+    start_s: f64,
+    end_s: f64,
+    thresholds: *const QsEventThresholds,
+    events: *mut QsEvent,
+    num_events: *mut u32,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        let thresholds = match unsafe { thresholds.as_ref() } {
+            Some(thresholds) => *thresholds,
+            None => QsEventThresholds::default(),
+        };
+        let sample_rate = measurement.sample_rate()?;
+        let range = measurement.range_indices(start_s, end_s)?;
+        let values = measurement.channel_range(channel, start_s, end_s)?;
+
+        let found = detect_events(&values, sample_rate, &thresholds)?
+            .into_iter()
+            .map(|(class, samples, score)| QsEvent {
+                start_s: (range.start + samples.start) as f64 / sample_rate,
+                end_s: (range.start + samples.end) as f64 / sample_rate,
+                score,
+                class: class as u8,
+            })
+            .collect::<Vec<_>>();
+        write_buffers(&[(&found[..], events)], num_events)
+    });
+    report(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::measurement_with_samples;
+    use assert_approx_eq::assert_approx_eq;
+    use core::f64::consts::PI;
+
+    /// Background noise with a cough at 2 s and a swallow at 6 s
+    fn recording(sample_rate: f64) -> Vec<f64> {
+        let mut rng = XorShiftRng::seed_from_u64(11);
+        (0..(10.0 * sample_rate) as usize)
+            .map(|i| {
+                let t = i as f64 / sample_rate;
+                let noise = 40.0 * (rng.next_u32() as f64 / u32::MAX as f64 - 0.5);
+                let cough = if t >= 2.0 {
+                    let dt = t - 2.0;
+                    4000.0
+                        * libm::exp(-dt / 0.08)
+                        * (libm::sin(2.0 * PI * 420.0 * dt) + libm::sin(2.0 * PI * 610.0 * dt))
+                } else {
+                    0.0
+                };
+                let swallow = if (6.0..6.8).contains(&t) {
+                    let dt = t - 6.0;
+                    3000.0
+                        * libm::sin(PI * dt / 0.8)
+                        * (libm::sin(2.0 * PI * 60.0 * dt) + libm::sin(2.0 * PI * 85.0 * dt))
+                } else {
+                    0.0
+                };
+                noise + cough + swallow
+            })
+            .collect()
+    }
+
+    #[test]
+    fn separates_coughs_from_swallows() {
+        let sample_rate = 2000.0;
+        let events = detect_events(
+            &recording(sample_rate),
+            sample_rate,
+            &QsEventThresholds::default(),
+        )
+        .unwrap();
+        assert_eq!(events.len(), 2);
+
+        let (class, samples, score) = &events[0];
+        assert_eq!(*class, QsEventClass::Cough);
+        assert_approx_eq!(samples.start as f64 / sample_rate, 2.0, 0.03);
+        assert!(*score > 0.9);
+
+        let (class, samples, _) = &events[1];
+        assert_eq!(*class, QsEventClass::Swallow);
+        assert_approx_eq!(samples.start as f64 / sample_rate, 6.0, 0.1);
+        assert_approx_eq!(samples.end as f64 / sample_rate, 6.8, 0.1);
+
+        // Silence has no centroid to compare against the split
+        assert_eq!(spectral_centroid(&[0.0; 64], sample_rate), 0.0);
+    }
+
+    #[test]
+    fn events_through_ffi_respect_thresholds() {
+        let samples = recording(2000.0)
+            .iter()
+            .map(|v| vec![*v as i16])
+            .collect::<Vec<_>>();
+        let measurement_id = measurement_with_samples(&samples, 2000.0);
+
+        let mut events = [QsEvent::default(); 4];
+        let mut num_events = 4;
+        assert!(qs_detect_events(
+            measurement_id,
+            0,
+            5.0,
+            f64::INFINITY,
+            core::ptr::null(),
+            events.as_mut_ptr(),
+            &mut num_events,
+        ));
+        assert_eq!(num_events, 1);
+        assert_eq!(events[0].class, QsEventClass::Swallow as u8);
+        assert_approx_eq!(events[0].start_s, 6.0, 0.1);
+
+        // Coughs longer than the deployment allows are ignored
+        let mut thresholds = QsEventThresholds::default();
+        assert!(qs_default_event_thresholds(&mut thresholds));
+        thresholds.cough_max_s = 0.1;
+        let mut num_events = 4;
+        assert!(qs_detect_events(
+            measurement_id,
+            0,
+            0.0,
+            f64::INFINITY,
+            &thresholds,
+            events.as_mut_ptr(),
+            &mut num_events,
+        ));
+        assert_eq!(num_events, 1);
+        assert_eq!(events[0].class, QsEventClass::Swallow as u8);
+
+        qs_drop_measurement(measurement_id);
+    }
+}
@@ -0,0 +1,380 @@
+use super::*;
+use crate::despike::{despike, QsDespikeConfig};
+use crate::pipeline::{find_pipeline_by_id, Pipeline};
+use core::ffi::c_void;
+
+/// Numeric type written into caller buffers by an export
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsSampleType {
+    I16 = 0,
+    F32 = 1,
+    F64 = 2,
+}
+
+/// Arrangement of the exported channels in caller buffers
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsLayout {
+    /// One buffer per selected channel
+    Planar = 0,
+    /// One row-major buffer with a row of selected channels per sample
+    Interleaved = 1,
+}
+
+impl QsSampleType {
+    fn from_u8(value: u8) -> Option<QsSampleType> {
+        match value {
+            0 => Some(QsSampleType::I16),
+            1 => Some(QsSampleType::F32),
+            2 => Some(QsSampleType::F64),
+            _ => None,
+        }
+    }
+
+    unsafe fn write(self, buf: *mut c_void, index: usize, value: f64) {
+        match self {
+            QsSampleType::I16 => (buf as *mut i16)
+                .add(index)
+                .write(libm::round(value) as i16),
+            QsSampleType::F32 => (buf as *mut f32).add(index).write(value as f32),
+            QsSampleType::F64 => (buf as *mut f64).add(index).write(value),
+        }
+    }
+}
+
+impl QsLayout {
+    fn from_u8(value: u8) -> Option<QsLayout> {
+        match value {
+            0 => Some(QsLayout::Planar),
+            1 => Some(QsLayout::Interleaved),
+            _ => None,
+        }
+    }
+}
+
+/// Optional processing and downsampling of an export
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsExportOptions {
+    pub downsample_seed: u64,
+    pub downsample_threshold: u32,
+    pub downsample_scale: u32,
+    /// Pipeline applied from a fresh state for this export only, or 0 for none
+    pub pipeline_id: u32,
+    /// Whether channels with a calibration are converted to physical units before processing
+    pub calibrated: bool,
+    /// Whether spikes are removed with `despike_config` before any processing
+    pub despike: bool,
+    pub despike_config: QsDespikeConfig,
+    /// Receives the number of samples despiked in each exported channel, or NULL
+    pub num_despiked: *mut u32,
+}
+
+impl Default for QsExportOptions {
+    fn default() -> Self {
+        QsExportOptions {
+            downsample_seed: 0,
+            downsample_threshold: 1,
+            downsample_scale: 1,
+            pipeline_id: 0,
+            calibrated: false,
+            despike: false,
+            despike_config: QsDespikeConfig::default(),
+            num_despiked: core::ptr::null_mut(),
+        }
+    }
+}
+
+pub(crate) struct Export {
+    pub downsample_seed: u64,
+    pub downsample_threshold: u32,
+    pub downsample_scale: u32,
+    pub channel_mask: u32,
+    pub sample_type: QsSampleType,
+    pub layout: QsLayout,
+    pub pipeline: Option<Pipeline>,
+    /// Whether channels with a calibration are converted to physical units
+    pub calibrated: bool,
+    /// Spike removal applied to the samples before any processing
+    pub despike: Option<QsDespikeConfig>,
+}
+
+#[no_mangle]
+pub extern "C" fn qs_default_export_options(options: *mut QsExportOptions) -> bool {
+    if options.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    unsafe {
+        core::ptr::write(options, QsExportOptions::default());
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_export_signals(
+    measurement_id: u32,
+    channel_mask: u32,
+    sample_type: u8,
+    layout: u8,
+    options: *const QsExportOptions,
+    channel_data: *mut *mut c_void,
+    num_samples_per_channel: *mut u32,
+) -> bool {
+    if channel_data.is_null() || num_samples_per_channel.is_null() {
+        push_error("Null buffer passed to signal export");
+        return false;
+    }
+    let options = match unsafe { options.as_ref() } {
+        Some(options) => *options,
+        None => QsExportOptions::default(),
+    };
+    let sample_type = match QsSampleType::from_u8(sample_type) {
+        Some(sample_type) => sample_type,
+        None => {
+            push_error("Unknown sample type for signal export");
+            return false;
+        }
+    };
+    let layout = match QsLayout::from_u8(layout) {
+        Some(layout) => layout,
+        None => {
+            push_error("Unknown layout for signal export");
+            return false;
+        }
+    };
+    let pipeline = if options.pipeline_id == 0 {
+        None
+    } else {
+        match find_pipeline_by_id(options.pipeline_id) {
+            Some(pipeline) => Some(pipeline),
+            None => {
+                push_error("No pipeline exists for the given id");
+                return false;
+            }
+        }
+    };
+    let export = Export {
+        downsample_seed: options.downsample_seed,
+        downsample_threshold: options.downsample_threshold,
+        downsample_scale: options.downsample_scale,
+        channel_mask,
+        sample_type,
+        layout,
+        pipeline,
+        calibrated: options.calibrated,
+        despike: match options.despike {
+            true => Some(options.despike_config),
+            false => None,
+        },
+    };
+
+    let capacity = unsafe { *num_samples_per_channel };
+    let result = with_measurement(measurement_id, |measurement| {
+        let (exported, despiked) = measurement.export(&export, channel_data, capacity)?;
+        unsafe {
+            *num_samples_per_channel = exported;
+            if !options.num_despiked.is_null() {
+                core::ptr::copy_nonoverlapping(
+                    despiked.as_ptr(),
+                    options.num_despiked,
+                    despiked.len(),
+                );
+            }
+        }
+        Ok(())
+    });
+    report(result)
+}
+
+impl Measurement {
+    /// Copies the selected channels into caller buffers, returning the number of samples per channel
+    /// and the number of samples despiked in each exported channel
+    pub(crate) fn export(
+        &self,
+        export: &Export,
+        channel_data: *mut *mut c_void,
+        capacity: u32,
+    ) -> Result<(u32, Vec<u32>), &'static str> {
+        if export.downsample_scale == 0 {
+            return Err("Downsample scale must be non-zero");
+        }
+        if export.channel_mask == 0 {
+            return Err("No channels selected for export");
+        }
+        if export.channel_mask & !self.channel_mask() != 0 {
+            return Err("Channel mask selects channels that are not active");
+        }
+
+        let indices = downsample_indices(
+            self.samples_per_channel(),
+            export.downsample_seed,
+            export.downsample_threshold,
+            export.downsample_scale,
+            capacity as usize,
+        );
+        let channels = (0..32)
+            .filter(|c| export.channel_mask & (1 << c) != 0)
+            .collect::<Vec<usize>>();
+        let buffers = match export.layout {
+            QsLayout::Planar => channels.len(),
+            QsLayout::Interleaved => 1,
+        };
+        let buffers = unsafe { core::slice::from_raw_parts(channel_data, buffers) };
+        if buffers.iter().any(|b| b.is_null()) {
+            return Err("Null channel buffer passed to signal export");
+        }
+
+        let mut despiked = vec![0; channels.len()];
+        for (column, channel) in channels.iter().enumerate() {
+            let values = match &export.despike {
+                Some(config) => {
+                    let mut raw = match export.calibrated {
+                        true => self.calibrated_values_from(*channel, 0),
+                        false => self.channel_values(*channel),
+                    };
+                    despiked[column] = despike(config, &mut raw)?;
+                    self.reprocessed_values(*channel, raw, export.pipeline.as_ref())?
+                }
+                None => {
+                    self.processed_values(*channel, export.pipeline.as_ref(), export.calibrated)?
+                }
+            };
+            for (row, sample_index) in indices.iter().enumerate() {
+                let value = values[*sample_index];
+                unsafe {
+                    match export.layout {
+                        QsLayout::Planar => export.sample_type.write(buffers[column], row, value),
+                        QsLayout::Interleaved => export.sample_type.write(
+                            buffers[0],
+                            row * channels.len() + column,
+                            value,
+                        ),
+                    }
+                }
+            }
+        }
+
+        Ok((indices.len() as u32, despiked))
+    }
+}
+
+/// Selects sample indices with the same random draws used for timestamps, up to `capacity`
+pub(crate) fn downsample_indices(
+    samples: usize,
+    downsample_seed: u64,
+    downsample_threshold: u32,
+    downsample_scale: u32,
+    capacity: usize,
+) -> Vec<usize> {
+    let mut rng = XorShiftRng::seed_from_u64(downsample_seed);
+    (0..samples)
+        .filter(|_| rng.next_u32() % downsample_scale <= downsample_threshold)
+        .take(capacity)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::encode_payload;
+
+    fn measurement_with_ramp() -> u32 {
+        let measurement_id = qs_create_measurement(3);
+        for counter in 0..2 {
+            let samples = (0..4)
+                .map(|i| {
+                    let i = counter as i16 * 4 + i;
+                    vec![i, -i, 100 * i]
+                })
+                .collect::<Vec<_>>();
+            let raw_payload = encode_payload(counter, &samples);
+            qs_add_signals(
+                measurement_id,
+                raw_payload.as_ptr(),
+                raw_payload.len() as u16,
+            );
+        }
+        measurement_id
+    }
+
+    #[test]
+    fn export_channel_subset_planar_i16() {
+        let measurement_id = measurement_with_ramp();
+
+        let mut channel0 = [0i16; 8];
+        let mut channel2 = [0i16; 8];
+        let mut channel_data = [
+            channel0.as_mut_ptr() as *mut c_void,
+            channel2.as_mut_ptr() as *mut c_void,
+        ];
+        let mut num_samples = 8;
+        assert!(qs_export_signals(
+            measurement_id,
+            0b101,
+            QsSampleType::I16 as u8,
+            QsLayout::Planar as u8,
+            core::ptr::null(),
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+        assert_eq!(num_samples, 8);
+        assert_eq!(channel0, [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(channel2, [0, 100, 200, 300, 400, 500, 600, 700]);
+
+        qs_drop_measurement(measurement_id);
+    }
+
+    #[test]
+    fn export_interleaved_f32_respects_capacity() {
+        let measurement_id = measurement_with_ramp();
+
+        let mut rows = [0f32; 6];
+        let mut channel_data = [rows.as_mut_ptr() as *mut c_void];
+        let mut num_samples = 3;
+        assert!(qs_export_signals(
+            measurement_id,
+            0b011,
+            QsSampleType::F32 as u8,
+            QsLayout::Interleaved as u8,
+            core::ptr::null(),
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+        assert_eq!(num_samples, 3);
+        assert_eq!(rows, [0.0, 0.0, 1.0, -1.0, 2.0, -2.0]);
+
+        qs_drop_measurement(measurement_id);
+    }
+
+    #[test]
+    fn export_rejects_inactive_channels() {
+        let measurement_id = measurement_with_ramp();
+
+        let mut channel_data = [core::ptr::null_mut(); 4];
+        let mut num_samples = 8;
+        assert!(!qs_export_signals(
+            measurement_id,
+            0b1000,
+            QsSampleType::F64 as u8,
+            QsLayout::Planar as u8,
+            core::ptr::null(),
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+
+        qs_drop_measurement(measurement_id);
+
+        // A dropped measurement fails like any other missing id
+        assert!(!qs_export_signals(
+            measurement_id,
+            0b1,
+            QsSampleType::F64 as u8,
+            QsLayout::Planar as u8,
+            core::ptr::null(),
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+    }
+}
@@ -0,0 +1,500 @@
+use super::*;
+use crate::spectral::{bin_frequencies, fft, padded_power, Complex, QsWindow};
+use crate::stats::RunningStats;
+use alloc::string::String;
+
+const MAX_BANDS: usize = 8;
+const NAME_LENGTH: usize = 48;
+
+/// Groups of features, combined as bit flags
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsFeatureSet {
+    /// Mean, standard deviation, RMS, minimum, maximum and zero crossing rate
+    Statistics = 1,
+    /// Power between each pair of consecutive band edges
+    BandPowers = 2,
+    /// Spectral centroid, dominant frequency and normalized spectral entropy
+    Spectral = 4,
+    /// Lag and height of the first autocorrelation peak
+    Autocorrelation = 8,
+}
+
+const ALL_FEATURE_SETS: u32 = 0b1111;
+
+const STATISTICS: [&str; 6] = ["mean", "std_dev", "rms", "min", "max", "zero_crossing_rate"];
+const SPECTRAL: [&str; 3] = ["spectral_centroid_hz", "dominant_hz", "spectral_entropy"];
+const AUTOCORRELATION: [&str; 2] = ["acf_peak_lag_s", "acf_peak"];
+
+/// Windowing and feature selection for feature extraction
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsFeatureConfig {
+    pub window_s: f64,
+    pub hop_s: f64,
+    pub feature_sets: u32,
+    /// Edges of consecutive bands, only the first num_bands + 1 are used
+    pub band_edges_hz: [f64; MAX_BANDS + 1],
+    pub num_bands: u8,
+}
+
+impl Default for QsFeatureConfig {
+    fn default() -> Self {
+        let mut band_edges_hz = [0.0; MAX_BANDS + 1];
+        band_edges_hz[..6].copy_from_slice(&[0.1, 0.5, 1.0, 3.0, 10.0, 30.0]);
+        QsFeatureConfig {
+            window_s: 10.0,
+            hop_s: 5.0,
+            feature_sets: ALL_FEATURE_SETS,
+            band_edges_hz,
+            num_bands: 5,
+        }
+    }
+}
+
+/// A nul-terminated feature name
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct QsFeatureName {
+    pub name: [c_char; NAME_LENGTH],
+}
+
+impl Default for QsFeatureName {
+    fn default() -> Self {
+        QsFeatureName {
+            name: [0; NAME_LENGTH],
+        }
+    }
+}
+
+impl QsFeatureConfig {
+    pub(crate) fn validate(&self) -> Result<(), &'static str> {
+        if !(self.window_s > 0.0 && self.hop_s > 0.0) {
+            return Err("Feature windows and hops must be positive");
+        }
+        if self.feature_sets == 0 || self.feature_sets & !ALL_FEATURE_SETS != 0 {
+            return Err("Unknown feature set selection");
+        }
+        if self.num_bands as usize > MAX_BANDS {
+            return Err("Feature band powers are limited to 8 bands");
+        }
+        if self.bands().any(|(low, high)| !(low >= 0.0 && low < high)) {
+            return Err("Feature band edges must increase from zero or above");
+        }
+        Ok(())
+    }
+
+    fn has(&self, set: QsFeatureSet) -> bool {
+        self.feature_sets & set as u32 != 0
+    }
+
+    fn bands(&self) -> impl Iterator<Item = (f64, f64)> + '_ {
+        self.band_edges_hz
+            .windows(2)
+            .take(self.num_bands as usize)
+            .map(|edges| (edges[0], edges[1]))
+    }
+
+    /// Number of columns in the feature rows of the selected channels
+    pub(crate) fn num_features(&self, channel_mask: u32) -> usize {
+        selected_channels(channel_mask).len() * self.channel_names(0).len()
+    }
+
+    /// FNV-1a hash of the feature names of the selected channels, each followed by its nul
+    /// terminator, identifying the layout of the feature rows
+    pub(crate) fn layout_hash(&self, channel_mask: u32) -> u32 {
+        selected_channels(channel_mask)
+            .iter()
+            .flat_map(|c| self.channel_names(*c as usize))
+            .flat_map(|name| name.into_bytes().into_iter().chain(Some(0)))
+            .fold(0x811c_9dc5, |hash, b| {
+                (hash ^ b as u32).wrapping_mul(0x0100_0193)
+            })
+    }
+
+    /// Names of the features of one channel in the order they are extracted
+    fn channel_names(&self, channel: usize) -> Vec<String> {
+        let mut features = Vec::new();
+        if self.has(QsFeatureSet::Statistics) {
+            features.extend(STATISTICS.iter().map(|f| String::from(*f)));
+        }
+        if self.has(QsFeatureSet::BandPowers) {
+            features.extend(
+                self.bands()
+                    .map(|(low, high)| format!("band_{}_{}_hz", low, high)),
+            );
+        }
+        if self.has(QsFeatureSet::Spectral) {
+            features.extend(SPECTRAL.iter().map(|f| String::from(*f)));
+        }
+        if self.has(QsFeatureSet::Autocorrelation) {
+            features.extend(AUTOCORRELATION.iter().map(|f| String::from(*f)));
+        }
+        features
+            .iter()
+            .map(|f| format!("ch{}_{}", channel, f))
+            .collect()
+    }
+}
+
+/// Normalized autocorrelation for lags up to half the window
+pub(crate) fn autocorrelation(values: &[f64]) -> Vec<f64> {
+    let n = values.len();
+    let fft_length = (2 * n).next_power_of_two();
+    let mean = values.iter().sum::<f64>() / n as f64;
+    let mut buf = vec![Complex::default(); fft_length];
+    for (b, v) in buf.iter_mut().zip(values.iter()) {
+        b.re = v - mean;
+    }
+    fft(&mut buf);
+    // The power spectrum is real and even, so a forward transform inverts it
+    let mut power = buf
+        .iter()
+        .map(|c| Complex {
+            re: c.norm_sqr(),
+            im: 0.0,
+        })
+        .collect::<Vec<_>>();
+    fft(&mut power);
+    let zero_lag = power[0].re;
+    power[..n / 2].iter().map(|c| c.re / zero_lag).collect()
+}
+
+/// Features of one channel window in the order of `channel_names`
+fn window_features(values: &[f64], sample_rate: f64, config: &QsFeatureConfig) -> Vec<f64> {
+    let mut features = Vec::new();
+    if config.has(QsFeatureSet::Statistics) {
+        let mut stats = RunningStats::default();
+        values.iter().for_each(|v| stats.push(*v));
+        let stats = stats.finish(0.0);
+        features.extend_from_slice(&[
+            stats.mean,
+            stats.std_dev,
+            stats.rms,
+            stats.min,
+            stats.max,
+            stats.zero_crossing_rate,
+        ]);
+    }
+
+    if config.has(QsFeatureSet::BandPowers) || config.has(QsFeatureSet::Spectral) {
+        // Periodogram scaled so band powers sum to the variance in the band
+        let (power, fft_length) = padded_power(values, QsWindow::Hann);
+        let window = QsWindow::Hann.coefficients(values.len());
+        let scale = 2.0 / (fft_length as f64 * window.iter().map(|w| w * w).sum::<f64>());
+        let frequencies = bin_frequencies(sample_rate, fft_length);
+        if config.has(QsFeatureSet::BandPowers) {
+            features.extend(config.bands().map(|(low, high)| {
+                power
+                    .iter()
+                    .zip(frequencies.iter())
+                    .filter(|(_, f)| **f >= low && **f < high)
+                    .map(|(p, _)| p * scale)
+                    .sum::<f64>()
+            }));
+        }
+        if config.has(QsFeatureSet::Spectral) {
+            let total = power.iter().sum::<f64>();
+            let centroid = power
+                .iter()
+                .zip(frequencies.iter())
+                .map(|(p, f)| p * f)
+                .sum::<f64>()
+                / total;
+            let dominant = power
+                .iter()
+                .enumerate()
+                .skip(1)
+                .fold(
+                    (0, 0.0),
+                    |best, (k, p)| if *p > best.1 { (k, *p) } else { best },
+                )
+                .0;
+            let entropy = power
+                .iter()
+                .filter(|p| **p > 0.0)
+                .map(|p| -(p / total) * libm::log(p / total))
+                .sum::<f64>()
+                / libm::log(power.len() as f64);
+            features.extend_from_slice(&[centroid, frequencies[dominant], entropy]);
+        }
+    }
+
+    if config.has(QsFeatureSet::Autocorrelation) {
+        // The first local maximum after the autocorrelation first turns negative
+        let acf = autocorrelation(values);
+        let peak = acf
+            .iter()
+            .position(|r| *r < 0.0)
+            .and_then(|first_negative| {
+                (first_negative.max(1)..acf.len().saturating_sub(1))
+                    .find(|k| acf[*k] > 0.0 && acf[*k] >= acf[k - 1] && acf[*k] >= acf[k + 1])
+            });
+        match peak {
+            Some(k) => features.extend_from_slice(&[k as f64 / sample_rate, acf[k]]),
+            None => features.extend_from_slice(&[f64::NAN, f64::NAN]),
+        }
+    }
+    features
+}
+
+fn selected_channels(channel_mask: u32) -> Vec<u8> {
+    (0..32u8).filter(|c| channel_mask & (1 << c) != 0).collect()
+}
+
+impl Measurement {
+    /// The start time and feature row of every complete window in the time range
+    pub(crate) fn features(
+        &self,
+        channel_mask: u32,
+        start_s: f64,
+        end_s: f64,
+        config: &QsFeatureConfig,
+    ) -> Result<(Vec<f64>, Vec<Vec<f64>>), &'static str> {
+        config.validate()?;
+        let channels = selected_channels(channel_mask);
+        if channels.is_empty() {
+            return Err("No channels selected for feature extraction");
+        }
+        let sample_rate = self.sample_rate()?;
+        let range = self.range_indices(start_s, end_s)?;
+        let window = libm::round(config.window_s * sample_rate) as usize;
+        let hop = (libm::round(config.hop_s * sample_rate) as usize).max(1);
+        if window < 2 {
+            return Err("Feature windows are shorter than two samples");
+        }
+
+        let values = channels
+            .iter()
+            .map(|c| self.channel_range(*c, start_s, end_s))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut starts = Vec::new();
+        let mut rows = Vec::new();
+        let mut offset = 0;
+        while offset + window <= range.end - range.start {
+            starts.push((range.start + offset) as f64 / sample_rate);
+            rows.push(
+                values
+                    .iter()
+                    .flat_map(|channel| {
+                        window_features(&channel[offset..offset + window], sample_rate, config)
+                    })
+                    .collect(),
+            );
+            offset += hop;
+        }
+        Ok((starts, rows))
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn qs_default_feature_config(config: *mut QsFeatureConfig) -> bool {
+    if config.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    unsafe {
+        core::ptr::write(config, QsFeatureConfig::default());
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_feature_names(
+    channel_mask: u32,
+    config: *const QsFeatureConfig,
+    names: *mut QsFeatureName,
+    num_features: *mut u32,
+) -> bool {
+    let config = match unsafe { config.as_ref() } {
+        Some(config) => *config,
+        None => QsFeatureConfig::default(),
+    };
+    let result = config.validate().and_then(|_| {
+        let found = selected_channels(channel_mask)
+            .iter()
+            .flat_map(|c| config.channel_names(*c as usize))
+            .map(|name| {
+                let mut feature = QsFeatureName::default();
+                for (c, b) in feature.name[..NAME_LENGTH - 1].iter_mut().zip(name.bytes()) {
+                    *c = b as c_char;
+                }
+                feature
+            })
+            .collect::<Vec<_>>();
+        write_buffers(&[(&found[..], names)], num_features)
+    });
+    report(result)
+}
+
+#[no_mangle]
+pub extern "C" fn qs_feature_layout_hash(
+    channel_mask: u32,
+    config: *const QsFeatureConfig,
+    hash: *mut u32,
+) -> bool {
+    let config = match unsafe { config.as_ref() } {
+        Some(config) => *config,
+        None => QsFeatureConfig::default(),
+    };
+    let result = match hash.is_null() {
+        true => Err("Null buffer passed to the library"),
+        false => config.validate().map(|_| unsafe {
+            *hash = config.layout_hash(channel_mask);
+        }),
+    };
+    report(result)
+}
+
+#[no_mangle]
+pub extern "C" fn qs_extract_features(
+    measurement_id: u32,
+    channel_mask: u32,
+    start_s: f64,
+    end_s: f64,
+    config: *const QsFeatureConfig,
+    window_start_s: *mut f64,
+    features: *mut f64,
+    num_windows: *mut u32,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        let config = match unsafe { config.as_ref() } {
+            Some(config) => *config,
+            None => QsFeatureConfig::default(),
+        };
+        let (starts, rows) = measurement.features(channel_mask, start_s, end_s, &config)?;
+        if features.is_null() {
+            return Err("Null buffer passed to the library");
+        }
+        write_buffers(&[(&starts[..], window_start_s)], num_windows)?;
+        let matrix = rows.concat();
+        unsafe {
+            core::ptr::copy_nonoverlapping(matrix.as_ptr(), features, matrix.len());
+        }
+        Ok(())
+    });
+    report(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::measurement_with_samples;
+    use assert_approx_eq::assert_approx_eq;
+    use core::f64::consts::PI;
+
+    fn name(feature: &QsFeatureName) -> String {
+        feature
+            .name
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as u8 as char)
+            .collect()
+    }
+
+    #[test]
+    fn names_are_stable_and_ordered() {
+        let mut names = [QsFeatureName::default(); 40];
+        let mut num_features = 40;
+        assert!(qs_feature_names(
+            0b101,
+            core::ptr::null(),
+            names.as_mut_ptr(),
+            &mut num_features
+        ));
+        assert_eq!(num_features, 32);
+        assert_eq!(name(&names[0]), "ch0_mean");
+        assert_eq!(name(&names[8]), "ch0_band_1_3_hz");
+        assert_eq!(name(&names[14]), "ch0_acf_peak_lag_s");
+        assert_eq!(name(&names[16]), "ch2_mean");
+        assert_eq!(name(&names[31]), "ch2_acf_peak");
+
+        let mut config = QsFeatureConfig::default();
+        assert!(qs_default_feature_config(&mut config));
+        config.feature_sets = QsFeatureSet::Spectral as u32 | QsFeatureSet::Autocorrelation as u32;
+        let mut num_features = 40;
+        assert!(qs_feature_names(
+            0b10,
+            &config,
+            names.as_mut_ptr(),
+            &mut num_features
+        ));
+        assert_eq!(num_features, 5);
+        assert_eq!(name(&names[0]), "ch1_spectral_centroid_hz");
+
+        // The layout hash covers the names, not just their number
+        let mut hash = 0;
+        assert!(qs_feature_layout_hash(0b10, &config, &mut hash));
+        assert_eq!(hash, config.layout_hash(0b10));
+        assert_ne!(hash, config.layout_hash(0b100));
+        config.num_bands = 5;
+        config.feature_sets = QsFeatureSet::BandPowers as u32;
+        assert_ne!(hash, config.layout_hash(0b10));
+
+        config.feature_sets = 16;
+        assert!(!qs_feature_names(
+            0b10,
+            &config,
+            names.as_mut_ptr(),
+            &mut num_features
+        ));
+    }
+
+    #[test]
+    fn extracts_a_feature_matrix() {
+        let samples = (0..2000)
+            .map(|i| {
+                let t = i as f64 / 100.0;
+                vec![(1000.0 * libm::sin(2.0 * PI * 2.0 * t)) as i16, 250]
+            })
+            .collect::<Vec<_>>();
+        let measurement_id = measurement_with_samples(&samples, 100.0);
+
+        let config = QsFeatureConfig {
+            window_s: 4.0,
+            hop_s: 2.0,
+            ..Default::default()
+        };
+        let mut starts = [0.0; 16];
+        let mut features = vec![0.0; 16 * 32];
+        let mut num_windows = 16;
+        assert!(qs_extract_features(
+            measurement_id,
+            0b11,
+            0.0,
+            f64::INFINITY,
+            &config,
+            starts.as_mut_ptr(),
+            features.as_mut_ptr(),
+            &mut num_windows,
+        ));
+        assert_eq!(num_windows, 9);
+        assert_approx_eq!(starts[8], 16.0);
+
+        let row = &features[32 * 3..32 * 4];
+        assert_approx_eq!(row[0], 0.0, 1.0);
+        assert_approx_eq!(row[1], 707.0, 2.0);
+        assert_approx_eq!(row[8] / 500_000.0, 1.0, 0.02);
+        assert_approx_eq!(row[12], 2.0, 0.2);
+        assert_approx_eq!(row[14], 0.5, 0.01);
+        assert!(row[15] > 0.8);
+        // A constant channel has its mean but no periodicity
+        assert_approx_eq!(row[16], 250.0);
+        assert!(row[30].is_nan());
+
+        let mut num_windows = 4;
+        assert!(!qs_extract_features(
+            measurement_id,
+            0b11,
+            0.0,
+            f64::INFINITY,
+            &config,
+            starts.as_mut_ptr(),
+            features.as_mut_ptr(),
+            &mut num_windows,
+        ));
+        assert_eq!(num_windows, 9);
+
+        qs_drop_measurement(measurement_id);
+    }
+}
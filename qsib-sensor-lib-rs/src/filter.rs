@@ -0,0 +1,219 @@
+use alloc::vec::Vec;
+use core::{cmp::min, f64::consts::PI};
+
+/// Second order section shapes designed from a cutoff (or center) frequency and Q
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsFilterKind {
+    LowPass = 0,
+    HighPass = 1,
+    BandPass = 2,
+    Notch = 3,
+}
+
+impl QsFilterKind {
+    pub(crate) fn from_u8(value: u8) -> Option<QsFilterKind> {
+        match value {
+            0 => Some(QsFilterKind::LowPass),
+            1 => Some(QsFilterKind::HighPass),
+            2 => Some(QsFilterKind::BandPass),
+            3 => Some(QsFilterKind::Notch),
+            _ => None,
+        }
+    }
+}
+
+/// Normalized biquad coefficients (a0 = 1)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Biquad {
+    pub b0: f64,
+    pub b1: f64,
+    pub b2: f64,
+    pub a1: f64,
+    pub a2: f64,
+}
+
+/// Transposed direct form II delay line for one biquad
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct BiquadState {
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    /// Designs a section with the RBJ audio EQ cookbook formulas
+    pub fn design(
+        kind: QsFilterKind,
+        sample_rate: f64,
+        cutoff_hz: f64,
+        q: f64,
+    ) -> Result<Biquad, &'static str> {
+        if !(cutoff_hz > 0.0 && cutoff_hz < sample_rate / 2.0) {
+            return Err("Filter cutoff must be between 0 and the Nyquist frequency");
+        }
+        if q <= 0.0 || q.is_nan() {
+            return Err("Filter Q must be positive");
+        }
+
+        let w0 = 2.0 * PI * cutoff_hz / sample_rate;
+        let cos_w0 = libm::cos(w0);
+        let alpha = libm::sin(w0) / (2.0 * q);
+        let (b0, b1, b2) = match kind {
+            QsFilterKind::LowPass => ((1.0 - cos_w0) / 2.0, 1.0 - cos_w0, (1.0 - cos_w0) / 2.0),
+            QsFilterKind::HighPass => ((1.0 + cos_w0) / 2.0, -(1.0 + cos_w0), (1.0 + cos_w0) / 2.0),
+            QsFilterKind::BandPass => (alpha, 0.0, -alpha),
+            QsFilterKind::Notch => (1.0, -2.0 * cos_w0, 1.0),
+        };
+        let a0 = 1.0 + alpha;
+
+        Ok(Biquad {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: -2.0 * cos_w0 / a0,
+            a2: (1.0 - alpha) / a0,
+        })
+    }
+
+    /// Delay line that has settled on a constant input `x`, along with the output
+    pub(crate) fn settled(&self, x: f64) -> (BiquadState, f64) {
+        let y = x * (self.b0 + self.b1 + self.b2) / (1.0 + self.a1 + self.a2);
+        let state = BiquadState {
+            z1: y - self.b0 * x,
+            z2: self.b2 * x - self.a2 * y,
+        };
+        (state, y)
+    }
+
+    pub fn process(&self, state: &mut BiquadState, x: f64) -> f64 {
+        let y = self.b0 * x + state.z1;
+        state.z1 = self.b1 * x - self.a1 * y + state.z2;
+        state.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+/// Runs `values` through each section in order, continuing from `states`
+pub(crate) fn cascade(sections: &[Biquad], states: &mut [BiquadState], values: &mut [f64]) {
+    for v in values.iter_mut() {
+        *v = sections
+            .iter()
+            .zip(states.iter_mut())
+            .fold(*v, |x, (section, state)| section.process(state, x));
+    }
+}
+
+/// Filters forward and then backward for zero phase shift. The ends are
+/// extended by odd reflection and the filters start settled on the first
+/// sample to keep start-up transients out of the result.
+pub(crate) fn filtfilt(sections: &[Biquad], values: &[f64]) -> Vec<f64> {
+    if values.is_empty() {
+        return Vec::new();
+    }
+    let pad = min(values.len() - 1, 6 * sections.len() + 3);
+    let (first, last) = (values[0], values[values.len() - 1]);
+    let mut padded = Vec::with_capacity(values.len() + 2 * pad);
+    padded.extend(values[1..=pad].iter().rev().map(|v| 2.0 * first - v));
+    padded.extend_from_slice(values);
+    padded.extend(
+        values[values.len() - 1 - pad..values.len() - 1]
+            .iter()
+            .rev()
+            .map(|v| 2.0 * last - v),
+    );
+
+    for _ in 0..2 {
+        let mut input = padded[0];
+        let mut states = sections
+            .iter()
+            .map(|section| {
+                let (state, output) = section.settled(input);
+                input = output;
+                state
+            })
+            .collect::<Vec<_>>();
+        cascade(sections, &mut states, &mut padded);
+        padded.reverse();
+    }
+    padded.truncate(padded.len() - pad);
+    padded.drain(..pad);
+    padded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn sine(hz: f64, sample_rate: f64, samples: usize) -> Vec<f64> {
+        (0..samples)
+            .map(|i| libm::sin(2.0 * PI * hz * i as f64 / sample_rate))
+            .collect()
+    }
+
+    fn amplitude(values: &[f64]) -> f64 {
+        values.iter().fold(0.0, |m: f64, v| m.max(v.abs()))
+    }
+
+    #[test]
+    fn sections_pass_and_reject_expected_bands() {
+        let sample_rate = 500.0;
+        let cases = [
+            (QsFilterKind::LowPass, 10.0, 2.0, 100.0),
+            (QsFilterKind::HighPass, 10.0, 100.0, 0.5),
+            (QsFilterKind::BandPass, 20.0, 20.0, 240.0),
+            (QsFilterKind::Notch, 60.0, 20.0, 60.0),
+        ];
+        for (kind, cutoff, pass_hz, stop_hz) in cases.iter() {
+            let section = Biquad::design(*kind, sample_rate, *cutoff, 0.707).unwrap();
+            let mut pass = sine(*pass_hz, sample_rate, 4000);
+            let mut stop = sine(*stop_hz, sample_rate, 4000);
+            cascade(&[section], &mut [BiquadState::default()], &mut pass);
+            cascade(&[section], &mut [BiquadState::default()], &mut stop);
+            assert!(amplitude(&pass[2000..]) > 0.7, "{:?} passband", kind);
+            assert!(amplitude(&stop[2000..]) < 0.1, "{:?} stopband", kind);
+        }
+    }
+
+    #[test]
+    fn incremental_filtering_matches_single_pass() {
+        let sections = [
+            Biquad::design(QsFilterKind::HighPass, 250.0, 0.5, 0.707).unwrap(),
+            Biquad::design(QsFilterKind::Notch, 250.0, 50.0, 30.0).unwrap(),
+        ];
+        let signal = sine(7.0, 250.0, 1000);
+
+        let mut whole = signal.clone();
+        cascade(&sections, &mut [BiquadState::default(); 2], &mut whole);
+
+        let mut states = [BiquadState::default(); 2];
+        let mut pieces = signal.clone();
+        for chunk in pieces.chunks_mut(37) {
+            cascade(&sections, &mut states, chunk);
+        }
+        assert_eq!(whole, pieces);
+    }
+
+    #[test]
+    fn filtfilt_has_no_phase_shift() {
+        let sections = [
+            Biquad::design(QsFilterKind::HighPass, 250.0, 1.0, 0.707).unwrap(),
+            Biquad::design(QsFilterKind::LowPass, 250.0, 30.0, 0.707).unwrap(),
+        ];
+        let signal = sine(10.0, 250.0, 1000)
+            .iter()
+            .map(|v| v + 500.0)
+            .collect::<Vec<_>>();
+        let filtered = filtfilt(&sections, &signal);
+        assert_eq!(filtered.len(), signal.len());
+        for (f, s) in filtered.iter().zip(signal.iter()).skip(250).take(500) {
+            assert!((f - (s - 500.0)).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn design_rejects_cutoff_above_nyquist() {
+        assert!(Biquad::design(QsFilterKind::LowPass, 100.0, 50.0, 0.707).is_err());
+        assert!(Biquad::design(QsFilterKind::LowPass, 100.0, 10.0, 0.0).is_err());
+    }
+}
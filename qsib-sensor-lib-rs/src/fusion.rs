@@ -0,0 +1,404 @@
+use super::*;
+use crate::derived::{add_derived, physical_mask, Derivation};
+use core::f64::consts::PI;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsFusionAlgorithm {
+    /// Gradient descent correction toward gravity, weighted by beta
+    Madgwick = 0,
+    /// Proportional and integral feedback of the gravity error, weighted by kp and ki
+    Mahony = 1,
+}
+
+impl QsFusionAlgorithm {
+    pub(crate) fn from_u8(value: u8) -> Option<QsFusionAlgorithm> {
+        match value {
+            0 => Some(QsFusionAlgorithm::Madgwick),
+            1 => Some(QsFusionAlgorithm::Mahony),
+            _ => None,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsOrientationOutput {
+    /// Four channels holding w, x, y and z of a unit quaternion
+    Quaternion = 0,
+    /// Three channels holding roll, pitch and yaw in degrees
+    Euler = 1,
+}
+
+impl QsOrientationOutput {
+    pub(crate) fn from_u8(value: u8) -> Option<QsOrientationOutput> {
+        match value {
+            0 => Some(QsOrientationOutput::Quaternion),
+            1 => Some(QsOrientationOutput::Euler),
+            _ => None,
+        }
+    }
+}
+
+/// Sensor channels and settings for fusing orientation
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsFusionConfig {
+    /// Channels of the x, y and z accelerometer axes
+    pub accel: [u8; 3],
+    /// Channels of the x, y and z gyroscope axes
+    pub gyro: [u8; 3],
+    /// Full-scale ranges reached at the largest 16-bit count
+    pub accel_full_scale_g: f64,
+    pub gyro_full_scale_dps: f64,
+    pub algorithm: u8,
+    pub beta: f64,
+    pub kp: f64,
+    pub ki: f64,
+}
+
+impl Default for QsFusionConfig {
+    fn default() -> Self {
+        QsFusionConfig {
+            accel: [0, 1, 2],
+            gyro: [3, 4, 5],
+            accel_full_scale_g: 8.0,
+            gyro_full_scale_dps: 2000.0,
+            algorithm: QsFusionAlgorithm::Madgwick as u8,
+            beta: 0.1,
+            kp: 1.0,
+            ki: 0.0,
+        }
+    }
+}
+
+type Quaternion = [f64; 4];
+
+fn normalize(q: Quaternion) -> Quaternion {
+    let norm = libm::sqrt(q.iter().map(|v| v * v).sum::<f64>());
+    match norm {
+        norm if norm > 0.0 => [q[0] / norm, q[1] / norm, q[2] / norm, q[3] / norm],
+        _ => q,
+    }
+}
+
+/// Rate of change of `q` when rotating at `gyro` in rad/s
+fn rate(q: Quaternion, gyro: [f64; 3]) -> Quaternion {
+    let [w, x, y, z] = q;
+    let [gx, gy, gz] = gyro;
+    [
+        0.5 * (-x * gx - y * gy - z * gz),
+        0.5 * (w * gx + y * gz - z * gy),
+        0.5 * (w * gy - x * gz + z * gx),
+        0.5 * (w * gz + x * gy - y * gx),
+    ]
+}
+
+/// Orientation with zero yaw that puts gravity along the measured acceleration
+fn from_gravity(accel: [f64; 3]) -> Quaternion {
+    let [ax, ay, az] = accel;
+    let roll = libm::atan2(ay, az);
+    let pitch = libm::atan2(-ax, libm::sqrt(ay * ay + az * az));
+    let (sr, cr) = (libm::sin(roll / 2.0), libm::cos(roll / 2.0));
+    let (sp, cp) = (libm::sin(pitch / 2.0), libm::cos(pitch / 2.0));
+    [cr * cp, sr * cp, cr * sp, -sr * sp]
+}
+
+/// Fusion filter state advanced one sample at a time
+pub(crate) struct Fusion {
+    algorithm: QsFusionAlgorithm,
+    beta: f64,
+    kp: f64,
+    ki: f64,
+    q: Quaternion,
+    integral: [f64; 3],
+}
+
+impl Fusion {
+    pub fn new(config: &QsFusionConfig, accel: [f64; 3]) -> Result<Fusion, &'static str> {
+        Ok(Fusion {
+            algorithm: QsFusionAlgorithm::from_u8(config.algorithm)
+                .ok_or("Unknown fusion algorithm")?,
+            beta: config.beta,
+            kp: config.kp,
+            ki: config.ki,
+            q: from_gravity(accel),
+            integral: [0.0; 3],
+        })
+    }
+
+    /// Advances by `dt` seconds given acceleration in any unit and rotation in rad/s
+    pub fn update(&mut self, accel: [f64; 3], gyro: [f64; 3], dt: f64) -> Quaternion {
+        let norm = libm::sqrt(accel.iter().map(|v| v * v).sum::<f64>());
+        let [w, x, y, z] = self.q;
+        let mut q_dot;
+        match self.algorithm {
+            QsFusionAlgorithm::Madgwick => {
+                q_dot = rate(self.q, gyro);
+                if norm > 0.0 {
+                    let [ax, ay, az] = [accel[0] / norm, accel[1] / norm, accel[2] / norm];
+                    // Gradient of the error between estimated and measured gravity
+                    let f = [
+                        2.0 * (x * z - w * y) - ax,
+                        2.0 * (w * x + y * z) - ay,
+                        2.0 * (0.5 - x * x - y * y) - az,
+                    ];
+                    let step = normalize([
+                        -2.0 * y * f[0] + 2.0 * x * f[1],
+                        2.0 * z * f[0] + 2.0 * w * f[1] - 4.0 * x * f[2],
+                        -2.0 * w * f[0] + 2.0 * z * f[1] - 4.0 * y * f[2],
+                        2.0 * x * f[0] + 2.0 * y * f[1],
+                    ]);
+                    for (d, s) in q_dot.iter_mut().zip(step.iter()) {
+                        *d -= self.beta * s;
+                    }
+                }
+            }
+            QsFusionAlgorithm::Mahony => {
+                let mut gyro = gyro;
+                if norm > 0.0 {
+                    let [ax, ay, az] = [accel[0] / norm, accel[1] / norm, accel[2] / norm];
+                    let v = [
+                        2.0 * (x * z - w * y),
+                        2.0 * (w * x + y * z),
+                        w * w - x * x - y * y + z * z,
+                    ];
+                    let error = [
+                        ay * v[2] - az * v[1],
+                        az * v[0] - ax * v[2],
+                        ax * v[1] - ay * v[0],
+                    ];
+                    for i in 0..3 {
+                        self.integral[i] += self.ki * error[i] * dt;
+                        gyro[i] += self.kp * error[i] + self.integral[i];
+                    }
+                }
+                q_dot = rate(self.q, gyro);
+            }
+        }
+        self.q = normalize([
+            w + q_dot[0] * dt,
+            x + q_dot[1] * dt,
+            y + q_dot[2] * dt,
+            z + q_dot[3] * dt,
+        ]);
+        self.q
+    }
+}
+
+/// Roll, pitch and yaw in degrees of a unit quaternion
+pub(crate) fn euler(q: Quaternion) -> [f64; 3] {
+    let [w, x, y, z] = q;
+    let roll = libm::atan2(2.0 * (w * x + y * z), 1.0 - 2.0 * (x * x + y * y));
+    let pitch = libm::asin((2.0 * (w * y - z * x)).clamp(-1.0, 1.0));
+    let yaw = libm::atan2(2.0 * (w * z + x * y), 1.0 - 2.0 * (y * y + z * z));
+    [roll * 180.0 / PI, pitch * 180.0 / PI, yaw * 180.0 / PI]
+}
+
+/// Orientation at every sample of a measurement, with Euler angles in the first three
+/// components. Every component is NaN while the sample rate is not set.
+pub(crate) fn orientation(
+    measurement: &Measurement,
+    config: &QsFusionConfig,
+    output: QsOrientationOutput,
+) -> Vec<[f64; 4]> {
+    let samples = measurement.samples_per_channel();
+    let sample_rate = match measurement.sample_rate() {
+        Ok(sample_rate) if samples > 0 => sample_rate,
+        _ => return vec![[f64::NAN; 4]; samples],
+    };
+    let accel_scale = config.accel_full_scale_g / 32768.0;
+    let gyro_scale = config.gyro_full_scale_dps / 32768.0 * PI / 180.0;
+    let read = |channels: [u8; 3], scale: f64| {
+        channels
+            .iter()
+            .map(|c| {
+                measurement
+                    .channel_values(*c as usize)
+                    .into_iter()
+                    .map(|v| v * scale)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    };
+    let accel = read(config.accel, accel_scale);
+    let gyro = read(config.gyro, gyro_scale);
+
+    let at = |axes: &Vec<Vec<f64>>, i: usize| [axes[0][i], axes[1][i], axes[2][i]];
+    let mut fusion = match Fusion::new(config, at(&accel, 0)) {
+        Ok(fusion) => fusion,
+        Err(_) => return vec![[f64::NAN; 4]; samples],
+    };
+    let mut q = fusion.q;
+    (0..samples)
+        .map(|i| {
+            if i > 0 {
+                q = fusion.update(at(&accel, i), at(&gyro, i), 1.0 / sample_rate);
+            }
+            match output {
+                QsOrientationOutput::Quaternion => q,
+                QsOrientationOutput::Euler => {
+                    let [roll, pitch, yaw] = euler(q);
+                    [roll, pitch, yaw, 0.0]
+                }
+            }
+        })
+        .collect()
+}
+
+#[no_mangle]
+pub extern "C" fn qs_default_fusion_config(config: *mut QsFusionConfig) -> bool {
+    if config.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    unsafe {
+        core::ptr::write(config, QsFusionConfig::default());
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_add_derived_orientation(
+    measurement_id: u32,
+    config: *const QsFusionConfig,
+    output: u8,
+) -> u8 {
+    add_derived(measurement_id, |measurement| {
+        let config = match unsafe { config.as_ref() } {
+            Some(config) => *config,
+            None => QsFusionConfig::default(),
+        };
+        let output = QsOrientationOutput::from_u8(output).ok_or("Unknown orientation output")?;
+        QsFusionAlgorithm::from_u8(config.algorithm).ok_or("Unknown fusion algorithm")?;
+        let selected = config
+            .accel
+            .iter()
+            .chain(config.gyro.iter())
+            .fold(0u32, |mask, c| mask | (1u32 << min(*c, 31)));
+        if selected & !physical_mask(measurement) != 0 {
+            return Err("Derived channels must be computed from active physical channels");
+        }
+        let components = match output {
+            QsOrientationOutput::Quaternion => 4,
+            QsOrientationOutput::Euler => 3,
+        };
+        Ok((0..components)
+            .map(|component| Derivation::Orientation {
+                config,
+                output,
+                component,
+            })
+            .collect())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{encode_payload, measurement_with_samples};
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn fusion_holds_tilt_and_integrates_yaw() {
+        for algorithm in [QsFusionAlgorithm::Madgwick, QsFusionAlgorithm::Mahony].iter() {
+            let config = QsFusionConfig {
+                algorithm: *algorithm as u8,
+                ..Default::default()
+            };
+            let tilted = [0.0, libm::sin(PI / 6.0), libm::cos(PI / 6.0)];
+            let mut fusion = Fusion::new(&config, tilted).unwrap();
+            let mut q = fusion.q;
+            for _ in 0..100 {
+                q = fusion.update(tilted, [0.0; 3], 0.01);
+            }
+            let [roll, pitch, yaw] = euler(q);
+            assert_approx_eq!(roll, 30.0, 0.1);
+            assert_approx_eq!(pitch, 0.0, 0.1);
+            assert_approx_eq!(yaw, 0.0, 0.1);
+
+            // Turning at 90 degrees per second while lying flat
+            let mut fusion = Fusion::new(&config, [0.0, 0.0, 1.0]).unwrap();
+            for _ in 0..100 {
+                q = fusion.update([0.0, 0.0, 1.0], [0.0, 0.0, PI / 2.0], 0.01);
+            }
+            assert_approx_eq!(euler(q)[2], 90.0, 0.5);
+        }
+    }
+
+    #[test]
+    fn orientation_channels_export_from_raw_counts() {
+        // Flat at 4096 counts per g while turning at 90 degrees per second
+        let samples = (0..101)
+            .map(|_| vec![0, 0, 4096, 0, 0, 1475])
+            .collect::<Vec<_>>();
+        let measurement_id = measurement_with_samples(&samples, 100.0);
+
+        let mut config = QsFusionConfig::default();
+        assert!(qs_default_fusion_config(&mut config));
+        assert_eq!(
+            qs_add_derived_orientation(measurement_id, &config, QsOrientationOutput::Euler as u8),
+            8
+        );
+        assert_eq!(
+            qs_add_derived_orientation(
+                measurement_id,
+                &config,
+                QsOrientationOutput::Quaternion as u8
+            ),
+            11
+        );
+        // Only one channel remains free
+        assert_eq!(
+            qs_add_derived_orientation(measurement_id, &config, QsOrientationOutput::Euler as u8),
+            0
+        );
+
+        let mut bufs = vec![[0.0f64; 101]; 5];
+        let mut channel_data = bufs
+            .iter_mut()
+            .map(|b| b.as_mut_ptr() as *mut core::ffi::c_void)
+            .collect::<Vec<_>>();
+        let mut num_samples = 101;
+        assert!(qs_export_signals(
+            measurement_id,
+            0b111_1100_0000_0000,
+            QsSampleType::F64 as u8,
+            QsLayout::Planar as u8,
+            core::ptr::null(),
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+        assert_eq!(num_samples, 101);
+        assert_approx_eq!(bufs[0][0], 0.0);
+        assert_approx_eq!(bufs[0][100], 90.0, 0.5);
+        let q = (1..5).map(|c| bufs[c][100]).collect::<Vec<_>>();
+        assert_approx_eq!(q.iter().map(|v| v * v).sum::<f64>(), 1.0);
+        assert_approx_eq!(q[3], libm::sin(PI / 4.0), 0.01);
+
+        // Orientation is fused again once more samples arrive
+        let raw_payload = encode_payload(2, &samples[..50]);
+        qs_add_signals(
+            measurement_id,
+            raw_payload.as_ptr(),
+            raw_payload.len() as u16,
+        );
+        let mut yaw = [0.0f64; 151];
+        let mut channel_data = [yaw.as_mut_ptr() as *mut core::ffi::c_void];
+        let mut num_samples = 151;
+        assert!(qs_export_signals(
+            measurement_id,
+            0b100_0000_0000,
+            QsSampleType::F64 as u8,
+            QsLayout::Planar as u8,
+            core::ptr::null(),
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+        assert_eq!(num_samples, 151);
+        assert_approx_eq!(yaw[100], bufs[0][100]);
+        assert_approx_eq!(yaw[150], 135.0, 0.5);
+
+        qs_drop_measurement(measurement_id);
+    }
+}
@@ -4,12 +4,15 @@
 #![feature(integer_atomics)]
 #![feature(drain_filter)]
 #![allow(dead_code)]
+// Exported functions take pointers from C callers and check them before use
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
 
 extern crate alloc;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use alloc::{format, vec};
 use core::sync::atomic::{AtomicU32, Ordering};
-use core::{cmp::min, ptr::copy_nonoverlapping};
+use core::{cmp::min, ops::Range, ptr::copy_nonoverlapping};
 use cstr_core::{c_char, CString};
 use heapless::binary_heap::{BinaryHeap, Max};
 use heapless::consts::*;
@@ -17,6 +20,94 @@ use rand_core::*;
 use rand_xorshift::XorShiftRng;
 use spin::RwLock;
 
+mod activity;
+mod calibration;
+mod cardiac;
+mod changepoint;
+mod correlation;
+mod derived;
+mod despike;
+mod detrend;
+mod events;
+mod export;
+mod features;
+mod filter;
+mod fusion;
+mod model;
+mod periodicity;
+mod pipeline;
+mod quality;
+mod respiration;
+mod spectral;
+mod stats;
+mod trigger;
+mod wavelet;
+pub use activity::{
+    qs_classify_activity, qs_default_activity_config, QsActivityConfig, QsActivityEpoch,
+    QsActivityLevel, QsActivitySummary, QsAxisMapping, QsPosture,
+};
+pub use calibration::{
+    qs_calibrate_accelerometer, qs_create_calibrated_measurement, qs_get_accel_calibration,
+    qs_get_calibration, qs_set_accel_calibration, qs_set_calibration, QsAccelCalibration,
+    QsCalibration,
+};
+pub use cardiac::{qs_default_beat_config, qs_detect_beats, QsBeatConfig, QsHeartRateSummary};
+pub use changepoint::{
+    qs_default_change_point_config, qs_detect_change_points, QsChangePoint, QsChangePointConfig,
+};
+pub use correlation::{qs_cross_correlate, QsLagEstimate};
+pub use derived::{
+    qs_add_derived_difference, qs_add_derived_magnitude, qs_add_derived_sum, qs_clear_derived,
+};
+pub use despike::{qs_default_despike_config, QsDespikeConfig, QsDespikeMethod};
+pub use detrend::{
+    qs_default_detrend_config, qs_pipeline_add_detrend, QsDetrendConfig, QsDetrendMethod,
+};
+pub use events::{
+    qs_default_event_thresholds, qs_detect_events, QsEvent, QsEventClass, QsEventThresholds,
+};
+pub use export::{
+    qs_default_export_options, qs_export_signals, QsExportOptions, QsLayout, QsSampleType,
+};
+pub use features::{
+    qs_default_feature_config, qs_extract_features, qs_feature_layout_hash, qs_feature_names,
+    QsFeatureConfig, QsFeatureName, QsFeatureSet,
+};
+pub use filter::QsFilterKind;
+pub use fusion::{
+    qs_add_derived_orientation, qs_default_fusion_config, QsFusionAlgorithm, QsFusionConfig,
+    QsOrientationOutput,
+};
+pub use model::{
+    qs_classify_windows, qs_drop_model, qs_load_model, qs_model_info, QsModelInfo, QsModelKind,
+};
+pub use periodicity::{
+    qs_default_periodicity_config, qs_track_period, QsPeriodMethod, QsPeriodWindow,
+    QsPeriodicityConfig,
+};
+pub use pipeline::{
+    qs_attach_pipeline, qs_create_pipeline, qs_drop_pipeline, qs_pipeline_add_filter,
+};
+pub use quality::{
+    qs_default_artifact_thresholds, qs_default_quality_config, qs_detect_artifacts,
+    qs_signal_quality, QsArtifact, QsArtifactKind, QsArtifactThresholds, QsQualityConfig,
+    QsQualityWindow,
+};
+pub use respiration::{
+    qs_default_respiration_config, qs_respiration_rate, QsRespirationConfig, QsRespirationMethod,
+    QsRespirationWindow,
+};
+pub use spectral::{qs_amplitude_spectrum, qs_spectrogram, qs_welch_psd, QsWindow};
+pub use stats::{qs_enable_live_stats, qs_live_stats, qs_window_stats, QsWindowStats};
+pub use trigger::{
+    qs_add_trigger, qs_clear_snapshots, qs_default_trigger_config, qs_remove_trigger,
+    qs_snapshot_signals, qs_snapshots, QsSnapshot, QsTriggerCallback, QsTriggerConfig,
+    QsTriggerKind,
+};
+pub use wavelet::{
+    qs_default_wavelet_config, qs_pipeline_add_wavelet_denoise, QsThresholdRule, QsWaveletConfig,
+};
+
 #[cfg(test)]
 #[macro_use]
 extern crate std;
@@ -152,18 +243,70 @@ pub extern "C" fn qs_add_signals(measurement_id: u32, buf: *const u8, len: u16)
         copy_nonoverlapping(buf, owned_buf.as_mut_ptr(), len as usize);
     }
     let result = (*measurement_guard).consume(&owned_buf[0..len as usize]);
+    let completed = measurement_guard.triggers.take_completed();
+    drop(measurement_guard);
+    trigger::notify(measurement_id, completed);
     match result {
         Ok(num_samples) => num_samples,
         Err(err) => {
-            let mut error_guard = ERRORS.write();
-            if (*error_guard).len() < 16 {
-                (*error_guard).push(CString::new(err).unwrap());
-            }
+            push_error(err);
             0
         }
     }
 }
 
+#[no_mangle]
+pub extern "C" fn qs_set_sample_rate(measurement_id: u32, hz: f32, rate_scaler: f32) -> bool {
+    if !(hz > 0.0 && rate_scaler > 0.0) {
+        push_error("Sample rate and rate scaler must be positive");
+        return false;
+    }
+    let rw_measurement = match find_measurement_by_id(measurement_id) {
+        Some(rwm) => rwm,
+        None => {
+            push_error("No measurement exists for the given id");
+            return false;
+        }
+    };
+    let mut measurement_guard = rw_measurement.measurement.write();
+    let measurement = &mut *measurement_guard;
+    let previous = (measurement.hz, measurement.rate_scaler);
+    measurement.hz = hz;
+    measurement.rate_scaler = rate_scaler;
+    // Triggers count their durations and filter their bands in samples of the old rate
+    let result = measurement
+        .sample_rate()
+        .and_then(|sample_rate| measurement.triggers.set_sample_rate(sample_rate));
+    if let Err(err) = result {
+        measurement.hz = previous.0;
+        measurement.rate_scaler = previous.1;
+        push_error(err);
+        return false;
+    }
+
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_measurement_info(measurement_id: u32, info: *mut QsMeasurementInfo) -> bool {
+    if info.is_null() {
+        return false;
+    }
+    let rw_measurement = match find_measurement_by_id(measurement_id) {
+        Some(rwm) => rwm,
+        None => {
+            push_error("No measurement exists for the given id");
+            return false;
+        }
+    };
+    let measurement_guard = rw_measurement.measurement.read();
+    unsafe {
+        core::ptr::write(info, (*measurement_guard).info());
+    }
+
+    true
+}
+
 #[no_mangle]
 pub extern "C" fn qs_interpret_timestamps(
     measurement_id: u32,
@@ -242,50 +385,84 @@ pub extern "C" fn qs_copy_signals(
     channel_data: *mut *mut f64,
     num_samples_per_channel: *mut u32,
 ) -> bool {
-    let rw_measurement = match find_measurement_by_id(measurement_id) {
-        Some(rwm) => rwm,
+    let channel_mask = match find_measurement_by_id(measurement_id) {
+        Some(rwm) => derived::physical_mask(&rwm.measurement.read()),
         None => return false,
     };
-    let measurement_guard = rw_measurement.measurement.read();
-    let num_samples = unsafe { *num_samples_per_channel };
 
-    let mut rng = XorShiftRng::seed_from_u64(downsample_seed);
-    let mut sample_index: u32 = 0;
-    for payload in (*measurement_guard).payloads.iter() {
-        let num_samples_in_payload = payload.channels[0].len() as u32;
-        let mut sample_mask = (0..num_samples_in_payload)
-            .map(|_| rng.next_u32() % downsample_scale <= downsample_threshold)
-            .collect::<Vec<bool>>();
-        let mut downsample_index = 0;
-        for v in sample_mask.iter_mut() {
-            if *v && downsample_index < num_samples {
-                downsample_index += 1;
-            } else {
-                *v = false;
-            }
-        }
-        for i in 0..payload.active_channels {
-            let channel: *mut f64 = unsafe { *channel_data.offset(i as isize) };
-            let mut copy_index = 0;
-            payload.channels[i as usize]
-                .iter()
-                .zip(&sample_mask)
-                .filter(|v| *v.1)
-                .map(|v| v.0)
-                .for_each(|v| {
-                    unsafe {
-                        (*channel.offset(sample_index as isize + copy_index as isize)) = *v as f64;
-                    }
-                    copy_index += 1
-                })
+    qs_export_signals(
+        measurement_id,
+        channel_mask,
+        QsSampleType::F64 as u8,
+        QsLayout::Planar as u8,
+        &QsExportOptions {
+            downsample_seed,
+            downsample_threshold,
+            downsample_scale,
+            ..Default::default()
+        },
+        channel_data as *mut *mut core::ffi::c_void,
+        num_samples_per_channel,
+    )
+}
+
+fn push_error(err: &str) {
+    let mut error_guard = ERRORS.write();
+    if (*error_guard).len() < 16 {
+        (*error_guard).push(CString::new(err).unwrap());
+    }
+}
+
+/// Queues the error of a failed FFI call, returning whether it succeeded
+fn report(result: Result<(), &'static str>) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(err) => {
+            push_error(err);
+            false
         }
-        sample_index += sample_mask.iter().filter(|v| **v).map(|_v| 1).sum::<u32>();
     }
+}
+
+/// Runs `f` while holding a read lock on the measurement
+fn with_measurement<T>(
+    measurement_id: u32,
+    f: impl FnOnce(&Measurement) -> Result<T, &'static str>,
+) -> Result<T, &'static str> {
+    let rw_measurement =
+        find_measurement_by_id(measurement_id).ok_or("No measurement exists for the given id")?;
+    let measurement_guard = rw_measurement.measurement.read();
+    f(&measurement_guard)
+}
+
+/// Copies equal length results into caller buffers with the capacity read from `len`.
+/// The number of values is stored in `len` afterwards, including when the buffers are too small.
+fn write_buffers<T: Copy>(outputs: &[(&[T], *mut T)], len: *mut u32) -> Result<(), &'static str> {
+    if len.is_null() || outputs.iter().any(|(_, buf)| buf.is_null()) {
+        return Err("Null buffer passed to the library");
+    }
+    let count = outputs.first().map_or(0, |(values, _)| values.len());
+    let capacity = unsafe { *len } as usize;
     unsafe {
-        *num_samples_per_channel = sample_index;
+        *len = count as u32;
+    }
+    if count > capacity {
+        return Err("Buffer is too small for the result");
+    }
+    for (values, buf) in outputs.iter() {
+        unsafe {
+            copy_nonoverlapping(values.as_ptr(), *buf, values.len());
+        }
     }
+    Ok(())
+}
 
-    true
+/// Rejects values that have no ordering, before they are ranked or sorted
+fn require_finite(values: &[f64]) -> Result<(), &'static str> {
+    if values.iter().any(|v| !v.is_finite()) {
+        return Err("Channel values must be finite");
+    }
+    Ok(())
 }
 
 fn find_measurement_by_id(measurement_id: u32) -> Option<RwMeasurement> {
@@ -321,7 +498,40 @@ static ERRORS: RwLock<Vec<CString>> = RwLock::new(Vec::new());
 struct Measurement {
     id: u32,
     payloads: Vec<Payload>,
+    /// Samples per channel across all payloads
+    samples: usize,
     active_channels: u8,
+    duplicates: u32,
+    hz: f32,
+    rate_scaler: f32,
+    /// Incremented when samples already read change, such as by an out of order payload
+    generation: u32,
+    attached: spin::Mutex<Option<pipeline::StreamingPipeline>>,
+    live_stats: Option<stats::LiveStats>,
+    triggers: trigger::Triggers,
+    /// Virtual channels numbered from 8 in the order they were added
+    derived: Vec<derived::Derivation>,
+    orientations: spin::Mutex<derived::OrientationCache>,
+    /// Conversions to physical units, indexed by channel number
+    calibrations: [Option<calibration::QsCalibration>; 16],
+    /// Conversions of accelerometer channel triples to g, including misalignment
+    accel_calibrations: Vec<([u8; 3], calibration::QsAccelCalibration)>,
+}
+
+/// Summary of the payloads held by a measurement, used to size
+/// buffers before copying timestamps or signals out of the library.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QsMeasurementInfo {
+    pub payloads: u32,
+    pub samples_per_channel: u32,
+    pub active_channels: u8,
+    pub first_counter: u64,
+    pub last_counter: u64,
+    /// Number of discontinuities in the notification counter sequence
+    pub gaps: u32,
+    pub duplicates_dropped: u32,
+    pub bytes_used: u64,
 }
 
 #[derive(Clone)]
@@ -363,12 +573,138 @@ impl Measurement {
         let payload = Payload::new(self.active_channels, data)?;
         let new_samples = payload.channels[0].len();
 
-        if let Err(pos) = self.payloads.binary_search(&payload) {
-            self.payloads.insert(pos, payload);
+        match self.payloads.binary_search(&payload) {
+            Ok(_) => self.duplicates += 1,
+            Err(pos) => {
+                let sample_rate = self.sample_rate().ok();
+                if let Some(live_stats) = self.live_stats.as_mut() {
+                    live_stats.push_payload(&payload, sample_rate);
+                }
+                // Only a payload arriving out of order needs the samples before it counted
+                let first_index = match pos == self.payloads.len() {
+                    true => self.samples,
+                    false => self.payloads[..pos]
+                        .iter()
+                        .map(|p| p.channels[0].len())
+                        .sum(),
+                };
+                self.triggers.push_payload(&payload, first_index as u64);
+                if pos != self.payloads.len() {
+                    self.generation += 1;
+                }
+                self.samples += new_samples;
+                self.payloads.insert(pos, payload)
+            }
         }
 
         Ok(new_samples as u32)
     }
+
+    /// Samples per second per channel as configured by `qs_set_sample_rate`
+    pub fn sample_rate(&self) -> Result<f64, &'static str> {
+        if self.hz > 0.0 && self.rate_scaler > 0.0 {
+            Ok(self.hz as f64 / self.rate_scaler as f64)
+        } else {
+            Err("Sample rate has not been set for the measurement")
+        }
+    }
+
+    pub fn samples_per_channel(&self) -> usize {
+        self.samples
+    }
+
+    /// Bit mask of the physical and derived channels that can be read
+    pub fn channel_mask(&self) -> u32 {
+        let physical = (1u32 << min(self.active_channels, 8)) - 1;
+        let derived = ((1u32 << self.derived.len()) - 1) << derived::FIRST_DERIVED_CHANNEL;
+        physical | derived
+    }
+
+    pub fn has_channel(&self, channel: u8) -> bool {
+        channel < 32 && self.channel_mask() & (1 << channel) != 0
+    }
+
+    /// Collects one channel across all payloads in counter order
+    pub fn channel_values(&self, channel: usize) -> Vec<f64> {
+        self.channel_values_from(channel, 0)
+    }
+
+    /// Collects one channel in counter order from sample `start` on, decoding only
+    /// the payloads that hold those samples
+    pub fn channel_values_from(&self, channel: usize, start: usize) -> Vec<f64> {
+        if channel >= derived::FIRST_DERIVED_CHANNEL {
+            return self.derived_values(channel - derived::FIRST_DERIVED_CHANNEL, start);
+        }
+        let mut values = Vec::with_capacity(self.samples_per_channel().saturating_sub(start));
+        let mut index = 0;
+        for payload in self.payloads.iter() {
+            let len = payload.channels[0].len();
+            let samples = &payload.channels[channel];
+            for i in start.saturating_sub(index).min(len)..len {
+                values.push(samples.get(i).map_or(0, |v| *v) as f64);
+            }
+            index += len;
+        }
+        values
+    }
+
+    /// Sample indices covering [start_s, end_s) where the first sample is at 0 seconds
+    pub fn range_indices(&self, start_s: f64, end_s: f64) -> Result<Range<usize>, &'static str> {
+        let sample_rate = self.sample_rate()?;
+        let samples = self.samples_per_channel();
+        let start = min(libm::ceil(start_s.max(0.0) * sample_rate) as usize, samples);
+        let end = min(libm::ceil(end_s * sample_rate) as usize, samples);
+        if start >= end {
+            return Err("Time range contains no samples");
+        }
+        Ok(start..end)
+    }
+
+    /// One channel in physical units after any attached pipeline, limited to a time range
+    pub fn channel_range(
+        &self,
+        channel: u8,
+        start_s: f64,
+        end_s: f64,
+    ) -> Result<Vec<f64>, &'static str> {
+        if !self.has_channel(channel) {
+            return Err("Channel is not active in the measurement");
+        }
+        let range = self.range_indices(start_s, end_s)?;
+        let mut values = self.processed_values(channel as usize, None, true)?;
+        values.truncate(range.end);
+        values.drain(..range.start);
+        require_finite(&values)?;
+        Ok(values)
+    }
+
+    pub fn info(&self) -> QsMeasurementInfo {
+        let samples_per_channel = self.samples_per_channel();
+        let gaps = self
+            .payloads
+            .windows(2)
+            .filter(|w| w[1].counter != w[0].counter + 1)
+            .count();
+        let bytes_used = self
+            .payloads
+            .iter()
+            .map(|p| {
+                core::mem::size_of::<Payload>()
+                    + p.channels.iter().map(|c| c.len() * 2).sum::<usize>()
+            })
+            .sum::<usize>();
+
+        QsMeasurementInfo {
+            payloads: self.payloads.len() as u32,
+            samples_per_channel: samples_per_channel as u32,
+            active_channels: self.active_channels,
+            first_counter: self.payloads.first().map_or(0, |p| p.counter),
+            last_counter: self.payloads.last().map_or(0, |p| p.counter),
+            gaps: gaps as u32,
+            duplicates_dropped: self.duplicates,
+            bytes_used: bytes_used as u64,
+        }
+    }
 }
 
 struct Payload {
@@ -471,6 +807,45 @@ mod tests {
         qs_init();
     }
 
+    /// Serializes one notification the way the sensor does, with `samples[i]`
+    /// holding one value per channel for the i-th sample
+    pub(crate) fn encode_payload(counter: u32, samples: &[Vec<i16>]) -> Vec<u8> {
+        let channels = samples.first().map_or(1, |s| s.len());
+        let len = 2 + 1 + 1 + 4 + samples.len() * channels * 2;
+        let mut raw_payload = Vec::with_capacity(len);
+        raw_payload.extend_from_slice(&(len as u16).to_le_bytes());
+        raw_payload.push(0);
+        raw_payload.push((channels as u8) << 4);
+        raw_payload.extend_from_slice(&counter.to_le_bytes());
+        for sample in samples {
+            for value in sample {
+                raw_payload.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        raw_payload
+    }
+
+    /// Sends `samples` to a measurement as consecutive notifications of up to 100 samples
+    pub(crate) fn add_samples(measurement_id: u32, samples: &[Vec<i16>]) {
+        for (counter, chunk) in samples.chunks(100).enumerate() {
+            let raw_payload = encode_payload(counter as u32, chunk);
+            qs_add_signals(
+                measurement_id,
+                raw_payload.as_ptr(),
+                raw_payload.len() as u16,
+            );
+        }
+    }
+
+    /// Creates a measurement with a channel for each value of a sample, sets its sample
+    /// rate and adds `samples`
+    pub(crate) fn measurement_with_samples(samples: &[Vec<i16>], sample_rate: f32) -> u32 {
+        let measurement_id = qs_create_measurement(samples[0].len() as u8);
+        assert!(qs_set_sample_rate(measurement_id, sample_rate, 1.0));
+        add_samples(measurement_id, samples);
+        measurement_id
+    }
+
     #[test]
     fn create_measurement() {
         let _ = Measurement::new(1);
@@ -510,6 +885,44 @@ mod tests {
         assert_eq!(payload.channels[2][1], 0x0f00 as i16);
     }
 
+    #[test]
+    fn measurement_info_tracks_counters_and_duplicates() {
+        let mut measurement = Measurement::new(2);
+        assert_eq!(
+            measurement.info(),
+            QsMeasurementInfo {
+                active_channels: 2,
+                ..Default::default()
+            }
+        );
+
+        let samples = vec![vec![1, 2], vec![3, 4], vec![5, 6]];
+        for counter in [3, 1, 2, 2, 7].iter() {
+            assert_eq!(
+                measurement.consume(&encode_payload(*counter, &samples)),
+                Ok(3)
+            );
+        }
+
+        let info = measurement.info();
+        assert_eq!(info.payloads, 4);
+        assert_eq!(info.samples_per_channel, 12);
+        assert_eq!(info.active_channels, 2);
+        assert_eq!(info.first_counter, 1);
+        assert_eq!(info.last_counter, 7);
+        assert_eq!(info.gaps, 1);
+        assert_eq!(info.duplicates_dropped, 1);
+        assert!(info.bytes_used >= 4 * 3 * 2 * 2);
+
+        let measurement_id = qs_create_measurement(2);
+        let mut info = QsMeasurementInfo::default();
+        qs_add_signals(measurement_id, encode_payload(1, &samples).as_ptr(), 20);
+        assert!(qs_measurement_info(measurement_id, &mut info));
+        assert_eq!(info.samples_per_channel, 3);
+        qs_drop_measurement(measurement_id);
+        assert!(!qs_measurement_info(measurement_id, &mut info));
+    }
+
     #[test]
     fn create_and_drop_measurement() {
         setup();
@@ -0,0 +1,586 @@
+use super::*;
+use crate::features::QsFeatureConfig;
+
+const MODEL_MAGIC: &[u8; 4] = b"QSML";
+const MODEL_VERSION: u8 = 1;
+/// Feature index marking a tree node as a leaf
+const LEAF: u16 = 0xFFFF;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsModelKind {
+    /// Decision trees whose leaf class probabilities are averaged
+    TreeEnsemble = 0,
+    /// Fully connected layers with int8 weights, ReLU between layers and softmax output
+    QuantizedMlp = 1,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QsModelInfo {
+    pub kind: u8,
+    pub num_features: u32,
+    pub num_classes: u32,
+    /// Layout hash of the features the model was trained on
+    pub feature_hash: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Node {
+    feature: u16,
+    threshold: f32,
+    /// Left child, or the leaf index for leaves
+    left: u16,
+    right: u16,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Tree {
+    nodes: Vec<Node>,
+    leaves: Vec<Vec<f32>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Layer {
+    inputs: usize,
+    outputs: usize,
+    weight_scale: f32,
+    /// Row-major with one row of inputs per output
+    weights: Vec<i8>,
+    biases: Vec<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Network {
+    Trees(Vec<Tree>),
+    Mlp {
+        input_offset: Vec<f32>,
+        input_scale: Vec<f32>,
+        layers: Vec<Layer>,
+    },
+}
+
+/// A classifier loaded from the model format, tracked by id like pipelines
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Model {
+    id: u32,
+    num_features: usize,
+    num_classes: usize,
+    feature_hash: u32,
+    network: Network,
+}
+
+static MODEL_ID: AtomicU32 = AtomicU32::new(1);
+static MODELS: RwLock<Vec<Model>> = RwLock::new(Vec::new());
+
+/// Little-endian reader over model data
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        if self.data.len() - self.position < len {
+            return Err("Model data ends early");
+        }
+        let bytes = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, &'static str> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn f32(&mut self) -> Result<f32, &'static str> {
+        let bytes = self.take(4)?;
+        let value = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        if !value.is_finite() {
+            return Err("Model values must be finite");
+        }
+        Ok(value)
+    }
+
+    fn f32s(&mut self, len: usize) -> Result<Vec<f32>, &'static str> {
+        (0..len).map(|_| self.f32()).collect()
+    }
+}
+
+impl Model {
+    /// Parses and validates model data, see the header for the layout
+    fn parse(data: &[u8]) -> Result<Model, &'static str> {
+        let mut reader = Reader { data, position: 0 };
+        if reader.take(4)? != MODEL_MAGIC {
+            return Err("Model data does not start with the model magic");
+        }
+        if reader.u8()? != MODEL_VERSION {
+            return Err("Unsupported model version");
+        }
+        let kind = reader.u8()?;
+        let num_features = reader.u16()? as usize;
+        let num_classes = reader.u8()? as usize;
+        let feature_hash = reader.u32()?;
+        if num_features == 0 || num_classes < 2 {
+            return Err("Models need features and at least two classes");
+        }
+
+        let network = match kind {
+            k if k == QsModelKind::TreeEnsemble as u8 => {
+                let trees = (0..reader.u8()?)
+                    .map(|_| Self::parse_tree(&mut reader, num_features, num_classes))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if trees.is_empty() {
+                    return Err("Tree ensembles need at least one tree");
+                }
+                Network::Trees(trees)
+            }
+            k if k == QsModelKind::QuantizedMlp as u8 => {
+                let input_offset = reader.f32s(num_features)?;
+                let input_scale = reader.f32s(num_features)?;
+                let mut layers = Vec::new();
+                for _ in 0..reader.u8()? {
+                    let inputs = reader.u16()? as usize;
+                    let outputs = reader.u16()? as usize;
+                    let expected = layers.last().map_or(num_features, |l: &Layer| l.outputs);
+                    if inputs != expected || outputs == 0 {
+                        return Err("Model layer sizes do not chain");
+                    }
+                    let weight_scale = reader.f32()?;
+                    let weights = reader
+                        .take(inputs * outputs)?
+                        .iter()
+                        .map(|w| *w as i8)
+                        .collect();
+                    let biases = reader.f32s(outputs)?;
+                    layers.push(Layer {
+                        inputs,
+                        outputs,
+                        weight_scale,
+                        weights,
+                        biases,
+                    });
+                }
+                if layers.last().map(|l| l.outputs) != Some(num_classes) {
+                    return Err("Model output layer does not match the classes");
+                }
+                Network::Mlp {
+                    input_offset,
+                    input_scale,
+                    layers,
+                }
+            }
+            _ => return Err("Unknown model kind"),
+        };
+        if reader.position != data.len() {
+            return Err("Model data continues past the model");
+        }
+        Ok(Model {
+            id: 0,
+            num_features,
+            num_classes,
+            feature_hash,
+            network,
+        })
+    }
+
+    fn parse_tree(
+        reader: &mut Reader,
+        num_features: usize,
+        num_classes: usize,
+    ) -> Result<Tree, &'static str> {
+        let nodes = (0..reader.u16()?)
+            .map(|_| {
+                Ok(Node {
+                    feature: reader.u16()?,
+                    threshold: reader.f32()?,
+                    left: reader.u16()?,
+                    right: reader.u16()?,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let leaves = (0..reader.u16()?)
+            .map(|_| reader.f32s(num_classes))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if nodes.is_empty() {
+            return Err("Model trees need at least one node");
+        }
+        for (i, node) in nodes.iter().enumerate() {
+            let valid = match node.feature {
+                LEAF => (node.left as usize) < leaves.len(),
+                // Children following their parent rules out cycles
+                feature => {
+                    (feature as usize) < num_features
+                        && (i + 1..nodes.len()).contains(&(node.left as usize))
+                        && (i + 1..nodes.len()).contains(&(node.right as usize))
+                }
+            };
+            if !valid {
+                return Err("Model tree node refers outside the tree");
+            }
+        }
+        Ok(Tree { nodes, leaves })
+    }
+
+    /// Class probabilities of one feature row
+    pub(crate) fn predict(&self, features: &[f64]) -> Vec<f64> {
+        match &self.network {
+            Network::Trees(trees) => {
+                let mut probabilities = vec![0.0; self.num_classes];
+                for tree in trees.iter() {
+                    let mut node = &tree.nodes[0];
+                    while node.feature != LEAF {
+                        // Missing (NaN) features follow the right branch
+                        node = if features[node.feature as usize] <= node.threshold as f64 {
+                            &tree.nodes[node.left as usize]
+                        } else {
+                            &tree.nodes[node.right as usize]
+                        };
+                    }
+                    for (p, leaf) in probabilities
+                        .iter_mut()
+                        .zip(tree.leaves[node.left as usize].iter())
+                    {
+                        *p += *leaf as f64 / trees.len() as f64;
+                    }
+                }
+                probabilities
+            }
+            Network::Mlp {
+                input_offset,
+                input_scale,
+                layers,
+            } => {
+                // Single precision throughout, as on targets with only an f32 FPU
+                let mut activations = features
+                    .iter()
+                    .zip(input_offset.iter().zip(input_scale.iter()))
+                    .map(|(x, (offset, scale))| (*x as f32 - offset) * scale)
+                    .collect::<Vec<_>>();
+                for (l, layer) in layers.iter().enumerate() {
+                    activations = (0..layer.outputs)
+                        .map(|o| {
+                            let row = &layer.weights[o * layer.inputs..(o + 1) * layer.inputs];
+                            let sum = row
+                                .iter()
+                                .zip(activations.iter())
+                                .map(|(w, a)| *w as f32 * a)
+                                .sum::<f32>();
+                            let z = sum * layer.weight_scale + layer.biases[o];
+                            if l + 1 < layers.len() {
+                                z.max(0.0)
+                            } else {
+                                z
+                            }
+                        })
+                        .collect();
+                }
+                let peak = activations.iter().fold(f32::NEG_INFINITY, |m, a| m.max(*a));
+                let exps = activations
+                    .iter()
+                    .map(|a| libm::expf(a - peak))
+                    .collect::<Vec<_>>();
+                let total = exps.iter().sum::<f32>();
+                exps.iter().map(|e| (e / total) as f64).collect()
+            }
+        }
+    }
+
+    fn info(&self) -> QsModelInfo {
+        QsModelInfo {
+            kind: match self.network {
+                Network::Trees(_) => QsModelKind::TreeEnsemble as u8,
+                Network::Mlp { .. } => QsModelKind::QuantizedMlp as u8,
+            },
+            num_features: self.num_features as u32,
+            num_classes: self.num_classes as u32,
+            feature_hash: self.feature_hash,
+        }
+    }
+}
+
+fn find_model_by_id(model_id: u32) -> Option<Model> {
+    let models_guard = MODELS.read();
+    (*models_guard).iter().find(|m| m.id == model_id).cloned()
+}
+
+#[no_mangle]
+pub extern "C" fn qs_load_model(data: *const u8, len: u32) -> u32 {
+    if data.is_null() {
+        push_error("Null buffer passed to the library");
+        return 0;
+    }
+    let data = unsafe { core::slice::from_raw_parts(data, len as usize) };
+    match Model::parse(data) {
+        Ok(mut model) => {
+            model.id = MODEL_ID.fetch_add(1, Ordering::SeqCst);
+            let id = model.id;
+            MODELS.write().push(model);
+            id
+        }
+        Err(err) => {
+            push_error(err);
+            0
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn qs_drop_model(model_id: u32) -> bool {
+    let mut models_guard = MODELS.write();
+    let before = (*models_guard).len();
+    (*models_guard).retain(|m| m.id != model_id);
+    before != (*models_guard).len()
+}
+
+#[no_mangle]
+pub extern "C" fn qs_model_info(model_id: u32, info: *mut QsModelInfo) -> bool {
+    let result = match find_model_by_id(model_id) {
+        _ if info.is_null() => Err("Null buffer passed to the library"),
+        Some(model) => {
+            unsafe {
+                core::ptr::write(info, model.info());
+            }
+            Ok(())
+        }
+        None => Err("No model exists for the given id"),
+    };
+    report(result)
+}
+
+#[no_mangle]
+pub extern "C" fn qs_classify_windows(
+    measurement_id: u32,
+    model_id: u32,
+    channel_mask: u32,
+    start_s: f64,
+    end_s: f64,
+    config: *const QsFeatureConfig,
+    window_start_s: *mut f64,
+    probabilities: *mut f64,
+    num_windows: *mut u32,
+) -> bool {
+    let model = match find_model_by_id(model_id) {
+        Some(model) => model,
+        None => {
+            push_error("No model exists for the given id");
+            return false;
+        }
+    };
+    let result = with_measurement(measurement_id, |measurement| {
+        let config = match unsafe { config.as_ref() } {
+            Some(config) => *config,
+            None => QsFeatureConfig::default(),
+        };
+        config.validate()?;
+        if config.num_features(channel_mask) != model.num_features {
+            return Err("Model expects a different number of features");
+        }
+        if config.layout_hash(channel_mask) != model.feature_hash {
+            return Err("Model was trained on a different feature layout");
+        }
+        let (starts, rows) = measurement.features(channel_mask, start_s, end_s, &config)?;
+        if probabilities.is_null() {
+            return Err("Null buffer passed to the library");
+        }
+        write_buffers(&[(&starts[..], window_start_s)], num_windows)?;
+        let matrix = rows
+            .iter()
+            .flat_map(|row| model.predict(row))
+            .collect::<Vec<_>>();
+        unsafe {
+            core::ptr::copy_nonoverlapping(matrix.as_ptr(), probabilities, matrix.len());
+        }
+        Ok(())
+    });
+    report(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::QsFeatureSet;
+    use crate::tests::measurement_with_samples;
+    use assert_approx_eq::assert_approx_eq;
+    use core::f64::consts::PI;
+
+    fn header(kind: QsModelKind, num_features: u16, num_classes: u8, hash: u32) -> Vec<u8> {
+        let mut data = MODEL_MAGIC.to_vec();
+        data.extend_from_slice(&[MODEL_VERSION, kind as u8]);
+        data.extend_from_slice(&num_features.to_le_bytes());
+        data.push(num_classes);
+        data.extend_from_slice(&hash.to_le_bytes());
+        data
+    }
+
+    fn statistics_config() -> QsFeatureConfig {
+        QsFeatureConfig {
+            window_s: 2.0,
+            hop_s: 2.0,
+            feature_sets: QsFeatureSet::Statistics as u32,
+            ..Default::default()
+        }
+    }
+
+    fn node(data: &mut Vec<u8>, feature: u16, threshold: f32, left: u16, right: u16) {
+        data.extend_from_slice(&feature.to_le_bytes());
+        data.extend_from_slice(&threshold.to_le_bytes());
+        data.extend_from_slice(&left.to_le_bytes());
+        data.extend_from_slice(&right.to_le_bytes());
+    }
+
+    fn floats(data: &mut Vec<u8>, values: &[f32]) {
+        values
+            .iter()
+            .for_each(|v| data.extend_from_slice(&v.to_le_bytes()));
+    }
+
+    /// One tree splitting on the standard deviation of channel 0
+    fn still_or_moving() -> Vec<u8> {
+        let hash = statistics_config().layout_hash(0b1);
+        let mut data = header(QsModelKind::TreeEnsemble, 6, 2, hash);
+        data.push(1);
+        data.extend_from_slice(&3u16.to_le_bytes());
+        node(&mut data, 1, 100.0, 1, 2);
+        node(&mut data, LEAF, 0.0, 0, 0);
+        node(&mut data, LEAF, 0.0, 1, 0);
+        data.extend_from_slice(&2u16.to_le_bytes());
+        floats(&mut data, &[0.9, 0.1]);
+        floats(&mut data, &[0.2, 0.8]);
+        data
+    }
+
+    #[test]
+    fn classifies_windows_over_time() {
+        let samples = (0..1000)
+            .map(|i| {
+                let moving = if i >= 500 { 1000.0 } else { 5.0 };
+                vec![(moving * libm::sin(2.0 * PI * i as f64 / 25.0)) as i16]
+            })
+            .collect::<Vec<_>>();
+        let measurement_id = measurement_with_samples(&samples, 50.0);
+
+        let data = still_or_moving();
+        let model_id = qs_load_model(data.as_ptr(), data.len() as u32);
+        assert_ne!(model_id, 0);
+        let mut info = QsModelInfo::default();
+        assert!(qs_model_info(model_id, &mut info));
+        assert_eq!(info.num_classes, 2);
+
+        let config = statistics_config();
+        let mut hash = 0;
+        assert!(qs_feature_layout_hash(0b1, &config, &mut hash));
+        assert_eq!(info.feature_hash, hash);
+        let mut starts = [0.0; 10];
+        let mut probabilities = [0.0; 20];
+        let mut num_windows = 10;
+        assert!(qs_classify_windows(
+            measurement_id,
+            model_id,
+            0b1,
+            0.0,
+            f64::INFINITY,
+            &config,
+            starts.as_mut_ptr(),
+            probabilities.as_mut_ptr(),
+            &mut num_windows,
+        ));
+        assert_eq!(num_windows, 10);
+        assert_approx_eq!(probabilities[8], 0.9, 1e-6);
+        assert_approx_eq!(probabilities[11], 0.8, 1e-6);
+        assert_approx_eq!(starts[5], 10.0);
+
+        // The model was trained on a different feature layout
+        let mut num_windows = 10;
+        assert!(!qs_classify_windows(
+            measurement_id,
+            model_id,
+            0b1,
+            0.0,
+            f64::INFINITY,
+            core::ptr::null(),
+            starts.as_mut_ptr(),
+            probabilities.as_mut_ptr(),
+            &mut num_windows,
+        ));
+        // Six band powers have as many features as the statistics but a different layout
+        let bands = QsFeatureConfig {
+            feature_sets: QsFeatureSet::BandPowers as u32,
+            band_edges_hz: [0.1, 0.5, 1.0, 3.0, 5.0, 10.0, 20.0, 0.0, 0.0],
+            num_bands: 6,
+            ..config
+        };
+        assert_eq!(bands.num_features(0b1), 6);
+        assert!(!qs_classify_windows(
+            measurement_id,
+            model_id,
+            0b1,
+            0.0,
+            f64::INFINITY,
+            &bands,
+            starts.as_mut_ptr(),
+            probabilities.as_mut_ptr(),
+            &mut num_windows,
+        ));
+
+        assert!(qs_drop_model(model_id));
+        assert!(!qs_drop_model(model_id));
+        qs_drop_measurement(measurement_id);
+    }
+
+    #[test]
+    fn quantized_mlp_matches_float_reference() {
+        let mut data = header(QsModelKind::QuantizedMlp, 2, 3, 0);
+        floats(&mut data, &[10.0, 0.0]);
+        floats(&mut data, &[0.5, 2.0]);
+        data.push(2);
+        data.extend_from_slice(&2u16.to_le_bytes());
+        data.extend_from_slice(&2u16.to_le_bytes());
+        floats(&mut data, &[0.01]);
+        data.extend_from_slice(&[100i8 as u8, -50i8 as u8, 20, 127]);
+        floats(&mut data, &[0.1, -0.2]);
+        data.extend_from_slice(&2u16.to_le_bytes());
+        data.extend_from_slice(&3u16.to_le_bytes());
+        floats(&mut data, &[0.02]);
+        data.extend_from_slice(&[50, 0, -100i8 as u8, 25, 60, 60]);
+        floats(&mut data, &[0.0, 0.5, -0.5]);
+        let model = Model::parse(&data).unwrap();
+
+        let x = [(14.0 - 10.0) * 0.5, 0.75 * 2.0];
+        let hidden = [
+            (0.01 * (100.0 * x[0] - 50.0 * x[1]) + 0.1f64).max(0.0),
+            (0.01 * (20.0 * x[0] + 127.0 * x[1]) - 0.2f64).max(0.0),
+        ];
+        let logits = [
+            0.02 * 50.0 * hidden[0],
+            0.02 * (-100.0 * hidden[0] + 25.0 * hidden[1]) + 0.5,
+            0.02 * 60.0 * (hidden[0] + hidden[1]) - 0.5,
+        ];
+        let total = logits.iter().map(|l| libm::exp(*l)).sum::<f64>();
+        let probabilities = model.predict(&[14.0, 0.75]);
+        for (p, l) in probabilities.iter().zip(logits.iter()) {
+            assert_approx_eq!(*p, libm::exp(*l) / total, 1e-6);
+        }
+
+        // Truncated, padded and cyclic models are rejected
+        assert!(Model::parse(&data[..data.len() - 1]).is_err());
+        let mut padded = data.clone();
+        padded.push(0);
+        assert!(Model::parse(&padded).is_err());
+        let mut cyclic = still_or_moving();
+        cyclic[22..24].copy_from_slice(&0u16.to_le_bytes());
+        assert!(Model::parse(&cyclic).is_err());
+        assert_eq!(qs_load_model(cyclic.as_ptr(), cyclic.len() as u32), 0);
+    }
+}
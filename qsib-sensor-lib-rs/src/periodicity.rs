@@ -0,0 +1,327 @@
+use super::*;
+use crate::features::autocorrelation;
+use crate::spectral::{padded_power, QsWindow};
+use core::cmp::max;
+
+/// Share of the highest autocorrelation peak that a shorter period must reach to be
+/// preferred, so that multiples of the period are not reported
+const OCTAVE_TOLERANCE: f64 = 0.8;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsPeriodMethod {
+    /// Shortest strong peak of the normalized autocorrelation
+    Autocorrelation = 0,
+    /// Peak of the product of the power spectrum compressed by each harmonic
+    HarmonicProduct = 1,
+}
+
+impl QsPeriodMethod {
+    pub(crate) fn from_u8(value: u8) -> Option<QsPeriodMethod> {
+        match value {
+            0 => Some(QsPeriodMethod::Autocorrelation),
+            1 => Some(QsPeriodMethod::HarmonicProduct),
+            _ => None,
+        }
+    }
+}
+
+/// Settings for tracking the dominant period over sliding windows
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsPeriodicityConfig {
+    pub window_s: f64,
+    pub step_s: f64,
+    /// Range of plausible periods, the longest may be at most half the window
+    pub min_period_s: f64,
+    pub max_period_s: f64,
+    /// Harmonics multiplied by the harmonic product spectrum, including the fundamental
+    pub harmonics: u8,
+    pub method: u8,
+}
+
+impl Default for QsPeriodicityConfig {
+    fn default() -> Self {
+        QsPeriodicityConfig {
+            window_s: 10.0,
+            step_s: 1.0,
+            min_period_s: 0.25,
+            max_period_s: 2.0,
+            harmonics: 3,
+            method: QsPeriodMethod::Autocorrelation as u8,
+        }
+    }
+}
+
+impl QsPeriodicityConfig {
+    pub(crate) fn validate(&self) -> Result<QsPeriodMethod, &'static str> {
+        if !(self.window_s > 0.0 && self.step_s > 0.0) {
+            return Err("Periodicity window and step must be positive");
+        }
+        if !(self.min_period_s > 0.0
+            && self.min_period_s < self.max_period_s
+            && self.max_period_s <= self.window_s / 2.0)
+        {
+            return Err("Period range must be increasing and at most half the window");
+        }
+        if !(1..=8).contains(&self.harmonics) {
+            return Err("Harmonic product spectrum uses 1 to 8 harmonics");
+        }
+        QsPeriodMethod::from_u8(self.method).ok_or("Unknown periodicity method")
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QsPeriodWindow {
+    pub start_s: f64,
+    /// NaN when no period was found within the range
+    pub period_s: f64,
+    pub frequency_hz: f64,
+    /// Confidence in the period from 0 (none) to 1
+    pub confidence: f64,
+}
+
+/// Offset of a peak from its sample, by a parabola through its neighbours
+fn refine(a: f64, b: f64, c: f64) -> f64 {
+    match a - 2.0 * b + c {
+        curvature if curvature < 0.0 => 0.5 * (a - c) / curvature,
+        _ => 0.0,
+    }
+}
+
+/// Period and confidence from the autocorrelation of one window, where the
+/// confidence is the height of the peak corrected for the shrinking overlap
+fn autocorrelation_period(values: &[f64], lags: Range<usize>) -> (f64, f64) {
+    let acf = autocorrelation(values);
+    let end = min(lags.end + 1, acf.len().saturating_sub(1));
+    let peaks = (lags.start.max(1)..end)
+        .filter(|k| acf[*k] > 0.0 && acf[*k] >= acf[k - 1] && acf[*k] >= acf[k + 1])
+        .collect::<Vec<_>>();
+    let highest = peaks.iter().map(|k| acf[*k]).fold(0.0, f64::max);
+    let peak = match peaks
+        .into_iter()
+        .find(|k| acf[*k] >= OCTAVE_TOLERANCE * highest)
+    {
+        Some(peak) => peak,
+        None => return (f64::NAN, 0.0),
+    };
+    let lag = peak as f64 + refine(acf[peak - 1], acf[peak], acf[peak + 1]);
+    let unbiased = acf[peak] * values.len() as f64 / (values.len() - peak) as f64;
+    (lag, unbiased.clamp(0.0, 1.0))
+}
+
+/// Period and confidence from the harmonic product spectrum of one window, where
+/// the confidence is the share of power within a bin of the fundamental or its harmonics
+fn harmonic_product_period(values: &[f64], lags: Range<usize>, harmonics: usize) -> (f64, f64) {
+    let (power, fft_length) = padded_power(values, QsWindow::Hann);
+    let total = power[1..].iter().sum::<f64>();
+    // Candidate bins between the longest and shortest periods
+    let low = max(1, fft_length / lags.end.max(1));
+    let high = min(
+        fft_length / lags.start.max(1),
+        ((power.len() - 1) / harmonics).saturating_sub(1),
+    );
+    if total <= 0.0 || low + 1 >= high {
+        return (f64::NAN, 0.0);
+    }
+    let product = (low - 1..=high + 1)
+        .map(|k| {
+            (1..=harmonics)
+                .map(|h| libm::log(power[h * k] + f64::MIN_POSITIVE))
+                .sum::<f64>()
+        })
+        .collect::<Vec<_>>();
+    let peak = (1..product.len() - 1)
+        .max_by(|a, b| product[*a].total_cmp(&product[*b]))
+        .unwrap();
+    let bin = (low - 1 + peak) as f64 + refine(product[peak - 1], product[peak], product[peak + 1]);
+    let k = low - 1 + peak;
+    let harmonic_power = (1..=harmonics)
+        .map(|h| power[h * k - 1..=h * k + 1].iter().sum::<f64>())
+        .sum::<f64>();
+    (fft_length as f64 / bin, (harmonic_power / total).min(1.0))
+}
+
+/// Dominant period in samples and its confidence for one window, or NaN and 0
+/// when the window has no period between the lags in the range
+pub(crate) fn dominant_period(
+    values: &[f64],
+    lags: Range<usize>,
+    method: QsPeriodMethod,
+    harmonics: usize,
+) -> (f64, f64) {
+    match method {
+        QsPeriodMethod::Autocorrelation => autocorrelation_period(values, lags),
+        QsPeriodMethod::HarmonicProduct => harmonic_product_period(values, lags, harmonics),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn qs_default_periodicity_config(config: *mut QsPeriodicityConfig) -> bool {
+    if config.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    unsafe {
+        core::ptr::write(config, QsPeriodicityConfig::default());
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_track_period(
+    measurement_id: u32,
+    channel: u8,
+    start_s: f64,
+    end_s: f64,
+    config: *const QsPeriodicityConfig,
+    windows: *mut QsPeriodWindow,
+    num_windows: *mut u32,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        let config = match unsafe { config.as_ref() } {
+            Some(config) => *config,
+            None => QsPeriodicityConfig::default(),
+        };
+        let method = config.validate()?;
+        let sample_rate = measurement.sample_rate()?;
+        let window = libm::round(config.window_s * sample_rate) as usize;
+        let step = max(libm::round(config.step_s * sample_rate) as usize, 1);
+        let lags = libm::floor(config.min_period_s * sample_rate) as usize
+            ..libm::ceil(config.max_period_s * sample_rate) as usize;
+        if lags.start < 2 || window < 2 * lags.end {
+            return Err("Period range must span several samples within the window");
+        }
+        let range = measurement.range_indices(start_s, end_s)?;
+        let values = measurement.channel_range(channel, start_s, end_s)?;
+
+        let periods = (0..)
+            .map(|w| w * step)
+            .take_while(|start| start + window <= values.len())
+            .map(|start| {
+                let (lag, confidence) = dominant_period(
+                    &values[start..start + window],
+                    lags.clone(),
+                    method,
+                    config.harmonics as usize,
+                );
+                QsPeriodWindow {
+                    start_s: (range.start + start) as f64 / sample_rate,
+                    period_s: lag / sample_rate,
+                    frequency_hz: sample_rate / lag,
+                    confidence,
+                }
+            })
+            .collect::<Vec<_>>();
+        write_buffers(&[(&periods[..], windows)], num_windows)
+    });
+    report(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::measurement_with_samples;
+    use assert_approx_eq::assert_approx_eq;
+    use core::f64::consts::PI;
+
+    /// A pulse wave whose second harmonic is stronger than its fundamental, with noise
+    fn pulses(hz: impl Fn(f64) -> f64, sample_rate: f64, seconds: f64, seed: u64) -> Vec<f64> {
+        let mut rng = XorShiftRng::seed_from_u64(seed);
+        let mut phase = 0.0;
+        (0..(seconds * sample_rate) as usize)
+            .map(|i| {
+                phase += 2.0 * PI * hz(i as f64 / sample_rate) / sample_rate;
+                let uniform = rng.next_u32() as f64 / u32::MAX as f64 - 0.5;
+                300.0 * libm::sin(phase)
+                    + 500.0 * libm::sin(2.0 * phase + 0.4)
+                    + 200.0 * libm::sin(3.0 * phase + 1.1)
+                    + 200.0 * uniform
+            })
+            .collect()
+    }
+
+    #[test]
+    fn both_methods_find_the_fundamental() {
+        let sample_rate = 100.0;
+        let values = pulses(|_| 1.3, sample_rate, 10.0, 5);
+        for method in [
+            QsPeriodMethod::Autocorrelation,
+            QsPeriodMethod::HarmonicProduct,
+        ]
+        .iter()
+        {
+            let (lag, confidence) = dominant_period(&values, 25..200, *method, 3);
+            assert_approx_eq!(lag / sample_rate, 1.0 / 1.3, 0.02);
+            assert!(confidence > 0.6);
+        }
+
+        let mut rng = XorShiftRng::seed_from_u64(7);
+        let noise = (0..values.len())
+            .map(|_| rng.next_u32() as f64 / u32::MAX as f64)
+            .collect::<Vec<_>>();
+        for method in [
+            QsPeriodMethod::Autocorrelation,
+            QsPeriodMethod::HarmonicProduct,
+        ]
+        .iter()
+        {
+            let (_, confidence) = dominant_period(&noise, 25..200, *method, 3);
+            assert!(confidence < 0.3);
+        }
+    }
+
+    #[test]
+    fn tracks_a_changing_rate() {
+        // 1 Hz for the first 30 s, then 1.5 Hz
+        let samples = pulses(|t| if t < 30.0 { 1.0 } else { 1.5 }, 100.0, 60.0, 11)
+            .iter()
+            .map(|v| vec![*v as i16])
+            .collect::<Vec<_>>();
+        let measurement_id = measurement_with_samples(&samples, 100.0);
+
+        let mut config = QsPeriodicityConfig::default();
+        assert!(qs_default_periodicity_config(&mut config));
+        config.step_s = 5.0;
+        for method in [
+            QsPeriodMethod::Autocorrelation,
+            QsPeriodMethod::HarmonicProduct,
+        ]
+        .iter()
+        {
+            config.method = *method as u8;
+            let mut windows = [QsPeriodWindow::default(); 16];
+            let mut num_windows = 16;
+            assert!(qs_track_period(
+                measurement_id,
+                0,
+                0.0,
+                f64::INFINITY,
+                &config,
+                windows.as_mut_ptr(),
+                &mut num_windows,
+            ));
+            assert_eq!(num_windows, 11);
+            assert_eq!(windows[2].start_s, 10.0);
+            assert_approx_eq!(windows[2].frequency_hz, 1.0, 0.03);
+            assert_approx_eq!(windows[10].period_s, 1.0 / 1.5, 0.02);
+            assert!(windows[10].confidence > 0.6);
+        }
+
+        config.max_period_s = 6.0;
+        let mut windows = [QsPeriodWindow::default(); 16];
+        let mut num_windows = 16;
+        assert!(!qs_track_period(
+            measurement_id,
+            0,
+            0.0,
+            f64::INFINITY,
+            &config,
+            windows.as_mut_ptr(),
+            &mut num_windows,
+        ));
+
+        qs_drop_measurement(measurement_id);
+    }
+}
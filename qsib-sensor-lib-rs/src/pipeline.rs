@@ -0,0 +1,486 @@
+use super::*;
+use crate::detrend::{detrend, QsDetrendConfig};
+use crate::filter::{cascade, Biquad, BiquadState, QsFilterKind};
+use crate::wavelet::{denoise, QsWaveletConfig};
+
+/// One processing step applied to the channels selected by its mask.
+/// Stages run in the order they were added to the pipeline.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Stage {
+    Filter {
+        channel_mask: u32,
+        kind: QsFilterKind,
+        cutoff_hz: f64,
+        q: f64,
+    },
+    Detrend {
+        channel_mask: u32,
+        config: QsDetrendConfig,
+    },
+    Denoise {
+        channel_mask: u32,
+        config: QsWaveletConfig,
+    },
+}
+
+/// An ordered set of processing stages that can be attached to a
+/// measurement or applied to a single export
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Pipeline {
+    id: u32,
+    stages: Vec<Stage>,
+}
+
+/// A pipeline attached to a measurement, holding filter state and output
+/// for the samples already processed so later exports only process new samples.
+/// Calibrated channels are processed in physical units.
+pub(crate) struct StreamingPipeline {
+    pipeline: Pipeline,
+    generation: u32,
+    sample_rate: f64,
+    channels: Vec<StreamingChannel>,
+}
+
+#[derive(Default)]
+struct StreamingChannel {
+    sections: Vec<Biquad>,
+    states: Vec<BiquadState>,
+    output: Vec<f64>,
+}
+
+static PIPELINE_ID: AtomicU32 = AtomicU32::new(1);
+static PIPELINES: RwLock<Vec<Pipeline>> = RwLock::new(Vec::new());
+
+#[no_mangle]
+pub extern "C" fn qs_create_pipeline() -> u32 {
+    let pipeline = Pipeline {
+        id: PIPELINE_ID.fetch_add(1, Ordering::SeqCst),
+        ..Default::default()
+    };
+    let id = pipeline.id;
+    PIPELINES.write().push(pipeline);
+    id
+}
+
+#[no_mangle]
+pub extern "C" fn qs_drop_pipeline(pipeline_id: u32) -> bool {
+    let mut pipelines_guard = PIPELINES.write();
+    let before = (*pipelines_guard).len();
+    (*pipelines_guard).retain(|p| p.id != pipeline_id);
+    before != (*pipelines_guard).len()
+}
+
+#[no_mangle]
+pub extern "C" fn qs_pipeline_add_filter(
+    pipeline_id: u32,
+    channel_mask: u32,
+    kind: u8,
+    cutoff_hz: f32,
+    q: f32,
+) -> bool {
+    let kind = match QsFilterKind::from_u8(kind) {
+        Some(kind) => kind,
+        None => {
+            push_error("Unknown filter kind");
+            return false;
+        }
+    };
+    // Validate against the widest possible rate, the measurement rate is checked when applied
+    if let Err(err) = Biquad::design(kind, f64::MAX, cutoff_hz as f64, q as f64) {
+        push_error(err);
+        return false;
+    }
+    add_stage(
+        pipeline_id,
+        Stage::Filter {
+            channel_mask,
+            kind,
+            cutoff_hz: cutoff_hz as f64,
+            q: q as f64,
+        },
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn qs_attach_pipeline(measurement_id: u32, pipeline_id: u32) -> bool {
+    let rw_measurement = match find_measurement_by_id(measurement_id) {
+        Some(rwm) => rwm,
+        None => {
+            push_error("No measurement exists for the given id");
+            return false;
+        }
+    };
+    let pipeline = if pipeline_id == 0 {
+        None
+    } else {
+        match find_pipeline_by_id(pipeline_id) {
+            Some(pipeline) => Some(pipeline),
+            None => {
+                push_error("No pipeline exists for the given id");
+                return false;
+            }
+        }
+    };
+    let measurement_guard = rw_measurement.measurement.read();
+    *measurement_guard.attached.lock() = pipeline.map(|pipeline| StreamingPipeline {
+        pipeline,
+        generation: 0,
+        sample_rate: 0.0,
+        channels: Vec::new(),
+    });
+
+    true
+}
+
+pub(crate) fn add_stage(pipeline_id: u32, stage: Stage) -> bool {
+    let mut pipelines_guard = PIPELINES.write();
+    match (*pipelines_guard).iter_mut().find(|p| p.id == pipeline_id) {
+        Some(pipeline) => {
+            pipeline.stages.push(stage);
+            true
+        }
+        None => {
+            push_error("No pipeline exists for the given id");
+            false
+        }
+    }
+}
+
+pub(crate) fn find_pipeline_by_id(pipeline_id: u32) -> Option<Pipeline> {
+    let pipelines_guard = PIPELINES.read();
+    (*pipelines_guard)
+        .iter()
+        .find(|p| p.id == pipeline_id)
+        .cloned()
+}
+
+impl Stage {
+    fn selects(&self, channel: usize) -> bool {
+        let channel_mask = match self {
+            Stage::Filter { channel_mask, .. }
+            | Stage::Detrend { channel_mask, .. }
+            | Stage::Denoise { channel_mask, .. } => channel_mask,
+        };
+        channel_mask & (1 << channel) != 0
+    }
+}
+
+/// Runs the stages selecting a channel in order, each filter from a fresh state
+fn run_stages(
+    stages: &[Stage],
+    channel: usize,
+    sample_rate: f64,
+    values: &mut [f64],
+) -> Result<(), &'static str> {
+    for stage in stages.iter().filter(|stage| stage.selects(channel)) {
+        match stage {
+            Stage::Filter {
+                kind, cutoff_hz, q, ..
+            } => {
+                let section = Biquad::design(*kind, sample_rate, *cutoff_hz, *q)?;
+                cascade(&[section], &mut [BiquadState::default()], values);
+            }
+            Stage::Detrend { config, .. } => detrend(config, sample_rate, values)?,
+            Stage::Denoise { config, .. } => denoise(config, values)?,
+        }
+    }
+    Ok(())
+}
+
+impl Pipeline {
+    /// Number of leading stages that can run on samples as they arrive, which are
+    /// those before the first stage of the channel that needs the whole series
+    fn streamed_stages(&self, channel: usize) -> usize {
+        self.stages
+            .iter()
+            .position(|stage| stage.selects(channel) && !matches!(stage, Stage::Filter { .. }))
+            .unwrap_or(self.stages.len())
+    }
+
+    /// Filter sections of the stages that can run on samples as they arrive
+    fn sections(&self, channel: usize, sample_rate: f64) -> Result<Vec<Biquad>, &'static str> {
+        self.stages[..self.streamed_stages(channel)]
+            .iter()
+            .filter(|stage| stage.selects(channel))
+            .filter_map(|stage| match stage {
+                Stage::Filter {
+                    kind, cutoff_hz, q, ..
+                } => Some(Biquad::design(*kind, sample_rate, *cutoff_hz, *q)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Processes a whole channel from a fresh state
+    pub fn apply(
+        &self,
+        channel: usize,
+        sample_rate: f64,
+        values: &mut [f64],
+    ) -> Result<(), &'static str> {
+        run_stages(&self.stages, channel, sample_rate, values)
+    }
+
+    /// Runs the stages after those that ran as samples arrived over a whole channel
+    fn apply_whole_series(
+        &self,
+        channel: usize,
+        sample_rate: f64,
+        values: &mut [f64],
+    ) -> Result<(), &'static str> {
+        let stages = &self.stages[self.streamed_stages(channel)..];
+        run_stages(stages, channel, sample_rate, values)
+    }
+}
+
+impl StreamingPipeline {
+    /// Picks up stages added to the pipeline since it was attached, restarting
+    /// processing. A dropped pipeline leaves the attached copy in place.
+    fn sync(&mut self) {
+        if let Some(pipeline) = find_pipeline_by_id(self.pipeline.id) {
+            if pipeline != self.pipeline {
+                self.pipeline = pipeline;
+                self.channels.clear();
+            }
+        }
+    }
+
+    /// Extends the processed output of a channel with samples that arrived since the last call
+    fn update(
+        &mut self,
+        measurement: &Measurement,
+        channel: usize,
+    ) -> Result<&[f64], &'static str> {
+        self.sync();
+        let sample_rate = measurement.sample_rate()?;
+        if self.generation != measurement.generation
+            || self.sample_rate != sample_rate
+            || self.channels.is_empty()
+        {
+            // Payloads arrived out of order or the timebase changed, start over
+            self.generation = measurement.generation;
+            self.sample_rate = sample_rate;
+            let channels = derived::FIRST_DERIVED_CHANNEL + derived::MAX_DERIVED_CHANNELS;
+            self.channels = (0..channels).map(|_| StreamingChannel::default()).collect();
+            for (c, stream) in self.channels.iter_mut().enumerate() {
+                stream.sections = self.pipeline.sections(c, sample_rate)?;
+                stream.states = vec![BiquadState::default(); stream.sections.len()];
+            }
+        }
+
+        let stream = &mut self.channels[channel];
+        let processed = stream.output.len();
+        let values = measurement.calibrated_values_from(channel, processed);
+        stream.output.extend_from_slice(&values);
+        cascade(
+            &stream.sections,
+            &mut stream.states,
+            &mut stream.output[processed..],
+        );
+        Ok(&stream.output)
+    }
+}
+
+impl Measurement {
+    /// The channel after the attached pipeline and then the given pipeline, starting
+    /// from physical units when `calibrated` and from raw counts otherwise
+    pub(crate) fn processed_values(
+        &self,
+        channel: usize,
+        pipeline: Option<&Pipeline>,
+        calibrated: bool,
+    ) -> Result<Vec<f64>, &'static str> {
+        if !calibrated && self.is_calibrated(channel) {
+            // Attached output is kept in physical units, so raw counts are processed afresh
+            return self.reprocessed_values(channel, self.channel_values(channel), pipeline);
+        }
+        let mut values = match &mut *self.attached.lock() {
+            Some(stream) => {
+                // Output of the leading filters is kept between calls, later stages are redone as samples arrive
+                let mut values = stream.update(self, channel)?.to_vec();
+                stream
+                    .pipeline
+                    .apply_whole_series(channel, self.sample_rate()?, &mut values)?;
+                values
+            }
+            None => self.calibrated_values_from(channel, 0),
+        };
+        if let Some(pipeline) = pipeline {
+            pipeline.apply(channel, self.sample_rate()?, &mut values)?;
+        }
+        Ok(values)
+    }
+
+    /// Replacement samples for a channel after the attached pipeline, processed
+    /// from a fresh state, and then the given pipeline
+    pub(crate) fn reprocessed_values(
+        &self,
+        channel: usize,
+        mut values: Vec<f64>,
+        pipeline: Option<&Pipeline>,
+    ) -> Result<Vec<f64>, &'static str> {
+        if let Some(stream) = &mut *self.attached.lock() {
+            stream.sync();
+            stream
+                .pipeline
+                .apply(channel, self.sample_rate()?, &mut values)?;
+        }
+        if let Some(pipeline) = pipeline {
+            pipeline.apply(channel, self.sample_rate()?, &mut values)?;
+        }
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::encode_payload;
+    use core::ffi::c_void;
+
+    fn add_sine(measurement_id: u32, counter: u32, samples: usize) {
+        let samples = (0..samples)
+            .map(|i| {
+                let t = (counter as usize * samples + i) as f64 / 100.0;
+                let v = 1000.0 * libm::sin(2.0 * core::f64::consts::PI * 5.0 * t);
+                vec![v as i16, 500]
+            })
+            .collect::<Vec<_>>();
+        let raw_payload = encode_payload(counter, &samples);
+        qs_add_signals(
+            measurement_id,
+            raw_payload.as_ptr(),
+            raw_payload.len() as u16,
+        );
+    }
+
+    fn export(measurement_id: u32, pipeline_id: u32, capacity: usize) -> Vec<f64> {
+        let mut channel0 = vec![0.0; capacity];
+        let mut channel_data = [channel0.as_mut_ptr() as *mut c_void];
+        let mut num_samples = capacity as u32;
+        assert!(qs_export_signals(
+            measurement_id,
+            0b01,
+            QsSampleType::F64 as u8,
+            QsLayout::Planar as u8,
+            &QsExportOptions {
+                pipeline_id,
+                ..Default::default()
+            },
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+        channel0.truncate(num_samples as usize);
+        channel0
+    }
+
+    #[test]
+    fn attached_pipeline_matches_export_pipeline_across_reads() {
+        let measurement_id = qs_create_measurement(2);
+        assert!(qs_set_sample_rate(measurement_id, 100.0, 1.0));
+        let pipeline_id = qs_create_pipeline();
+        assert!(qs_pipeline_add_filter(
+            pipeline_id,
+            0b11,
+            QsFilterKind::HighPass as u8,
+            1.0,
+            0.707
+        ));
+        assert!(qs_pipeline_add_filter(
+            pipeline_id,
+            0b01,
+            QsFilterKind::Notch as u8,
+            50.0 / 3.0,
+            10.0
+        ));
+        assert!(qs_attach_pipeline(measurement_id, pipeline_id));
+
+        // Read while the measurement grows, then compare against one offline pass
+        let mut live = Vec::new();
+        for counter in 0..10 {
+            add_sine(measurement_id, counter, 20);
+            live = export(measurement_id, 0, 200);
+        }
+        assert!(qs_attach_pipeline(measurement_id, 0));
+        let offline = export(measurement_id, pipeline_id, 200);
+        assert_eq!(live.len(), 200);
+        assert_eq!(live, offline);
+
+        // Stages added after attaching are picked up by the attached copy
+        assert!(qs_attach_pipeline(measurement_id, pipeline_id));
+        export(measurement_id, 0, 200);
+        assert!(qs_pipeline_add_filter(
+            pipeline_id,
+            0b01,
+            QsFilterKind::LowPass as u8,
+            20.0,
+            0.707
+        ));
+        let live = export(measurement_id, 0, 200);
+        assert!(qs_attach_pipeline(measurement_id, 0));
+        assert_eq!(live, export(measurement_id, pipeline_id, 200));
+        assert_ne!(live, offline);
+
+        qs_drop_pipeline(pipeline_id);
+        qs_drop_measurement(measurement_id);
+    }
+
+    #[test]
+    fn out_of_order_payloads_restart_streaming_state() {
+        let measurement_id = qs_create_measurement(2);
+        assert!(qs_set_sample_rate(measurement_id, 100.0, 1.0));
+        let pipeline_id = qs_create_pipeline();
+        assert!(qs_pipeline_add_filter(
+            pipeline_id,
+            0b01,
+            QsFilterKind::LowPass as u8,
+            10.0,
+            0.707
+        ));
+        assert!(qs_attach_pipeline(measurement_id, pipeline_id));
+
+        add_sine(measurement_id, 1, 20);
+        export(measurement_id, 0, 40);
+        add_sine(measurement_id, 0, 20);
+        let live = export(measurement_id, 0, 40);
+        assert!(qs_attach_pipeline(measurement_id, 0));
+        assert_eq!(live, export(measurement_id, pipeline_id, 40));
+
+        qs_drop_pipeline(pipeline_id);
+        qs_drop_measurement(measurement_id);
+    }
+
+    #[test]
+    fn filters_require_sample_rate() {
+        let measurement_id = qs_create_measurement(2);
+        let pipeline_id = qs_create_pipeline();
+        assert!(qs_pipeline_add_filter(
+            pipeline_id,
+            0b01,
+            QsFilterKind::LowPass as u8,
+            10.0,
+            0.707
+        ));
+        assert!(!qs_pipeline_add_filter(pipeline_id, 0b01, 9, 10.0, 0.707));
+        add_sine(measurement_id, 0, 20);
+
+        let mut channel0 = [0.0; 20];
+        let mut channel_data = [channel0.as_mut_ptr() as *mut c_void];
+        let mut num_samples = 20;
+        assert!(!qs_export_signals(
+            measurement_id,
+            0b01,
+            QsSampleType::F64 as u8,
+            QsLayout::Planar as u8,
+            &QsExportOptions {
+                pipeline_id,
+                ..Default::default()
+            },
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+
+        qs_drop_pipeline(pipeline_id);
+        qs_drop_measurement(measurement_id);
+    }
+}
@@ -0,0 +1,544 @@
+use super::*;
+use crate::spectral::{bin_frequencies, padded_power, QsWindow};
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsArtifactKind {
+    /// Samples at or beyond the clipping limits, value is the number of samples
+    Clipping = 0,
+    /// Samples within the flatline tolerance of each other, value is the duration in seconds
+    Flatline = 1,
+    /// Windows with excessive sample to sample noise, value is the largest RMS first difference
+    Noise = 2,
+    /// Missing notifications between payloads, value is the number of missing payloads
+    Dropout = 3,
+}
+
+/// Limits used to flag artifacts, in raw ADC counts unless noted
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsArtifactThresholds {
+    pub clip_low: i16,
+    pub clip_high: i16,
+    pub clip_min_samples: u32,
+    pub flat_tolerance: f64,
+    pub flat_min_s: f64,
+    pub noise_window_s: f64,
+    pub noise_threshold: f64,
+}
+
+impl Default for QsArtifactThresholds {
+    fn default() -> Self {
+        QsArtifactThresholds {
+            clip_low: i16::MIN + 64,
+            clip_high: i16::MAX - 64,
+            clip_min_samples: 2,
+            flat_tolerance: 0.0,
+            flat_min_s: 1.0,
+            noise_window_s: 0.5,
+            noise_threshold: 4096.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsArtifact {
+    pub start_s: f64,
+    pub end_s: f64,
+    pub value: f64,
+    pub kind: u8,
+    pub channel: u8,
+}
+
+impl Measurement {
+    /// Raw ADC counts of a channel limited to a time range, along with the range
+    pub(crate) fn raw_channel_range(
+        &self,
+        channel: u8,
+        start_s: f64,
+        end_s: f64,
+    ) -> Result<(Range<usize>, Vec<f64>), &'static str> {
+        if !self.has_channel(channel) {
+            return Err("Channel is not active in the measurement");
+        }
+        let range = self.range_indices(start_s, end_s)?;
+        let mut values = self.channel_values(channel as usize);
+        values.truncate(range.end);
+        values.drain(..range.start);
+        Ok((range, values))
+    }
+
+    /// Sample indices where notifications are missing, with the number of missing payloads
+    pub(crate) fn dropouts(&self) -> Vec<(usize, u64)> {
+        let mut index = 0;
+        let mut dropouts = Vec::new();
+        for pair in self.payloads.windows(2) {
+            index += pair[0].channels[0].len();
+            if pair[1].counter > pair[0].counter + 1 {
+                dropouts.push((index, pair[1].counter - pair[0].counter - 1));
+            }
+        }
+        dropouts
+    }
+}
+
+/// Runs of consecutive indices satisfying `flagged` as half-open ranges
+fn runs(len: usize, mut flagged: impl FnMut(usize) -> bool) -> Vec<Range<usize>> {
+    let mut runs = Vec::new();
+    let mut start = None;
+    for i in 0..len {
+        match (flagged(i), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                runs.push(s..i);
+                start = None;
+            }
+            _ => (),
+        }
+    }
+    if let Some(s) = start {
+        runs.push(s..len);
+    }
+    runs
+}
+
+/// Flags artifacts in raw values, returning sample ranges relative to `values`
+pub(crate) fn detect_artifacts(
+    values: &[f64],
+    sample_rate: f64,
+    thresholds: &QsArtifactThresholds,
+) -> Vec<(QsArtifactKind, Range<usize>, f64)> {
+    let mut artifacts = Vec::new();
+
+    let (low, high) = (thresholds.clip_low as f64, thresholds.clip_high as f64);
+    for run in runs(values.len(), |i| values[i] <= low || values[i] >= high) {
+        if run.len() >= thresholds.clip_min_samples as usize {
+            let samples = run.len() as f64;
+            artifacts.push((QsArtifactKind::Clipping, run, samples));
+        }
+    }
+
+    let flat_min = libm::ceil(thresholds.flat_min_s * sample_rate).max(2.0) as usize;
+    let mut start = 0;
+    while start < values.len() {
+        let (mut lo, mut hi) = (values[start], values[start]);
+        let mut end = start + 1;
+        while end < values.len() {
+            let (l, h) = (lo.min(values[end]), hi.max(values[end]));
+            if h - l > thresholds.flat_tolerance {
+                break;
+            }
+            lo = l;
+            hi = h;
+            end += 1;
+        }
+        if end - start >= flat_min {
+            let duration = (end - start) as f64 / sample_rate;
+            artifacts.push((QsArtifactKind::Flatline, start..end, duration));
+        }
+        start = end;
+    }
+
+    let window = libm::round(thresholds.noise_window_s * sample_rate).max(2.0) as usize;
+    let noise = values
+        .chunks(window)
+        .map(|chunk| {
+            let sum_sq = chunk
+                .windows(2)
+                .map(|d| (d[1] - d[0]) * (d[1] - d[0]))
+                .sum::<f64>();
+            libm::sqrt(sum_sq / (chunk.len().max(2) - 1) as f64)
+        })
+        .collect::<Vec<_>>();
+    for run in runs(noise.len(), |w| noise[w] > thresholds.noise_threshold) {
+        let worst = noise[run.clone()].iter().fold(0.0, |m: f64, v| m.max(*v));
+        let samples = run.start * window..min(run.end * window, values.len());
+        artifacts.push((QsArtifactKind::Noise, samples, worst));
+    }
+
+    artifacts
+}
+
+/// Settings for the per-window signal quality index
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsQualityConfig {
+    pub window_s: f64,
+    /// Band holding the physiological signal of interest
+    pub signal_low_hz: f64,
+    pub signal_high_hz: f64,
+    /// Power from above 0 Hz up to this frequency is attributed to motion
+    pub motion_high_hz: f64,
+    /// SNR at which the SNR component of the score saturates at 1
+    pub snr_good_db: f64,
+    /// Kurtosis above which the window is considered impulsive
+    pub kurtosis_max: f64,
+}
+
+impl Default for QsQualityConfig {
+    fn default() -> Self {
+        QsQualityConfig {
+            window_s: 5.0,
+            signal_low_hz: 1.0,
+            signal_high_hz: 40.0,
+            motion_high_hz: 0.5,
+            snr_good_db: 20.0,
+            kurtosis_max: 20.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QsQualityWindow {
+    pub start_s: f64,
+    pub snr_db: f64,
+    pub kurtosis: f64,
+    /// Fraction of the window's power at or below the motion cutoff
+    pub motion_energy: f64,
+    /// Fraction of the window's expected samples lost to dropped notifications
+    pub gap_fraction: f64,
+    /// Combined quality from 0 (unusable) to 1 (clean)
+    pub score: f64,
+}
+
+fn clamp_unit(value: f64) -> f64 {
+    value.clamp(0.0, 1.0)
+}
+
+/// Scores one window of samples that lost `missing_samples` to dropouts
+pub(crate) fn quality_window(
+    values: &[f64],
+    missing_samples: f64,
+    sample_rate: f64,
+    config: &QsQualityConfig,
+) -> QsQualityWindow {
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let m2 = values.iter().map(|v| libm::pow(v - mean, 2.0)).sum::<f64>() / n;
+    let m4 = values.iter().map(|v| libm::pow(v - mean, 4.0)).sum::<f64>() / n;
+    let kurtosis = if m2 > 0.0 { m4 / (m2 * m2) } else { 0.0 };
+
+    let (power, fft_length) = padded_power(values, QsWindow::Hann);
+    let frequencies = bin_frequencies(sample_rate, fft_length);
+    let band_power = |low: f64, high: f64| {
+        power
+            .iter()
+            .zip(&frequencies)
+            .filter(|(_, f)| **f >= low && **f <= high)
+            .map(|(p, _)| p)
+            .sum::<f64>()
+    };
+    let total = power.iter().skip(1).sum::<f64>();
+    let signal = band_power(config.signal_low_hz, config.signal_high_hz);
+    let motion = band_power(f64::MIN_POSITIVE, config.motion_high_hz);
+    let snr_db = 10.0 * libm::log10(signal.max(1e-12) / (total - signal).max(1e-12));
+    let motion_energy = if total > 0.0 { motion / total } else { 0.0 };
+    let gap_fraction = missing_samples / (n + missing_samples);
+
+    let snr_score = clamp_unit(snr_db / config.snr_good_db);
+    let kurtosis_score = if kurtosis > config.kurtosis_max {
+        config.kurtosis_max / kurtosis
+    } else {
+        1.0
+    };
+    QsQualityWindow {
+        start_s: 0.0,
+        snr_db,
+        kurtosis,
+        motion_energy,
+        gap_fraction,
+        score: snr_score * kurtosis_score * (1.0 - motion_energy) * (1.0 - gap_fraction),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn qs_default_artifact_thresholds(thresholds: *mut QsArtifactThresholds) -> bool {
+    if thresholds.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    unsafe {
+        core::ptr::write(thresholds, QsArtifactThresholds::default());
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_detect_artifacts(
+    measurement_id: u32,
+    channel: u8,
+    start_s: f64,
+    end_s: f64,
+    thresholds: *const QsArtifactThresholds,
+    artifacts: *mut QsArtifact,
+    num_artifacts: *mut u32,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        let thresholds = match unsafe { thresholds.as_ref() } {
+            Some(thresholds) => *thresholds,
+            None => QsArtifactThresholds::default(),
+        };
+        let sample_rate = measurement.sample_rate()?;
+        let (range, values) = measurement.raw_channel_range(channel, start_s, end_s)?;
+        let time = |i: usize| (range.start + i) as f64 / sample_rate;
+
+        let mut found = detect_artifacts(&values, sample_rate, &thresholds)
+            .into_iter()
+            .map(|(kind, samples, value)| QsArtifact {
+                start_s: time(samples.start),
+                end_s: time(samples.end),
+                value,
+                kind: kind as u8,
+                channel,
+            })
+            .collect::<Vec<_>>();
+        for (index, missing) in measurement.dropouts() {
+            if range.contains(&index) {
+                let t = index as f64 / sample_rate;
+                found.push(QsArtifact {
+                    start_s: t,
+                    end_s: t,
+                    value: missing as f64,
+                    kind: QsArtifactKind::Dropout as u8,
+                    channel,
+                });
+            }
+        }
+        found.sort_by(|a, b| {
+            a.start_s
+                .partial_cmp(&b.start_s)
+                .unwrap_or(core::cmp::Ordering::Equal)
+        });
+
+        write_buffers(&[(&found[..], artifacts)], num_artifacts)
+    });
+    report(result)
+}
+
+#[no_mangle]
+pub extern "C" fn qs_default_quality_config(config: *mut QsQualityConfig) -> bool {
+    if config.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    unsafe {
+        core::ptr::write(config, QsQualityConfig::default());
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_signal_quality(
+    measurement_id: u32,
+    channel: u8,
+    start_s: f64,
+    end_s: f64,
+    config: *const QsQualityConfig,
+    windows: *mut QsQualityWindow,
+    num_windows: *mut u32,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        let config = match unsafe { config.as_ref() } {
+            Some(config) => *config,
+            None => QsQualityConfig::default(),
+        };
+        let sample_rate = measurement.sample_rate()?;
+        let window = libm::round(config.window_s * sample_rate) as usize;
+        if window < 2 {
+            return Err("Quality window is shorter than two samples");
+        }
+        let range = measurement.range_indices(start_s, end_s)?;
+        let values = measurement.channel_range(channel, start_s, end_s)?;
+        let samples_per_payload =
+            measurement.samples_per_channel() as f64 / measurement.payloads.len() as f64;
+        let dropouts = measurement.dropouts();
+
+        let scored = values
+            .chunks_exact(window)
+            .enumerate()
+            .map(|(w, chunk)| {
+                let start = range.start + w * window;
+                let missing = dropouts
+                    .iter()
+                    .filter(|(index, _)| *index >= start && *index < start + window)
+                    .map(|(_, payloads)| *payloads as f64 * samples_per_payload)
+                    .sum::<f64>();
+                QsQualityWindow {
+                    start_s: start as f64 / sample_rate,
+                    ..quality_window(chunk, missing, sample_rate, &config)
+                }
+            })
+            .collect::<Vec<_>>();
+        write_buffers(&[(&scored[..], windows)], num_windows)
+    });
+    report(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::encode_payload;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn flags_clipping_flatline_and_noise() {
+        let mut values = (0..400)
+            .map(|i| 1000.0 * libm::sin(i as f64 / 10.0))
+            .collect::<Vec<_>>();
+        values[50..55].iter_mut().for_each(|v| *v = 32767.0);
+        values[100..250].iter_mut().for_each(|v| *v = 12.0);
+        values[300..350]
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, v)| *v = if i % 2 == 0 { 20000.0 } else { -20000.0 });
+
+        let thresholds = QsArtifactThresholds {
+            noise_threshold: 10000.0,
+            ..Default::default()
+        };
+        let artifacts = detect_artifacts(&values, 100.0, &thresholds);
+        let of_kind = |kind| {
+            artifacts
+                .iter()
+                .filter(|a| a.0 == kind)
+                .map(|a| a.1.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(of_kind(QsArtifactKind::Clipping), vec![50..55]);
+        assert_eq!(of_kind(QsArtifactKind::Flatline), vec![100..250]);
+        assert_eq!(of_kind(QsArtifactKind::Noise), vec![300..350]);
+    }
+
+    #[test]
+    fn reports_dropouts_and_clipping_as_time_intervals() {
+        let measurement_id = qs_create_measurement(1);
+        assert!(qs_set_sample_rate(measurement_id, 10.0, 1.0));
+        for counter in [0, 1, 4].iter() {
+            let samples = (0..10)
+                .map(|i| {
+                    vec![if *counter == 1 && i < 4 {
+                        i16::MIN
+                    } else {
+                        (i * 100) as i16
+                    }]
+                })
+                .collect::<Vec<_>>();
+            let raw_payload = encode_payload(*counter, &samples);
+            qs_add_signals(
+                measurement_id,
+                raw_payload.as_ptr(),
+                raw_payload.len() as u16,
+            );
+        }
+
+        let mut artifacts = [QsArtifact {
+            start_s: 0.0,
+            end_s: 0.0,
+            value: 0.0,
+            kind: 0,
+            channel: 0,
+        }; 4];
+        let mut num_artifacts = 4;
+        let mut thresholds = QsArtifactThresholds::default();
+        assert!(qs_default_artifact_thresholds(&mut thresholds));
+        thresholds.noise_threshold = 1e9;
+        assert!(qs_detect_artifacts(
+            measurement_id,
+            0,
+            0.0,
+            f64::INFINITY,
+            &thresholds,
+            artifacts.as_mut_ptr(),
+            &mut num_artifacts,
+        ));
+        assert_eq!(num_artifacts, 2);
+        assert_eq!(artifacts[0].kind, QsArtifactKind::Clipping as u8);
+        assert_approx_eq!(artifacts[0].start_s, 1.0);
+        assert_approx_eq!(artifacts[0].end_s, 1.4);
+        assert_eq!(artifacts[1].kind, QsArtifactKind::Dropout as u8);
+        assert_approx_eq!(artifacts[1].start_s, 2.0);
+        assert_approx_eq!(artifacts[1].value, 2.0);
+
+        qs_drop_measurement(measurement_id);
+    }
+
+    fn noisy_tone(noise: f64, samples: usize) -> Vec<f64> {
+        let mut rng = XorShiftRng::seed_from_u64(7);
+        (0..samples)
+            .map(|i| {
+                let uniform = rng.next_u32() as f64 / u32::MAX as f64 - 0.5;
+                1000.0 * libm::sin(2.0 * core::f64::consts::PI * 10.0 * i as f64 / 100.0)
+                    + noise * uniform
+            })
+            .collect()
+    }
+
+    #[test]
+    fn quality_drops_with_noise_motion_and_gaps() {
+        let config = QsQualityConfig::default();
+        let clean = quality_window(&noisy_tone(10.0, 500), 0.0, 100.0, &config);
+        assert!(clean.snr_db > 20.0);
+        assert!((clean.kurtosis - 1.5).abs() < 0.1);
+        assert!(clean.score > 0.9);
+
+        let noisy = quality_window(&noisy_tone(20000.0, 500), 0.0, 100.0, &config);
+        assert!(noisy.snr_db < clean.snr_db - 20.0);
+        assert!(noisy.score < 0.5);
+
+        let drifting = noisy_tone(10.0, 500)
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                v + 5000.0 * libm::sin(2.0 * core::f64::consts::PI * 0.2 * i as f64 / 100.0)
+            })
+            .collect::<Vec<_>>();
+        let drifting = quality_window(&drifting, 0.0, 100.0, &config);
+        assert!(drifting.motion_energy > 0.5);
+        assert!(drifting.score < 0.5);
+
+        let gappy = quality_window(&noisy_tone(10.0, 500), 500.0, 100.0, &config);
+        assert_approx_eq!(gappy.gap_fraction, 0.5);
+        assert_approx_eq!(gappy.score, clean.score * 0.5);
+    }
+
+    #[test]
+    fn signal_quality_windows_report_gaps_with_timestamps() {
+        let measurement_id = qs_create_measurement(1);
+        assert!(qs_set_sample_rate(measurement_id, 100.0, 1.0));
+        let values = noisy_tone(10.0, 1000);
+        for (counter, chunk) in [0, 1, 2, 3, 4, 6, 7, 8, 9, 10]
+            .iter()
+            .zip(values.chunks(100))
+        {
+            let samples = chunk.iter().map(|v| vec![*v as i16]).collect::<Vec<_>>();
+            let raw_payload = encode_payload(*counter, &samples);
+            qs_add_signals(
+                measurement_id,
+                raw_payload.as_ptr(),
+                raw_payload.len() as u16,
+            );
+        }
+
+        let mut windows = [QsQualityWindow::default(); 2];
+        let mut num_windows = 2;
+        assert!(qs_signal_quality(
+            measurement_id,
+            0,
+            0.0,
+            f64::INFINITY,
+            core::ptr::null(),
+            windows.as_mut_ptr(),
+            &mut num_windows,
+        ));
+        assert_eq!(num_windows, 2);
+        assert_approx_eq!(windows[0].start_s, 0.0);
+        assert_approx_eq!(windows[1].start_s, 5.0);
+        assert_approx_eq!(windows[0].gap_fraction, 0.0);
+        assert_approx_eq!(windows[1].gap_fraction, 100.0 / 600.0);
+        assert!(windows[0].score > windows[1].score);
+
+        qs_drop_measurement(measurement_id);
+    }
+}
@@ -0,0 +1,327 @@
+use super::*;
+use crate::filter::{filtfilt, Biquad, QsFilterKind};
+use crate::spectral::{bin_frequencies, padded_power, QsWindow};
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsRespirationMethod {
+    /// Strongest peak of the summed power spectra of the channels
+    Spectral = 0,
+    /// Breaths counted on the band-passed channel with the most power
+    PeakCounting = 1,
+}
+
+impl QsRespirationMethod {
+    pub(crate) fn from_u8(value: u8) -> Option<QsRespirationMethod> {
+        match value {
+            0 => Some(QsRespirationMethod::Spectral),
+            1 => Some(QsRespirationMethod::PeakCounting),
+            _ => None,
+        }
+    }
+}
+
+/// Settings for sliding window respiration rate estimation
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsRespirationConfig {
+    pub window_s: f64,
+    pub step_s: f64,
+    /// Band of plausible breathing frequencies, 0.1 to 1 Hz is 6 to 60 breaths per minute
+    pub low_hz: f64,
+    pub high_hz: f64,
+    pub method: u8,
+}
+
+impl Default for QsRespirationConfig {
+    fn default() -> Self {
+        QsRespirationConfig {
+            window_s: 30.0,
+            step_s: 5.0,
+            low_hz: 0.1,
+            high_hz: 1.0,
+            method: QsRespirationMethod::Spectral as u8,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QsRespirationWindow {
+    pub start_s: f64,
+    /// Breaths per minute, NaN when no breathing was found
+    pub rate_bpm: f64,
+    /// Confidence in the rate from 0 (none) to 1
+    pub confidence: f64,
+}
+
+/// Rate and confidence from the summed power spectra of one window of each channel
+pub(crate) fn spectral_rate(
+    channels: &[&[f64]],
+    sample_rate: f64,
+    config: &QsRespirationConfig,
+) -> (f64, f64) {
+    let mut total: Vec<f64> = Vec::new();
+    let mut fft_length = 0;
+    for values in channels.iter() {
+        let (power, n) = padded_power(values, QsWindow::Hann);
+        total.resize(power.len(), 0.0);
+        total.iter_mut().zip(power).for_each(|(t, p)| *t += p);
+        fft_length = n;
+    }
+    let frequencies = bin_frequencies(sample_rate, fft_length);
+    let band = (1..total.len() - 1)
+        .filter(|k| frequencies[*k] >= config.low_hz && frequencies[*k] <= config.high_hz)
+        .collect::<Vec<_>>();
+    let band_power = band.iter().map(|k| total[*k]).sum::<f64>();
+    let peak = match band.iter().max_by(|a, b| total[**a].total_cmp(&total[**b])) {
+        Some(peak) if band_power > 0.0 => *peak,
+        _ => return (f64::NAN, 0.0),
+    };
+
+    // Refine the peak with a parabola through the neighbouring bins
+    let (a, b, c) = (total[peak - 1], total[peak], total[peak + 1]);
+    let offset = match a - 2.0 * b + c {
+        curvature if curvature < 0.0 => 0.5 * (a - c) / curvature,
+        _ => 0.0,
+    };
+    let peak_hz = (peak as f64 + offset) * sample_rate / fft_length as f64;
+
+    // Power within the main lobe of a Hann window around the peak
+    let lobe_hz = 2.0 / config.window_s;
+    let lobe_power = band
+        .iter()
+        .filter(|k| libm::fabs(frequencies[**k] - peak_hz) <= lobe_hz)
+        .map(|k| total[*k])
+        .sum::<f64>();
+    (60.0 * peak_hz, lobe_power / band_power)
+}
+
+/// Rate and confidence from upward crossings of one band-passed window, where
+/// a breath must swing below and then above a quarter standard deviation
+pub(crate) fn counted_rate(values: &[f64], sample_rate: f64) -> (f64, f64) {
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let hysteresis =
+        0.25 * libm::sqrt(values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / n);
+    let mut armed = false;
+    let mut breaths = Vec::new();
+    for (i, v) in values.iter().enumerate() {
+        if v - mean < -hysteresis {
+            armed = true;
+        } else if armed && v - mean > hysteresis {
+            armed = false;
+            breaths.push(i as f64 / sample_rate);
+        }
+    }
+    if breaths.len() < 3 || hysteresis == 0.0 {
+        return (f64::NAN, 0.0);
+    }
+
+    let intervals = breaths.windows(2).map(|b| b[1] - b[0]).collect::<Vec<_>>();
+    let count = intervals.len() as f64;
+    let mean_interval = intervals.iter().sum::<f64>() / count;
+    let std_dev = libm::sqrt(
+        intervals
+            .iter()
+            .map(|i| (i - mean_interval) * (i - mean_interval))
+            .sum::<f64>()
+            / count,
+    );
+    (
+        60.0 / mean_interval,
+        (1.0 - std_dev / mean_interval).max(0.0),
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn qs_default_respiration_config(config: *mut QsRespirationConfig) -> bool {
+    if config.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    unsafe {
+        core::ptr::write(config, QsRespirationConfig::default());
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_respiration_rate(
+    measurement_id: u32,
+    channel_mask: u32,
+    start_s: f64,
+    end_s: f64,
+    config: *const QsRespirationConfig,
+    windows: *mut QsRespirationWindow,
+    num_windows: *mut u32,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        let config = match unsafe { config.as_ref() } {
+            Some(config) => *config,
+            None => QsRespirationConfig::default(),
+        };
+        let method =
+            QsRespirationMethod::from_u8(config.method).ok_or("Unknown respiration method")?;
+        if channel_mask == 0 || channel_mask & !measurement.channel_mask() != 0 {
+            return Err("Channel mask selects channels that are not active");
+        }
+        let sample_rate = measurement.sample_rate()?;
+        let window = libm::round(config.window_s * sample_rate) as usize;
+        let step = libm::round(config.step_s * sample_rate) as usize;
+        if window < 4 || step == 0 {
+            return Err("Respiration window and step must each cover a sample");
+        }
+        let range = measurement.range_indices(start_s, end_s)?;
+        let mut channels = Vec::new();
+        for channel in (0..32).filter(|c| channel_mask & (1 << c) != 0) {
+            channels.push(measurement.channel_range(channel, start_s, end_s)?);
+        }
+        if method == QsRespirationMethod::PeakCounting {
+            let sections = [
+                Biquad::design(QsFilterKind::HighPass, sample_rate, config.low_hz, 0.707)?,
+                Biquad::design(QsFilterKind::LowPass, sample_rate, config.high_hz, 0.707)?,
+            ];
+            channels = channels.iter().map(|c| filtfilt(&sections, c)).collect();
+        }
+
+        let samples = range.end - range.start;
+        let estimates = (0..)
+            .map(|w| w * step)
+            .take_while(|start| start + window <= samples)
+            .map(|start| {
+                let segments = channels
+                    .iter()
+                    .map(|c| &c[start..start + window])
+                    .collect::<Vec<_>>();
+                let (rate_bpm, confidence) = match method {
+                    QsRespirationMethod::Spectral => spectral_rate(&segments, sample_rate, &config),
+                    QsRespirationMethod::PeakCounting => {
+                        let energy = |s: &[f64]| s.iter().map(|v| v * v).sum::<f64>();
+                        let strongest = segments
+                            .iter()
+                            .max_by(|a, b| energy(a).total_cmp(&energy(b)))
+                            .unwrap();
+                        counted_rate(strongest, sample_rate)
+                    }
+                };
+                QsRespirationWindow {
+                    start_s: (range.start + start) as f64 / sample_rate,
+                    rate_bpm,
+                    confidence,
+                }
+            })
+            .collect::<Vec<_>>();
+        write_buffers(&[(&estimates[..], windows)], num_windows)
+    });
+    report(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::measurement_with_samples;
+    use assert_approx_eq::assert_approx_eq;
+    use core::f64::consts::PI;
+
+    /// Chest motion at `bpm` with a cardiac component and sensor noise
+    fn chest_motion(bpm: f64, sample_rate: f64, seconds: f64, seed: u64) -> Vec<f64> {
+        let mut rng = XorShiftRng::seed_from_u64(seed);
+        (0..(seconds * sample_rate) as usize)
+            .map(|i| {
+                let t = i as f64 / sample_rate;
+                let uniform = rng.next_u32() as f64 / u32::MAX as f64 - 0.5;
+                300.0 * libm::sin(2.0 * PI * bpm / 60.0 * t)
+                    + 40.0 * libm::sin(2.0 * PI * 1.2 * t)
+                    + 100.0 * uniform
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spectral_and_counted_rates_agree() {
+        let sample_rate = 50.0;
+        let config = QsRespirationConfig::default();
+        let values = chest_motion(15.0, sample_rate, 30.0, 1);
+
+        let (rate, confidence) = spectral_rate(&[&values], sample_rate, &config);
+        assert_approx_eq!(rate, 15.0, 0.5);
+        assert!(confidence > 0.8);
+
+        let sections = [
+            Biquad::design(QsFilterKind::HighPass, sample_rate, 0.1, 0.707).unwrap(),
+            Biquad::design(QsFilterKind::LowPass, sample_rate, 1.0, 0.707).unwrap(),
+        ];
+        let (rate, confidence) = counted_rate(&filtfilt(&sections, &values), sample_rate);
+        assert_approx_eq!(rate, 15.0, 0.5);
+        assert!(confidence > 0.8);
+
+        let mut rng = XorShiftRng::seed_from_u64(3);
+        let noise = (0..values.len())
+            .map(|_| rng.next_u32() as f64 / u32::MAX as f64)
+            .collect::<Vec<_>>();
+        let (_, confidence) = spectral_rate(&[&noise], sample_rate, &config);
+        assert!(confidence < 0.5);
+    }
+
+    #[test]
+    fn respiration_trend_follows_rate_change() {
+        let sample_rate = 25.0;
+
+        // Breathing slows from 20 to 12 breaths per minute after a minute,
+        // mostly visible on the first axis
+        let mut first = chest_motion(20.0, sample_rate, 60.0, 5);
+        first.extend(chest_motion(12.0, sample_rate, 60.0, 6));
+        let second = chest_motion(20.0, sample_rate, 120.0, 7)
+            .iter()
+            .map(|v| v / 10.0)
+            .collect::<Vec<_>>();
+        let samples = first
+            .iter()
+            .zip(second.iter())
+            .map(|(a, b)| vec![*a as i16, *b as i16])
+            .collect::<Vec<_>>();
+        let measurement_id = measurement_with_samples(&samples, 25.0);
+
+        let mut config = QsRespirationConfig::default();
+        assert!(qs_default_respiration_config(&mut config));
+        config.step_s = 10.0;
+        for method in [
+            QsRespirationMethod::Spectral,
+            QsRespirationMethod::PeakCounting,
+        ]
+        .iter()
+        {
+            config.method = *method as u8;
+            let mut windows = [QsRespirationWindow::default(); 16];
+            let mut num_windows = 16;
+            assert!(qs_respiration_rate(
+                measurement_id,
+                0b11,
+                0.0,
+                f64::INFINITY,
+                &config,
+                windows.as_mut_ptr(),
+                &mut num_windows,
+            ));
+            assert_eq!(num_windows, 10);
+            assert_approx_eq!(windows[0].start_s, 0.0);
+            assert_approx_eq!(windows[0].rate_bpm, 20.0, 1.0);
+            assert_approx_eq!(windows[9].start_s, 90.0);
+            assert_approx_eq!(windows[9].rate_bpm, 12.0, 1.0);
+            assert!(windows[9].confidence > 0.5);
+        }
+
+        assert!(!qs_respiration_rate(
+            measurement_id,
+            0b100,
+            0.0,
+            f64::INFINITY,
+            core::ptr::null(),
+            core::ptr::null_mut(),
+            &mut 0,
+        ));
+
+        qs_drop_measurement(measurement_id);
+    }
+}
@@ -0,0 +1,601 @@
+use super::*;
+use core::f64::consts::PI;
+
+/// Longest FFT of a zero padded amplitude spectrum, bounding the buffer it allocates
+const MAX_FFT_LENGTH: usize = 1 << 16;
+
+/// Taper applied to each segment before the FFT
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsWindow {
+    Rectangular = 0,
+    Hann = 1,
+    Hamming = 2,
+    Blackman = 3,
+}
+
+impl QsWindow {
+    pub(crate) fn from_u8(value: u8) -> Option<QsWindow> {
+        match value {
+            0 => Some(QsWindow::Rectangular),
+            1 => Some(QsWindow::Hann),
+            2 => Some(QsWindow::Hamming),
+            3 => Some(QsWindow::Blackman),
+            _ => None,
+        }
+    }
+
+    /// Periodic window coefficients of length `n`
+    pub(crate) fn coefficients(self, n: usize) -> Vec<f64> {
+        (0..n)
+            .map(|i| {
+                let phase = 2.0 * PI * i as f64 / n as f64;
+                match self {
+                    QsWindow::Rectangular => 1.0,
+                    QsWindow::Hann => 0.5 - 0.5 * libm::cos(phase),
+                    QsWindow::Hamming => 0.54 - 0.46 * libm::cos(phase),
+                    QsWindow::Blackman => {
+                        0.42 - 0.5 * libm::cos(phase) + 0.08 * libm::cos(2.0 * phase)
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub fn norm_sqr(&self) -> f64 {
+        self.re * self.re + self.im * self.im
+    }
+}
+
+/// In-place iterative radix-2 FFT, `buf.len()` must be a power of two
+pub(crate) fn fft(buf: &mut [Complex]) {
+    let n = buf.len();
+    if n < 2 {
+        return;
+    }
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            buf.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f64;
+        for k in 0..len / 2 {
+            let (sin, cos) = (libm::sin(angle * k as f64), libm::cos(angle * k as f64));
+            for start in (0..n).step_by(len) {
+                let a = buf[start + k];
+                let b = buf[start + k + len / 2];
+                let t = Complex {
+                    re: b.re * cos - b.im * sin,
+                    im: b.re * sin + b.im * cos,
+                };
+                buf[start + k] = Complex {
+                    re: a.re + t.re,
+                    im: a.im + t.im,
+                };
+                buf[start + k + len / 2] = Complex {
+                    re: a.re - t.re,
+                    im: a.im - t.im,
+                };
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// Power of each one-sided bin of a windowed, mean-removed segment
+pub(crate) fn segment_power(segment: &[f64], window: &[f64]) -> Vec<f64> {
+    let mean = segment.iter().sum::<f64>() / segment.len() as f64;
+    let mut buf = segment
+        .iter()
+        .zip(window)
+        .map(|(v, w)| Complex {
+            re: (v - mean) * w,
+            im: 0.0,
+        })
+        .collect::<Vec<_>>();
+    fft(&mut buf);
+    buf[..=segment.len() / 2]
+        .iter()
+        .map(|c| c.norm_sqr())
+        .collect()
+}
+
+/// Power of each one-sided bin of a mean-removed and windowed series,
+/// zero padded to the next power of two which is returned as the FFT length
+pub(crate) fn padded_power(values: &[f64], window: QsWindow) -> (Vec<f64>, usize) {
+    let fft_length = values.len().next_power_of_two().max(2);
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let mut buf = vec![Complex::default(); fft_length];
+    for (i, (v, w)) in values
+        .iter()
+        .zip(window.coefficients(values.len()))
+        .enumerate()
+    {
+        buf[i].re = (v - mean) * w;
+    }
+    fft(&mut buf);
+    let power = buf[..=fft_length / 2]
+        .iter()
+        .map(|c| c.norm_sqr())
+        .collect();
+    (power, fft_length)
+}
+
+/// Frequencies of the one-sided bins of an FFT of length `n`
+pub(crate) fn bin_frequencies(sample_rate: f64, n: usize) -> Vec<f64> {
+    (0..=n / 2)
+        .map(|k| k as f64 * sample_rate / n as f64)
+        .collect()
+}
+
+/// One-sided power spectral density in units^2/Hz by Welch's averaged periodogram
+pub(crate) fn welch(
+    values: &[f64],
+    sample_rate: f64,
+    window: QsWindow,
+    segment_length: usize,
+    overlap: usize,
+) -> Result<Vec<f64>, &'static str> {
+    if segment_length < 2 || !segment_length.is_power_of_two() {
+        return Err("Segment length must be a power of two");
+    }
+    if overlap >= segment_length {
+        return Err("Segment overlap must be less than the segment length");
+    }
+    if values.len() < segment_length {
+        return Err("Time range is shorter than one segment");
+    }
+
+    let window = window.coefficients(segment_length);
+    let scale = 1.0 / (sample_rate * window.iter().map(|w| w * w).sum::<f64>());
+    let mut psd = vec![0.0; segment_length / 2 + 1];
+    let mut segments = 0;
+    for start in (0..=values.len() - segment_length).step_by(segment_length - overlap) {
+        let power = segment_power(&values[start..start + segment_length], &window);
+        psd.iter_mut().zip(power).for_each(|(p, v)| *p += v);
+        segments += 1;
+    }
+
+    let nyquist = segment_length / 2;
+    for (k, p) in psd.iter_mut().enumerate() {
+        let one_sided = if k == 0 || k == nyquist { 1.0 } else { 2.0 };
+        *p *= one_sided * scale / segments as f64;
+    }
+    Ok(psd)
+}
+
+/// Single-sided amplitude spectrum of the first `fft_length` values, zero padded as needed
+pub(crate) fn amplitude_spectrum(
+    values: &[f64],
+    window: QsWindow,
+    fft_length: usize,
+) -> Result<Vec<f64>, &'static str> {
+    if fft_length < 2 || !fft_length.is_power_of_two() {
+        return Err("FFT length must be a power of two");
+    }
+    if fft_length > MAX_FFT_LENGTH {
+        return Err("FFT length may be at most 65536");
+    }
+    let used = min(values.len(), fft_length);
+    let coefficients = window.coefficients(used);
+    let gain = coefficients.iter().sum::<f64>();
+    let mut buf = vec![Complex::default(); fft_length];
+    for i in 0..used {
+        buf[i].re = values[i] * coefficients[i];
+    }
+    fft(&mut buf);
+
+    Ok(buf[..=fft_length / 2]
+        .iter()
+        .enumerate()
+        .map(|(k, c)| {
+            let one_sided = if k == 0 || k == fft_length / 2 {
+                1.0
+            } else {
+                2.0
+            };
+            one_sided * libm::sqrt(c.norm_sqr()) / gain
+        })
+        .collect())
+}
+
+/// Magnitude frames of a short-time Fourier transform, `[frame][bin]`
+pub(crate) fn stft(
+    values: &[f64],
+    window: QsWindow,
+    window_length: usize,
+    hop_length: usize,
+) -> Result<Vec<Vec<f64>>, &'static str> {
+    if window_length < 2 || !window_length.is_power_of_two() {
+        return Err("Window length must be a power of two");
+    }
+    if hop_length == 0 {
+        return Err("Hop length must be non-zero");
+    }
+    if values.len() < window_length {
+        return Err("Time range is shorter than one window");
+    }
+
+    let coefficients = window.coefficients(window_length);
+    Ok((0..=values.len() - window_length)
+        .step_by(hop_length)
+        .map(|start| {
+            let mut buf = values[start..start + window_length]
+                .iter()
+                .zip(&coefficients)
+                .map(|(v, w)| Complex { re: v * w, im: 0.0 })
+                .collect::<Vec<_>>();
+            fft(&mut buf);
+            buf[..=window_length / 2]
+                .iter()
+                .map(|c| libm::sqrt(c.norm_sqr()))
+                .collect()
+        })
+        .collect())
+}
+
+fn hz_to_mel(hz: f64) -> f64 {
+    2595.0 * libm::log10(1.0 + hz / 700.0)
+}
+
+fn mel_to_hz(mel: f64) -> f64 {
+    700.0 * (libm::pow(10.0, mel / 2595.0) - 1.0)
+}
+
+/// Triangular filters evenly spaced on the mel scale up to Nyquist, returned with their center frequencies
+pub(crate) fn mel_filterbank(
+    num_mels: usize,
+    fft_length: usize,
+    sample_rate: f64,
+) -> (Vec<Vec<f64>>, Vec<f64>) {
+    let max_mel = hz_to_mel(sample_rate / 2.0);
+    let edges = (0..num_mels + 2)
+        .map(|m| mel_to_hz(max_mel * m as f64 / (num_mels + 1) as f64))
+        .collect::<Vec<_>>();
+    let bins = bin_frequencies(sample_rate, fft_length);
+    let filters = edges
+        .windows(3)
+        .map(|edge| {
+            bins.iter()
+                .map(|f| {
+                    if *f > edge[0] && *f <= edge[1] {
+                        (f - edge[0]) / (edge[1] - edge[0])
+                    } else if *f > edge[1] && *f < edge[2] {
+                        (edge[2] - f) / (edge[2] - edge[1])
+                    } else {
+                        0.0
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    (filters, edges[1..=num_mels].to_vec())
+}
+
+/// Converts magnitudes to decibels relative to 1, flooring silence at -240 dB
+pub(crate) fn to_decibels(magnitude: f64) -> f64 {
+    20.0 * libm::log10(magnitude.max(1e-12))
+}
+
+#[no_mangle]
+pub extern "C" fn qs_welch_psd(
+    measurement_id: u32,
+    channel: u8,
+    start_s: f64,
+    end_s: f64,
+    window: u8,
+    segment_length: u32,
+    overlap: u32,
+    frequencies: *mut f64,
+    power: *mut f64,
+    num_bins: *mut u32,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        let window = QsWindow::from_u8(window).ok_or("Unknown window for spectrum")?;
+        let values = measurement.channel_range(channel, start_s, end_s)?;
+        let sample_rate = measurement.sample_rate()?;
+        let psd = welch(
+            &values,
+            sample_rate,
+            window,
+            segment_length as usize,
+            overlap as usize,
+        )?;
+        let bins = bin_frequencies(sample_rate, segment_length as usize);
+        write_buffers(&[(&bins[..], frequencies), (&psd[..], power)], num_bins)
+    });
+    report(result)
+}
+
+#[no_mangle]
+pub extern "C" fn qs_amplitude_spectrum(
+    measurement_id: u32,
+    channel: u8,
+    start_s: f64,
+    end_s: f64,
+    window: u8,
+    fft_length: u32,
+    frequencies: *mut f64,
+    amplitude: *mut f64,
+    num_bins: *mut u32,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        let window = QsWindow::from_u8(window).ok_or("Unknown window for spectrum")?;
+        let values = measurement.channel_range(channel, start_s, end_s)?;
+        let sample_rate = measurement.sample_rate()?;
+        let spectrum = amplitude_spectrum(&values, window, fft_length as usize)?;
+        let bins = bin_frequencies(sample_rate, fft_length as usize);
+        write_buffers(
+            &[(&bins[..], frequencies), (&spectrum[..], amplitude)],
+            num_bins,
+        )
+    });
+    report(result)
+}
+
+#[no_mangle]
+pub extern "C" fn qs_spectrogram(
+    measurement_id: u32,
+    channel: u8,
+    start_s: f64,
+    end_s: f64,
+    window: u8,
+    window_length: u32,
+    hop_length: u32,
+    num_mels: u32,
+    decibels: bool,
+    times: *mut f64,
+    frequencies: *mut f64,
+    magnitudes: *mut f64,
+    num_frames: *mut u32,
+    num_bins: *mut u32,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        if magnitudes.is_null() || num_frames.is_null() || num_bins.is_null() {
+            return Err("Null buffer passed to the library");
+        }
+        let window = QsWindow::from_u8(window).ok_or("Unknown window for spectrogram")?;
+        let range = measurement.range_indices(start_s, end_s)?;
+        let values = measurement.channel_range(channel, start_s, end_s)?;
+        let sample_rate = measurement.sample_rate()?;
+        let (window_length, hop_length) = (window_length as usize, hop_length as usize);
+        let mut frames = stft(&values, window, window_length, hop_length)?;
+
+        let bins = if num_mels == 0 {
+            bin_frequencies(sample_rate, window_length)
+        } else {
+            let (filters, centers) = mel_filterbank(num_mels as usize, window_length, sample_rate);
+            for frame in frames.iter_mut() {
+                *frame = filters
+                    .iter()
+                    .map(|filter| filter.iter().zip(frame.iter()).map(|(w, m)| w * m).sum())
+                    .collect();
+            }
+            centers
+        };
+        if decibels {
+            for frame in frames.iter_mut() {
+                frame.iter_mut().for_each(|m| *m = to_decibels(*m));
+            }
+        }
+        let frame_times = (0..frames.len())
+            .map(|f| (range.start + f * hop_length) as f64 / sample_rate)
+            .map(|t| t + window_length as f64 / 2.0 / sample_rate)
+            .collect::<Vec<_>>();
+
+        write_buffers(&[(&frame_times[..], times)], num_frames)?;
+        write_buffers(&[(&bins[..], frequencies)], num_bins)?;
+        for (f, frame) in frames.iter().enumerate() {
+            unsafe {
+                copy_nonoverlapping(frame.as_ptr(), magnitudes.add(f * bins.len()), bins.len());
+            }
+        }
+        Ok(())
+    });
+    report(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::measurement_with_samples;
+    use assert_approx_eq::assert_approx_eq;
+
+    fn tone(hz: f64, amplitude: f64, sample_rate: f64, samples: usize) -> Vec<f64> {
+        (0..samples)
+            .map(|i| amplitude * libm::sin(2.0 * PI * hz * i as f64 / sample_rate))
+            .collect()
+    }
+
+    #[test]
+    fn fft_matches_direct_dft() {
+        let values = tone(3.0, 1.0, 16.0, 16)
+            .iter()
+            .enumerate()
+            .map(|(i, v)| v + 0.25 * i as f64)
+            .collect::<Vec<_>>();
+        let mut buf = values
+            .iter()
+            .map(|v| Complex { re: *v, im: 0.0 })
+            .collect::<Vec<_>>();
+        fft(&mut buf);
+        for (k, bin) in buf.iter().enumerate() {
+            let (mut re, mut im) = (0.0, 0.0);
+            for (i, v) in values.iter().enumerate() {
+                let angle = -2.0 * PI * (k * i) as f64 / 16.0;
+                re += v * libm::cos(angle);
+                im += v * libm::sin(angle);
+            }
+            assert_approx_eq!(bin.re, re, 1e-9);
+            assert_approx_eq!(bin.im, im, 1e-9);
+        }
+    }
+
+    #[test]
+    fn welch_integrates_to_tone_power() {
+        let sample_rate = 256.0;
+        let values = tone(32.0, 2.0, sample_rate, 4096);
+        let psd = welch(&values, sample_rate, QsWindow::Hann, 256, 128).unwrap();
+        let peak = (0..psd.len())
+            .max_by(|a, b| psd[*a].total_cmp(&psd[*b]))
+            .unwrap();
+        assert_eq!(peak, 32);
+
+        // Parseval: the PSD integrates to the variance of the signal
+        let total = psd.iter().sum::<f64>() * sample_rate / 256.0;
+        assert_approx_eq!(total, 2.0, 1e-6);
+    }
+
+    #[test]
+    fn amplitude_spectrum_recovers_tone_amplitude() {
+        let spectrum =
+            amplitude_spectrum(&tone(10.0, 3.0, 128.0, 128), QsWindow::Rectangular, 128).unwrap();
+        assert_approx_eq!(spectrum[10], 3.0, 1e-9);
+        assert_approx_eq!(spectrum[11], 0.0, 1e-9);
+
+        let values = tone(10.0, 3.0, 128.0, 128);
+        assert!(amplitude_spectrum(&values, QsWindow::Rectangular, 96).is_err());
+        assert!(amplitude_spectrum(&values, QsWindow::Rectangular, MAX_FFT_LENGTH).is_ok());
+        assert!(amplitude_spectrum(&values, QsWindow::Rectangular, 2 * MAX_FFT_LENGTH).is_err());
+    }
+
+    #[test]
+    fn welch_psd_over_measurement_time_range() {
+        let samples = (0..1000)
+            .map(|i| {
+                let t = i as f64 / 100.0;
+                vec![(1000.0 * libm::sin(2.0 * PI * 12.5 * t)) as i16]
+            })
+            .collect::<Vec<_>>();
+        let measurement_id = measurement_with_samples(&samples, 100.0);
+
+        let mut frequencies = [0.0; 65];
+        let mut power = [0.0; 65];
+        let mut num_bins = 65;
+        assert!(qs_welch_psd(
+            measurement_id,
+            0,
+            1.0,
+            9.0,
+            QsWindow::Hann as u8,
+            128,
+            64,
+            frequencies.as_mut_ptr(),
+            power.as_mut_ptr(),
+            &mut num_bins,
+        ));
+        assert_eq!(num_bins, 65);
+        assert_approx_eq!(frequencies[64], 50.0);
+        let peak = (0..65)
+            .max_by(|a, b| power[*a].total_cmp(&power[*b]))
+            .unwrap();
+        assert_approx_eq!(frequencies[peak], 12.5);
+
+        let mut num_bins = 64;
+        assert!(!qs_welch_psd(
+            measurement_id,
+            0,
+            0.0,
+            f64::INFINITY,
+            QsWindow::Hann as u8,
+            128,
+            64,
+            frequencies.as_mut_ptr(),
+            power.as_mut_ptr(),
+            &mut num_bins,
+        ));
+
+        qs_drop_measurement(measurement_id);
+    }
+
+    #[test]
+    fn stft_tracks_tone_change() {
+        let sample_rate = 64.0;
+        let mut values = tone(8.0, 1.0, sample_rate, 256);
+        values.extend(tone(24.0, 1.0, sample_rate, 256));
+        let frames = stft(&values, QsWindow::Hann, 32, 16).unwrap();
+        assert_eq!(frames.len(), 1 + (512 - 32) / 16);
+
+        let peak = |frame: &Vec<f64>| {
+            (0..frame.len())
+                .max_by(|a, b| frame[*a].total_cmp(&frame[*b]))
+                .unwrap()
+        };
+        assert_eq!(peak(&frames[0]), 4);
+        assert_eq!(peak(&frames[frames.len() - 1]), 12);
+    }
+
+    #[test]
+    fn mel_filters_cover_spectrum_in_order() {
+        let (filters, centers) = mel_filterbank(10, 256, 1000.0);
+        assert_eq!(filters.len(), 10);
+        assert_eq!(filters[0].len(), 129);
+        assert!(centers.windows(2).all(|c| c[0] < c[1]));
+        assert!(centers[9] < 500.0);
+        assert!(filters.iter().all(|f| f.iter().any(|w| *w > 0.0)));
+    }
+
+    #[test]
+    fn spectrogram_with_mel_bins_in_decibels() {
+        let samples = (0..1000)
+            .map(|i| {
+                let t = i as f64 / 1000.0;
+                vec![(1000.0 * libm::sin(2.0 * PI * 125.0 * t)) as i16]
+            })
+            .collect::<Vec<_>>();
+        let measurement_id = measurement_with_samples(&samples, 1000.0);
+
+        let mut times = [0.0; 16];
+        let mut frequencies = [0.0; 20];
+        let mut magnitudes = [0.0; 16 * 20];
+        let (mut num_frames, mut num_bins) = (16, 20);
+        assert!(qs_spectrogram(
+            measurement_id,
+            0,
+            0.0,
+            0.5,
+            QsWindow::Hann as u8,
+            128,
+            64,
+            20,
+            true,
+            times.as_mut_ptr(),
+            frequencies.as_mut_ptr(),
+            magnitudes.as_mut_ptr(),
+            &mut num_frames,
+            &mut num_bins,
+        ));
+        assert_eq!(num_frames, 1 + (500 - 128) / 64);
+        assert_eq!(num_bins, 20);
+        assert_approx_eq!(times[0], 0.064);
+        assert_approx_eq!(times[1] - times[0], 0.064);
+
+        let frame = &magnitudes[..20];
+        let peak = (0..20)
+            .max_by(|a, b| frame[*a].total_cmp(&frame[*b]))
+            .unwrap();
+        assert!((frequencies[peak] - 125.0).abs() < 30.0);
+        assert!(frame[peak] > 40.0);
+
+        qs_drop_measurement(measurement_id);
+    }
+}
@@ -0,0 +1,275 @@
+use super::*;
+
+/// Summary statistics of one channel over one window
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QsWindowStats {
+    /// Time of the first sample in the window, NaN when the sample rate is not set
+    pub start_s: f64,
+    pub mean: f64,
+    pub rms: f64,
+    pub min: f64,
+    pub max: f64,
+    pub std_dev: f64,
+    /// Fraction of consecutive sample pairs that change sign
+    pub zero_crossing_rate: f64,
+    pub samples: u32,
+}
+
+/// Accumulates the statistics of a window one sample at a time
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RunningStats {
+    count: u32,
+    sum: f64,
+    sum_sq: f64,
+    min: f64,
+    max: f64,
+    crossings: u32,
+    prev: f64,
+}
+
+impl Default for RunningStats {
+    fn default() -> Self {
+        RunningStats {
+            count: 0,
+            sum: 0.0,
+            sum_sq: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            crossings: 0,
+            prev: 0.0,
+        }
+    }
+}
+
+impl RunningStats {
+    pub fn push(&mut self, value: f64) {
+        if self.count > 0 && (self.prev < 0.0) != (value < 0.0) {
+            self.crossings += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+        self.sum_sq += value * value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.prev = value;
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn finish(&self, start_s: f64) -> QsWindowStats {
+        let n = self.count as f64;
+        let mean = self.sum / n;
+        let variance = (self.sum_sq / n - mean * mean).max(0.0);
+        QsWindowStats {
+            start_s,
+            mean,
+            rms: libm::sqrt(self.sum_sq / n),
+            min: self.min,
+            max: self.max,
+            std_dev: libm::sqrt(variance),
+            zero_crossing_rate: if self.count > 1 {
+                self.crossings as f64 / (n - 1.0)
+            } else {
+                0.0
+            },
+            samples: self.count,
+        }
+    }
+}
+
+/// Statistics of fixed length windows maintained as payloads are consumed. Unlike
+/// `qs_window_stats` they summarize the raw counts of the physical channels in arrival
+/// order, before calibration or any pipeline, so they stay cheap enough to run per payload.
+pub(crate) struct LiveStats {
+    window: u32,
+    start_index: u64,
+    accumulators: [RunningStats; 8],
+    latest: [Option<QsWindowStats>; 8],
+}
+
+impl LiveStats {
+    pub fn new(window: u32) -> LiveStats {
+        LiveStats {
+            window,
+            start_index: 0,
+            accumulators: Default::default(),
+            latest: Default::default(),
+        }
+    }
+
+    /// Feeds the raw counts of a payload in arrival order, completing windows as they fill
+    pub fn push_payload(&mut self, payload: &Payload, sample_rate: Option<f64>) {
+        let channels = min(payload.active_channels, 8) as usize;
+        for i in 0..payload.channels[0].len() {
+            for c in 0..channels {
+                let value = payload.channels[c].get(i).map_or(0, |v| *v);
+                self.accumulators[c].push(value as f64);
+            }
+            if self.accumulators[0].count() == self.window {
+                let start_s = sample_rate.map_or(f64::NAN, |r| self.start_index as f64 / r);
+                for c in 0..channels {
+                    self.latest[c] = Some(self.accumulators[c].finish(start_s));
+                    self.accumulators[c] = RunningStats::default();
+                }
+                self.start_index += self.window as u64;
+            }
+        }
+    }
+}
+
+/// Statistics of consecutive non-overlapping windows, dropping a trailing partial window
+pub(crate) fn window_stats(
+    values: &[f64],
+    window: usize,
+    start_s: f64,
+    sample_rate: f64,
+) -> Vec<QsWindowStats> {
+    values
+        .chunks_exact(window)
+        .enumerate()
+        .map(|(w, chunk)| {
+            let mut stats = RunningStats::default();
+            chunk.iter().for_each(|v| stats.push(*v));
+            stats.finish(start_s + (w * window) as f64 / sample_rate)
+        })
+        .collect()
+}
+
+#[no_mangle]
+pub extern "C" fn qs_enable_live_stats(measurement_id: u32, window_samples: u32) -> bool {
+    let rw_measurement = match find_measurement_by_id(measurement_id) {
+        Some(rwm) => rwm,
+        None => {
+            push_error("No measurement exists for the given id");
+            return false;
+        }
+    };
+    let mut measurement_guard = rw_measurement.measurement.write();
+    measurement_guard.live_stats = match window_samples {
+        0 => None,
+        window => Some(LiveStats::new(window)),
+    };
+
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_live_stats(
+    measurement_id: u32,
+    channel: u8,
+    stats: *mut QsWindowStats,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        if stats.is_null() {
+            return Err("Null buffer passed to the library");
+        }
+        let live = measurement
+            .live_stats
+            .as_ref()
+            .ok_or("Live statistics are not enabled for the measurement")?;
+        let latest = live
+            .latest
+            .get(channel as usize)
+            .and_then(|s| *s)
+            .ok_or("No complete window of live statistics for the channel")?;
+        unsafe {
+            core::ptr::write(stats, latest);
+        }
+        Ok(())
+    });
+    report(result)
+}
+
+#[no_mangle]
+pub extern "C" fn qs_window_stats(
+    measurement_id: u32,
+    channel: u8,
+    start_s: f64,
+    end_s: f64,
+    window_s: f64,
+    stats: *mut QsWindowStats,
+    num_windows: *mut u32,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        let sample_rate = measurement.sample_rate()?;
+        let window = libm::round(window_s * sample_rate) as usize;
+        if window == 0 {
+            return Err("Window is shorter than one sample");
+        }
+        let range = measurement.range_indices(start_s, end_s)?;
+        let values = measurement.channel_range(channel, start_s, end_s)?;
+        let windows = window_stats(
+            &values,
+            window,
+            range.start as f64 / sample_rate,
+            sample_rate,
+        );
+        write_buffers(&[(&windows[..], stats)], num_windows)
+    });
+    report(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::add_samples;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn running_stats_of_square_wave() {
+        let mut stats = RunningStats::default();
+        [3.0, -3.0, 3.0, -3.0, 3.0, -1.0]
+            .iter()
+            .for_each(|v| stats.push(*v));
+        let stats = stats.finish(0.0);
+        assert_approx_eq!(stats.mean, 2.0 / 6.0);
+        assert_approx_eq!(stats.rms, libm::sqrt(46.0 / 6.0));
+        assert_approx_eq!(stats.std_dev, libm::sqrt(46.0 / 6.0 - 1.0 / 9.0));
+        assert_approx_eq!(stats.min, -3.0);
+        assert_approx_eq!(stats.max, 3.0);
+        assert_approx_eq!(stats.zero_crossing_rate, 1.0);
+        assert_eq!(stats.samples, 6);
+    }
+
+    #[test]
+    fn live_and_on_demand_window_stats() {
+        let measurement_id = qs_create_measurement(2);
+        assert!(qs_set_sample_rate(measurement_id, 10.0, 1.0));
+        assert!(qs_enable_live_stats(measurement_id, 15));
+
+        let mut live = QsWindowStats::default();
+        assert!(!qs_live_stats(measurement_id, 0, &mut live));
+        let samples = (0..40)
+            .map(|i| vec![i, if i % 2 == 0 { 100 } else { -100 }])
+            .collect::<Vec<_>>();
+        add_samples(measurement_id, &samples);
+
+        // 40 samples make two complete live windows, the latest covering samples 15..30
+        assert!(qs_live_stats(measurement_id, 0, &mut live));
+        assert_approx_eq!(live.start_s, 1.5);
+        assert_approx_eq!(live.mean, 22.0);
+        assert_approx_eq!(live.min, 15.0);
+        assert_approx_eq!(live.max, 29.0);
+
+        let mut windows = [QsWindowStats::default(); 4];
+        let mut num_windows = 4;
+        assert!(qs_window_stats(
+            measurement_id,
+            1,
+            1.0,
+            f64::INFINITY,
+            1.5,
+            windows.as_mut_ptr(),
+            &mut num_windows,
+        ));
+        assert_eq!(num_windows, 2);
+        assert_approx_eq!(windows[1].start_s, 2.5);
+        assert_approx_eq!(windows[1].rms, 100.0);
+        assert_approx_eq!(windows[1].zero_crossing_rate, 1.0);
+
+        qs_drop_measurement(measurement_id);
+    }
+}
@@ -0,0 +1,793 @@
+use super::*;
+use crate::filter::{Biquad, BiquadState, QsFilterKind};
+use alloc::collections::VecDeque;
+use core::ffi::c_void;
+
+/// Completed snapshots kept per measurement, the oldest are dropped first
+const MAX_SNAPSHOTS: usize = 32;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsTriggerKind {
+    /// The channel rises through the level
+    Rising = 0,
+    /// The channel falls through the level
+    Falling = 1,
+    /// The RMS of the channel within a band rises through the level
+    BandRms = 2,
+}
+
+impl QsTriggerKind {
+    pub(crate) fn from_u8(value: u8) -> Option<QsTriggerKind> {
+        match value {
+            0 => Some(QsTriggerKind::Rising),
+            1 => Some(QsTriggerKind::Falling),
+            2 => Some(QsTriggerKind::BandRms),
+            _ => None,
+        }
+    }
+}
+
+/// Condition on one physical channel and the context captured when it is met
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsTriggerConfig {
+    pub kind: u8,
+    pub channel: u8,
+    /// Counts for level triggers and RMS counts for band triggers
+    pub level: f64,
+    pub band_low_hz: f64,
+    pub band_high_hz: f64,
+    /// Length of the moving RMS for band triggers
+    pub rms_window_s: f64,
+    /// Context captured before the triggering sample
+    pub pre_s: f64,
+    /// Context captured from the triggering sample on
+    pub post_s: f64,
+    /// Time after a capture completes before the trigger re-arms
+    pub holdoff_s: f64,
+}
+
+impl Default for QsTriggerConfig {
+    fn default() -> Self {
+        QsTriggerConfig {
+            kind: QsTriggerKind::Rising as u8,
+            channel: 0,
+            level: 0.0,
+            band_low_hz: 1.0,
+            band_high_hz: 10.0,
+            rms_window_s: 0.5,
+            pre_s: 2.0,
+            post_s: 2.0,
+            holdoff_s: 0.0,
+        }
+    }
+}
+
+/// A captured window of every physical channel around a trigger
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QsSnapshot {
+    pub snapshot_id: u32,
+    pub trigger_id: u32,
+    /// Time of the triggering sample on the measurement timeline of samples in counter order
+    pub trigger_s: f64,
+    pub start_s: f64,
+    /// The channel value or band RMS that met the condition
+    pub value: f64,
+    /// Notification counter of the payload holding the triggering sample
+    pub trigger_counter: u64,
+    pub samples_per_channel: u32,
+    pub channels: u8,
+}
+
+/// Called with each completed snapshot after the measurement is unlocked
+pub type QsTriggerCallback =
+    Option<extern "C" fn(measurement_id: u32, snapshot: *const QsSnapshot, user_data: *mut c_void)>;
+
+struct Snapshot {
+    info: QsSnapshot,
+    values: Vec<Vec<f64>>,
+}
+
+struct Capture {
+    snapshot: Snapshot,
+    remaining: usize,
+}
+
+struct Trigger {
+    id: u32,
+    /// The settings the trigger was built from, kept to rebuild it for a new sample rate
+    config: QsTriggerConfig,
+    kind: QsTriggerKind,
+    channel: usize,
+    level: f64,
+    pre: usize,
+    post: usize,
+    holdoff: u64,
+    callback: QsTriggerCallback,
+    /// The host pointer handed back to the callback
+    user_data: usize,
+    sections: Vec<Biquad>,
+    states: Vec<BiquadState>,
+    squares: VecDeque<f64>,
+    window: usize,
+    sum_squares: f64,
+    previous: Option<f64>,
+    armed_from: u64,
+    capture: Option<Capture>,
+}
+
+impl Trigger {
+    fn new(
+        id: u32,
+        config: &QsTriggerConfig,
+        sample_rate: f64,
+        callback: QsTriggerCallback,
+        user_data: usize,
+    ) -> Result<Trigger, &'static str> {
+        let kind = QsTriggerKind::from_u8(config.kind).ok_or("Unknown trigger kind")?;
+        if !config.level.is_finite() {
+            return Err("Trigger level must be a number");
+        }
+        let samples = |seconds: f64| {
+            if seconds >= 0.0 && seconds.is_finite() {
+                Ok(libm::round(seconds * sample_rate) as usize)
+            } else {
+                Err("Trigger durations must be non-negative numbers of seconds")
+            }
+        };
+        let (pre, post, holdoff) = (
+            samples(config.pre_s)?,
+            samples(config.post_s)?.max(1),
+            samples(config.holdoff_s)?,
+        );
+        let (sections, window) = match kind {
+            QsTriggerKind::BandRms => (
+                vec![
+                    Biquad::design(
+                        QsFilterKind::HighPass,
+                        sample_rate,
+                        config.band_low_hz,
+                        0.707,
+                    )?,
+                    Biquad::design(
+                        QsFilterKind::LowPass,
+                        sample_rate,
+                        config.band_high_hz,
+                        0.707,
+                    )?,
+                ],
+                samples(config.rms_window_s)?.max(1),
+            ),
+            _ => (Vec::new(), 1),
+        };
+        Ok(Trigger {
+            id,
+            config: *config,
+            kind,
+            channel: config.channel as usize,
+            level: config.level,
+            pre,
+            post,
+            holdoff: holdoff as u64,
+            callback,
+            user_data,
+            states: vec![BiquadState::default(); sections.len()],
+            sections,
+            squares: VecDeque::with_capacity(window),
+            window,
+            sum_squares: 0.0,
+            previous: None,
+            armed_from: 0,
+            capture: None,
+        })
+    }
+
+    /// The quantity compared with the level, once enough samples have been seen
+    fn measure(&mut self, x: f64) -> Option<f64> {
+        if self.kind != QsTriggerKind::BandRms {
+            return Some(x);
+        }
+        if self.squares.is_empty() {
+            // Start the band filters settled on the first sample so its offset is not a step
+            let mut x = x;
+            for (section, state) in self.sections.iter().zip(self.states.iter_mut()) {
+                let (settled, y) = section.settled(x);
+                *state = settled;
+                x = y;
+            }
+        }
+        let y = self
+            .sections
+            .iter()
+            .zip(self.states.iter_mut())
+            .fold(x, |x, (section, state)| section.process(state, x));
+        self.squares.push_back(y * y);
+        self.sum_squares += y * y;
+        if self.squares.len() > self.window {
+            self.sum_squares -= self.squares.pop_front().unwrap_or(0.0);
+        }
+        if self.squares.len() < self.window {
+            return None;
+        }
+        Some(libm::sqrt(self.sum_squares.max(0.0) / self.window as f64))
+    }
+
+    /// The measured value when this sample meets the condition
+    fn fires(&mut self, x: f64) -> Option<f64> {
+        let current = self.measure(x)?;
+        let previous = self.previous.replace(current)?;
+        let crossed = match self.kind {
+            QsTriggerKind::Falling => previous > self.level && current <= self.level,
+            _ => previous < self.level && current >= self.level,
+        };
+        if crossed {
+            Some(current)
+        } else {
+            None
+        }
+    }
+}
+
+/// Triggers evaluated as payloads are consumed, with the snapshots they captured
+#[derive(Default)]
+pub(crate) struct Triggers {
+    sample_rate: f64,
+    triggers: Vec<Trigger>,
+    next_trigger_id: u32,
+    next_snapshot_id: u32,
+    /// Samples consumed so far in arrival order, which holdoffs count
+    sample_index: u64,
+    /// Recent samples of every physical channel for pre-trigger context
+    history: VecDeque<[i16; 8]>,
+    snapshots: Vec<Snapshot>,
+    completed: Vec<(QsTriggerCallback, usize, QsSnapshot)>,
+}
+
+impl Triggers {
+    fn history_length(&self) -> usize {
+        self.triggers.iter().map(|t| t.pre).max().unwrap_or(0)
+    }
+
+    /// Rebuilds every trigger for a new sample rate and moves the snapshots onto the new
+    /// timeline. Captures in progress are discarded, and nothing changes on failure.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) -> Result<(), &'static str> {
+        if sample_rate == self.sample_rate {
+            return Ok(());
+        }
+        let triggers = self
+            .triggers
+            .iter()
+            .map(|t| {
+                Trigger::new(t.id, &t.config, sample_rate, t.callback, t.user_data).map(
+                    |mut trigger| {
+                        trigger.armed_from = t.armed_from;
+                        trigger
+                    },
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if self.sample_rate > 0.0 {
+            let scale = self.sample_rate / sample_rate;
+            for snapshot in self.snapshots.iter_mut() {
+                snapshot.info.trigger_s *= scale;
+                snapshot.info.start_s *= scale;
+            }
+        }
+        self.triggers = triggers;
+        self.sample_rate = sample_rate;
+        Ok(())
+    }
+
+    /// Feeds a payload in arrival order, capturing and completing snapshots. The payload
+    /// starts at sample `first_index` of the measurement in counter order.
+    pub fn push_payload(&mut self, payload: &Payload, first_index: u64) {
+        // Snapshots after a late payload move along the timeline as its samples are inserted
+        let inserted = payload.channels[0].len() as f64 / self.sample_rate;
+        let captures = self
+            .triggers
+            .iter_mut()
+            .filter_map(|t| t.capture.as_mut().map(|c| &mut c.snapshot));
+        for snapshot in self.snapshots.iter_mut().chain(captures) {
+            if snapshot.info.trigger_counter > payload.counter {
+                snapshot.info.trigger_s += inserted;
+                snapshot.info.start_s += inserted;
+            }
+        }
+        if self.triggers.is_empty() {
+            self.sample_index += payload.channels[0].len() as u64;
+            self.history.clear();
+            return;
+        }
+        let channels = min(payload.active_channels, 8) as usize;
+        let history_length = self.history_length();
+        for i in 0..payload.channels[0].len() {
+            let mut sample = [0i16; 8];
+            for (value, channel) in sample.iter_mut().zip(&payload.channels[..channels]) {
+                *value = channel.get(i).map_or(0, |v| *v);
+            }
+            let index = self.sample_index;
+            for trigger in self.triggers.iter_mut() {
+                let fired = trigger.fires(sample[trigger.channel] as f64);
+                if trigger.capture.is_none() && index >= trigger.armed_from {
+                    if let Some(value) = fired {
+                        let history = &self.history;
+                        let pre = min(trigger.pre, history.len());
+                        let values = (0..channels)
+                            .map(|c| {
+                                let mut values = Vec::with_capacity(pre + trigger.post);
+                                values.extend(
+                                    history
+                                        .iter()
+                                        .skip(history.len() - pre)
+                                        .map(|s| s[c] as f64),
+                                );
+                                values
+                            })
+                            .collect();
+                        let trigger_s = (first_index + i as u64) as f64 / self.sample_rate;
+                        self.next_snapshot_id += 1;
+                        trigger.capture = Some(Capture {
+                            snapshot: Snapshot {
+                                info: QsSnapshot {
+                                    snapshot_id: self.next_snapshot_id,
+                                    trigger_id: trigger.id,
+                                    trigger_s,
+                                    start_s: trigger_s - pre as f64 / self.sample_rate,
+                                    value,
+                                    trigger_counter: payload.counter,
+                                    samples_per_channel: 0,
+                                    channels: channels as u8,
+                                },
+                                values,
+                            },
+                            remaining: trigger.post,
+                        });
+                    }
+                }
+
+                let done = match trigger.capture.as_mut() {
+                    Some(capture) => {
+                        for (c, values) in capture.snapshot.values.iter_mut().enumerate() {
+                            values.push(sample[c] as f64);
+                        }
+                        capture.remaining -= 1;
+                        capture.remaining == 0
+                    }
+                    None => false,
+                };
+                if done {
+                    let mut snapshot = trigger.capture.take().unwrap().snapshot;
+                    snapshot.info.samples_per_channel =
+                        snapshot.values.first().map_or(0, |v| v.len()) as u32;
+                    trigger.armed_from = index + 1 + trigger.holdoff;
+                    if trigger.callback.is_some() {
+                        self.completed
+                            .push((trigger.callback, trigger.user_data, snapshot.info));
+                    }
+                    if self.snapshots.len() == MAX_SNAPSHOTS {
+                        self.snapshots.remove(0);
+                    }
+                    self.snapshots.push(snapshot);
+                }
+            }
+
+            if history_length > 0 {
+                self.history.push_back(sample);
+                while self.history.len() > history_length {
+                    self.history.pop_front();
+                }
+            }
+            self.sample_index += 1;
+        }
+    }
+
+    /// Snapshots completed since the last call whose trigger has a callback
+    pub fn take_completed(&mut self) -> Vec<(QsTriggerCallback, usize, QsSnapshot)> {
+        core::mem::take(&mut self.completed)
+    }
+}
+
+/// Runs the callbacks of snapshots completed by `qs_add_signals`, which must
+/// not hold the measurement lock so that callbacks may query the measurement
+pub(crate) fn notify(measurement_id: u32, completed: Vec<(QsTriggerCallback, usize, QsSnapshot)>) {
+    for (callback, user_data, snapshot) in completed {
+        if let Some(callback) = callback {
+            callback(measurement_id, &snapshot, user_data as *mut c_void);
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn qs_default_trigger_config(config: *mut QsTriggerConfig) -> bool {
+    if config.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    unsafe {
+        core::ptr::write(config, QsTriggerConfig::default());
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_add_trigger(
+    measurement_id: u32,
+    config: *const QsTriggerConfig,
+    callback: QsTriggerCallback,
+    user_data: *mut c_void,
+) -> u32 {
+    let rw_measurement = match find_measurement_by_id(measurement_id) {
+        Some(rwm) => rwm,
+        None => {
+            push_error("No measurement exists for the given id");
+            return 0;
+        }
+    };
+    let config = match unsafe { config.as_ref() } {
+        Some(config) => *config,
+        None => QsTriggerConfig::default(),
+    };
+    let mut measurement_guard = rw_measurement.measurement.write();
+    let measurement = &mut *measurement_guard;
+    if config.channel >= min(measurement.active_channels, 8) {
+        push_error("Triggers are limited to the physical channels of the measurement");
+        return 0;
+    }
+    let result = measurement.sample_rate().and_then(|sample_rate| {
+        let triggers = &mut measurement.triggers;
+        let trigger = Trigger::new(
+            triggers.next_trigger_id + 1,
+            &config,
+            sample_rate,
+            callback,
+            user_data as usize,
+        )?;
+        triggers.sample_rate = sample_rate;
+        triggers.next_trigger_id += 1;
+        triggers.triggers.push(trigger);
+        Ok(triggers.next_trigger_id)
+    });
+    match result {
+        Ok(trigger_id) => trigger_id,
+        Err(err) => {
+            push_error(err);
+            0
+        }
+    }
+}
+
+/// Removes a trigger, discarding any capture in progress but keeping its snapshots
+#[no_mangle]
+pub extern "C" fn qs_remove_trigger(measurement_id: u32, trigger_id: u32) -> bool {
+    let rw_measurement = match find_measurement_by_id(measurement_id) {
+        Some(rwm) => rwm,
+        None => {
+            push_error("No measurement exists for the given id");
+            return false;
+        }
+    };
+    let mut measurement_guard = rw_measurement.measurement.write();
+    let triggers = &mut measurement_guard.triggers.triggers;
+    match triggers.iter().position(|t| t.id == trigger_id) {
+        Some(position) => {
+            triggers.remove(position);
+            true
+        }
+        None => {
+            push_error("No trigger exists for the given id");
+            false
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn qs_snapshots(
+    measurement_id: u32,
+    snapshots: *mut QsSnapshot,
+    num_snapshots: *mut u32,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        let infos = measurement
+            .triggers
+            .snapshots
+            .iter()
+            .map(|s| s.info)
+            .collect::<Vec<_>>();
+        write_buffers(&[(&infos[..], snapshots)], num_snapshots)
+    });
+    report(result)
+}
+
+#[no_mangle]
+pub extern "C" fn qs_snapshot_signals(
+    measurement_id: u32,
+    snapshot_id: u32,
+    channel_data: *mut *mut f64,
+    num_samples_per_channel: *mut u32,
+) -> bool {
+    let result = with_measurement(measurement_id, |measurement| {
+        if channel_data.is_null() {
+            return Err("Null buffer passed to the library");
+        }
+        let snapshot = measurement
+            .triggers
+            .snapshots
+            .iter()
+            .find(|s| s.info.snapshot_id == snapshot_id)
+            .ok_or("No snapshot exists for the given id")?;
+        let outputs = snapshot
+            .values
+            .iter()
+            .enumerate()
+            .map(|(c, values)| (&values[..], unsafe { *channel_data.add(c) }))
+            .collect::<Vec<_>>();
+        write_buffers(&outputs, num_samples_per_channel)
+    });
+    report(result)
+}
+
+#[no_mangle]
+pub extern "C" fn qs_clear_snapshots(measurement_id: u32) -> bool {
+    let rw_measurement = match find_measurement_by_id(measurement_id) {
+        Some(rwm) => rwm,
+        None => {
+            push_error("No measurement exists for the given id");
+            return false;
+        }
+    };
+    let mut measurement_guard = rw_measurement.measurement.write();
+    measurement_guard.triggers.snapshots.clear();
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{add_samples, encode_payload};
+    use assert_approx_eq::assert_approx_eq;
+    use core::f64::consts::PI;
+    use core::sync::atomic::AtomicU32;
+
+    extern "C" fn count_snapshots(
+        measurement_id: u32,
+        snapshot: *const QsSnapshot,
+        user_data: *mut c_void,
+    ) {
+        // The measurement is unlocked, so it can be queried from the callback
+        let mut snapshots = [QsSnapshot::default(); 4];
+        let mut num_snapshots = 4;
+        assert!(qs_snapshots(
+            measurement_id,
+            snapshots.as_mut_ptr(),
+            &mut num_snapshots
+        ));
+        let snapshot = unsafe { &*snapshot };
+        assert_eq!(snapshot.snapshot_id, num_snapshots);
+        let calls = unsafe { &*(user_data as *const AtomicU32) };
+        calls.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn level_trigger_captures_context() {
+        let measurement_id = qs_create_measurement(2);
+        assert!(qs_set_sample_rate(measurement_id, 100.0, 1.0));
+        let calls = AtomicU32::new(0);
+        let mut config = QsTriggerConfig::default();
+        assert!(qs_default_trigger_config(&mut config));
+        config.level = 1000.0;
+        config.pre_s = 0.5;
+        config.post_s = 1.0;
+        config.holdoff_s = 2.0;
+        let trigger_id = qs_add_trigger(
+            measurement_id,
+            &config,
+            Some(count_snapshots),
+            &calls as *const AtomicU32 as *mut c_void,
+        );
+        assert_eq!(trigger_id, 1);
+        config.channel = 2;
+        assert_eq!(
+            qs_add_trigger(measurement_id, &config, None, core::ptr::null_mut()),
+            0
+        );
+
+        // Steps up at 3 s, 4 s and 8 s, where the second is within the holdoff
+        let samples = (0..1000)
+            .map(|i| {
+                let high = [300..350, 400..450, 800..850]
+                    .iter()
+                    .any(|r| r.contains(&i));
+                vec![if high { 2000 } else { 0 }, i as i16]
+            })
+            .collect::<Vec<_>>();
+        add_samples(measurement_id, &samples);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let mut snapshots = [QsSnapshot::default(); 4];
+        let mut num_snapshots = 4;
+        assert!(qs_snapshots(
+            measurement_id,
+            snapshots.as_mut_ptr(),
+            &mut num_snapshots
+        ));
+        assert_eq!(num_snapshots, 2);
+        assert_eq!(snapshots[0].trigger_id, trigger_id);
+        assert_eq!(snapshots[0].trigger_s, 3.0);
+        assert_eq!(snapshots[0].start_s, 2.5);
+        assert_eq!(snapshots[0].samples_per_channel, 150);
+        assert_eq!(snapshots[0].channels, 2);
+        assert_eq!(snapshots[1].trigger_s, 8.0);
+
+        let mut bufs = vec![[0.0f64; 150]; 2];
+        let mut channel_data = bufs.iter_mut().map(|b| b.as_mut_ptr()).collect::<Vec<_>>();
+        let mut num_samples = 150;
+        assert!(qs_snapshot_signals(
+            measurement_id,
+            snapshots[1].snapshot_id,
+            channel_data.as_mut_ptr(),
+            &mut num_samples
+        ));
+        assert_eq!(num_samples, 150);
+        assert_eq!(bufs[0][49], 0.0);
+        assert_eq!(bufs[0][50], 2000.0);
+        assert_eq!(bufs[1][0], 750.0);
+        assert_eq!(bufs[1][149], 899.0);
+
+        // Removing the trigger keeps its snapshots until they are cleared
+        assert!(qs_remove_trigger(measurement_id, trigger_id));
+        assert!(!qs_remove_trigger(measurement_id, trigger_id));
+        add_samples(measurement_id, &samples);
+        assert!(qs_snapshots(
+            measurement_id,
+            snapshots.as_mut_ptr(),
+            &mut num_snapshots
+        ));
+        assert_eq!(num_snapshots, 2);
+        assert!(qs_clear_snapshots(measurement_id));
+        assert!(qs_snapshots(
+            measurement_id,
+            snapshots.as_mut_ptr(),
+            &mut num_snapshots
+        ));
+        assert_eq!(num_snapshots, 0);
+
+        qs_drop_measurement(measurement_id);
+    }
+
+    #[test]
+    fn trigger_times_follow_the_counter_order() {
+        let measurement_id = qs_create_measurement(1);
+        assert!(qs_set_sample_rate(measurement_id, 100.0, 1.0));
+        let config = QsTriggerConfig {
+            level: 1000.0,
+            pre_s: 0.1,
+            post_s: 0.1,
+            ..Default::default()
+        };
+        assert_eq!(
+            qs_add_trigger(measurement_id, &config, None, core::ptr::null_mut()),
+            1
+        );
+
+        // The payload holding the step arrives before the one preceding it
+        let quiet = vec![vec![0]; 50];
+        let step = (0..50)
+            .map(|i| vec![if i >= 10 { 2000 } else { 0 }])
+            .collect::<Vec<_>>();
+        let mut snapshots = [QsSnapshot::default(); 1];
+        for (counter, samples, trigger_s) in [
+            (0, &quiet, None),
+            (2, &step, Some(0.6)),
+            (1, &quiet, Some(1.1)),
+        ]
+        .iter()
+        {
+            let raw_payload = encode_payload(*counter, samples);
+            qs_add_signals(
+                measurement_id,
+                raw_payload.as_ptr(),
+                raw_payload.len() as u16,
+            );
+            let mut num_snapshots = 1;
+            assert!(qs_snapshots(
+                measurement_id,
+                snapshots.as_mut_ptr(),
+                &mut num_snapshots
+            ));
+            if let Some(trigger_s) = trigger_s {
+                assert_approx_eq!(snapshots[0].trigger_s, trigger_s);
+                assert_approx_eq!(snapshots[0].start_s, trigger_s - 0.1);
+                assert_eq!(snapshots[0].trigger_counter, 2);
+            }
+        }
+
+        // The triggering sample is at the same time in the measurement
+        let values =
+            with_measurement(measurement_id, |m| m.channel_range(0, 1.085, 1.115)).unwrap();
+        assert_eq!(values, vec![0.0, 2000.0, 2000.0]);
+
+        qs_drop_measurement(measurement_id);
+    }
+
+    #[test]
+    fn band_trigger_ignores_slow_swings() {
+        let measurement_id = qs_create_measurement(1);
+        assert!(qs_set_sample_rate(measurement_id, 200.0, 1.0));
+        let config = QsTriggerConfig {
+            kind: QsTriggerKind::BandRms as u8,
+            level: 100.0,
+            band_low_hz: 5.0,
+            band_high_hz: 20.0,
+            rms_window_s: 0.25,
+            ..Default::default()
+        };
+        assert_eq!(
+            qs_add_trigger(measurement_id, &config, None, core::ptr::null_mut()),
+            1
+        );
+
+        // A large breathing-like swing with a 10 Hz tremor burst from 12 s to 14 s
+        let signal = |sample_rate: f64| {
+            (0..(20.0 * sample_rate) as usize)
+                .map(|i| {
+                    let t = i as f64 / sample_rate;
+                    let burst = if (12.0..14.0).contains(&t) {
+                        400.0
+                    } else {
+                        0.0
+                    };
+                    let v = 5000.0
+                        + 3000.0 * libm::sin(2.0 * PI * 0.25 * t)
+                        + burst * libm::sin(2.0 * PI * 10.0 * t);
+                    vec![v as i16]
+                })
+                .collect::<Vec<_>>()
+        };
+        add_samples(measurement_id, &signal(200.0));
+
+        let mut snapshots = [QsSnapshot::default(); 4];
+        let mut num_snapshots = 4;
+        assert!(qs_snapshots(
+            measurement_id,
+            snapshots.as_mut_ptr(),
+            &mut num_snapshots
+        ));
+        assert_eq!(num_snapshots, 1);
+        assert!(snapshots[0].trigger_s > 12.0 && snapshots[0].trigger_s < 12.25);
+        assert!(snapshots[0].value >= 100.0);
+        assert_eq!(snapshots[0].samples_per_channel, 800);
+        let trigger_s = snapshots[0].trigger_s;
+
+        // The band no longer fits below the Nyquist rate, so the rate is kept
+        assert!(!qs_set_sample_rate(measurement_id, 30.0, 1.0));
+        assert_eq!(
+            with_measurement(measurement_id, |m| m.sample_rate()),
+            Ok(200.0)
+        );
+
+        // Doubling the rate rebuilds the trigger and halves the times of earlier samples
+        assert!(qs_set_sample_rate(measurement_id, 400.0, 1.0));
+        for (i, chunk) in signal(400.0).chunks(100).enumerate() {
+            let raw_payload = encode_payload(40 + i as u32, chunk);
+            qs_add_signals(
+                measurement_id,
+                raw_payload.as_ptr(),
+                raw_payload.len() as u16,
+            );
+        }
+        let mut num_snapshots = 4;
+        assert!(qs_snapshots(
+            measurement_id,
+            snapshots.as_mut_ptr(),
+            &mut num_snapshots
+        ));
+        assert_eq!(num_snapshots, 2);
+        assert_approx_eq!(snapshots[0].trigger_s, trigger_s / 2.0);
+        assert!(snapshots[1].trigger_s > 22.0 && snapshots[1].trigger_s < 22.25);
+        assert_eq!(snapshots[1].samples_per_channel, 1600);
+
+        qs_drop_measurement(measurement_id);
+    }
+}
@@ -0,0 +1,340 @@
+use super::*;
+use crate::pipeline::{add_stage, Stage};
+use core::f64::consts::FRAC_1_SQRT_2;
+
+/// Daubechies scaling filters with 1 to 4 vanishing moments, db1 is the Haar wavelet
+const DAUBECHIES: [&[f64]; 4] = [
+    &[FRAC_1_SQRT_2, FRAC_1_SQRT_2],
+    &[
+        0.48296291314469025,
+        0.836516303737469,
+        0.22414386804185735,
+        -0.12940952255092145,
+    ],
+    &[
+        0.3326705529509569,
+        0.8068915093133388,
+        0.4598775021193313,
+        -0.13501102001039084,
+        -0.08544127388224149,
+        0.035226291882100656,
+    ],
+    &[
+        0.23037781330885523,
+        0.7148465705525415,
+        0.6308807679295904,
+        -0.02798376941698385,
+        -0.18703481171888114,
+        0.030841381835986965,
+        0.032883011666982945,
+        -0.010597401784997278,
+    ],
+];
+
+/// Median absolute deviation of gaussian noise relative to its standard deviation
+const MAD_NORMAL: f64 = 0.6745;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QsThresholdRule {
+    /// Shrinks detail coefficients toward zero by the threshold
+    Soft = 0,
+    /// Zeroes detail coefficients below the threshold and keeps the rest
+    Hard = 1,
+}
+
+impl QsThresholdRule {
+    pub(crate) fn from_u8(value: u8) -> Option<QsThresholdRule> {
+        match value {
+            0 => Some(QsThresholdRule::Soft),
+            1 => Some(QsThresholdRule::Hard),
+            _ => None,
+        }
+    }
+}
+
+/// Settings for denoising by thresholding discrete wavelet detail coefficients
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QsWaveletConfig {
+    /// Vanishing moments of the Daubechies wavelet, from 1 to 4
+    pub order: u8,
+    /// Decomposition levels, reduced when the channel is too short
+    pub levels: u8,
+    /// Multiplier of the universal threshold
+    pub threshold_scale: f64,
+    pub rule: u8,
+}
+
+impl Default for QsWaveletConfig {
+    fn default() -> Self {
+        QsWaveletConfig {
+            order: 4,
+            levels: 5,
+            threshold_scale: 1.0,
+            rule: QsThresholdRule::Soft as u8,
+        }
+    }
+}
+
+impl QsWaveletConfig {
+    pub(crate) fn validate(&self) -> Result<QsThresholdRule, &'static str> {
+        if !(1..=DAUBECHIES.len() as u8).contains(&self.order) {
+            return Err("Daubechies wavelets are available with 1 to 4 vanishing moments");
+        }
+        if self.levels == 0 || self.levels > 16 {
+            return Err("Wavelet levels must be between 1 and 16");
+        }
+        if !(self.threshold_scale >= 0.0 && self.threshold_scale.is_finite()) {
+            return Err("Wavelet threshold scale must be a non-negative number");
+        }
+        QsThresholdRule::from_u8(self.rule).ok_or("Unknown wavelet threshold rule")
+    }
+}
+
+/// One periodized analysis step, returning the approximation and detail halves
+fn analyze(filter: &[f64], values: &[f64]) -> (Vec<f64>, Vec<f64>) {
+    let n = values.len();
+    let length = filter.len();
+    (0..n / 2)
+        .map(|k| {
+            (0..length).fold((0.0, 0.0), |(a, d), m| {
+                let x = values[(2 * k + m) % n];
+                let high = filter[length - 1 - m] * if m % 2 == 0 { 1.0 } else { -1.0 };
+                (a + filter[m] * x, d + high * x)
+            })
+        })
+        .unzip()
+}
+
+/// Inverts `analyze` by applying the transpose of the orthogonal step
+fn synthesize(filter: &[f64], approximation: &[f64], detail: &[f64]) -> Vec<f64> {
+    let n = 2 * approximation.len();
+    let length = filter.len();
+    let mut values = vec![0.0; n];
+    for (k, (a, d)) in approximation.iter().zip(detail.iter()).enumerate() {
+        for m in 0..length {
+            let high = filter[length - 1 - m] * if m % 2 == 0 { 1.0 } else { -1.0 };
+            values[(2 * k + m) % n] += filter[m] * a + high * d;
+        }
+    }
+    values
+}
+
+/// Thresholds the detail coefficients at each level against the universal
+/// threshold, estimating the noise from the finest level. The channel is
+/// mirrored before the periodized transform so its ends join smoothly.
+pub(crate) fn denoise(config: &QsWaveletConfig, values: &mut [f64]) -> Result<(), &'static str> {
+    let rule = config.validate()?;
+    require_finite(values)?;
+    let filter = DAUBECHIES[config.order as usize - 1];
+    let n = values.len();
+    if n < 2 * filter.len() {
+        return Ok(());
+    }
+
+    let mut levels = 0;
+    while levels < config.levels as usize && (2 * n) >> (levels + 1) >= filter.len() {
+        levels += 1;
+    }
+    let block = 1 << levels;
+    let mut extended = values.to_vec();
+    extended.extend(values.iter().rev());
+    let padded = extended.len() + (block - extended.len() % block) % block;
+    for i in extended.len()..padded {
+        extended.push(extended[i % (2 * n)]);
+    }
+
+    let mut approximation = extended;
+    let mut details = Vec::with_capacity(levels);
+    for _ in 0..levels {
+        let (a, d) = analyze(filter, &approximation);
+        approximation = a;
+        details.push(d);
+    }
+
+    let mut finest = details[0]
+        .iter()
+        .map(|d| libm::fabs(*d))
+        .collect::<Vec<_>>();
+    let middle = finest.len() / 2;
+    let sigma = *finest.select_nth_unstable_by(middle, f64::total_cmp).1 / MAD_NORMAL;
+    let threshold = config.threshold_scale * sigma * libm::sqrt(2.0 * libm::log(padded as f64));
+    for d in details.iter_mut().flat_map(|level| level.iter_mut()) {
+        let magnitude = libm::fabs(*d);
+        *d = match rule {
+            _ if magnitude <= threshold => 0.0,
+            QsThresholdRule::Soft => libm::copysign(magnitude - threshold, *d),
+            QsThresholdRule::Hard => *d,
+        };
+    }
+
+    for detail in details.iter().rev() {
+        approximation = synthesize(filter, &approximation, detail);
+    }
+    values.copy_from_slice(&approximation[..n]);
+    Ok(())
+}
+
+#[no_mangle]
+pub extern "C" fn qs_default_wavelet_config(config: *mut QsWaveletConfig) -> bool {
+    if config.is_null() {
+        push_error("Null buffer passed to the library");
+        return false;
+    }
+    unsafe {
+        core::ptr::write(config, QsWaveletConfig::default());
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn qs_pipeline_add_wavelet_denoise(
+    pipeline_id: u32,
+    channel_mask: u32,
+    config: *const QsWaveletConfig,
+) -> bool {
+    let config = match unsafe { config.as_ref() } {
+        Some(config) => *config,
+        None => QsWaveletConfig::default(),
+    };
+    if let Err(err) = config.validate() {
+        push_error(err);
+        return false;
+    }
+    add_stage(
+        pipeline_id,
+        Stage::Denoise {
+            channel_mask,
+            config,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{cascade, Biquad, BiquadState};
+    use crate::tests::measurement_with_samples;
+    use core::f64::consts::PI;
+
+    /// A clean signal with sharp features and the same signal with noise added
+    fn noisy(samples: usize) -> (Vec<f64>, Vec<f64>) {
+        let clean = (0..samples)
+            .map(|i| {
+                let t = i as f64 / 250.0;
+                let beat = libm::exp(-libm::pow((t % 1.0 - 0.5) / 0.04, 2.0));
+                300.0 * libm::sin(2.0 * PI * 0.25 * t) + 1000.0 * beat
+            })
+            .collect::<Vec<f64>>();
+        let mut rng = XorShiftRng::seed_from_u64(17);
+        let noisy = clean
+            .iter()
+            .map(|v| v + 400.0 * (rng.next_u32() as f64 / u32::MAX as f64 - 0.5))
+            .collect();
+        (clean, noisy)
+    }
+
+    fn rms_error(a: &[f64], b: &[f64]) -> f64 {
+        let sum = a
+            .iter()
+            .zip(b.iter())
+            .map(|(x, y)| (x - y) * (x - y))
+            .sum::<f64>();
+        libm::sqrt(sum / a.len() as f64)
+    }
+
+    #[test]
+    fn reconstructs_and_denoises() {
+        let (clean, noisy) = noisy(2501);
+        for order in 1..=4 {
+            // Without a threshold the transform is inverted exactly
+            let mut values = noisy.clone();
+            let config = QsWaveletConfig {
+                order,
+                threshold_scale: 0.0,
+                ..Default::default()
+            };
+            denoise(&config, &mut values).unwrap();
+            assert!(rms_error(&values, &noisy) < 1e-6);
+
+            let mut values = noisy.clone();
+            let config = QsWaveletConfig {
+                order,
+                ..Default::default()
+            };
+            denoise(&config, &mut values).unwrap();
+            assert!(rms_error(&values, &clean) < 0.75 * rms_error(&noisy, &clean));
+        }
+
+        let mut hard = noisy.clone();
+        let config = QsWaveletConfig {
+            rule: QsThresholdRule::Hard as u8,
+            ..Default::default()
+        };
+        denoise(&config, &mut hard).unwrap();
+        assert!(rms_error(&hard, &clean) < rms_error(&noisy, &clean));
+    }
+
+    #[test]
+    fn denoise_stage_runs_on_selected_channels() {
+        let (clean, noisy) = noisy(2500);
+        let samples = noisy
+            .iter()
+            .map(|v| vec![*v as i16, *v as i16])
+            .collect::<Vec<_>>();
+        let measurement_id = measurement_with_samples(&samples, 250.0);
+
+        let mut config = QsWaveletConfig::default();
+        assert!(qs_default_wavelet_config(&mut config));
+        let pipeline_id = qs_create_pipeline();
+        assert!(qs_pipeline_add_wavelet_denoise(pipeline_id, 0b01, &config));
+        config.order = 9;
+        assert!(!qs_pipeline_add_wavelet_denoise(pipeline_id, 0b01, &config));
+        assert!(qs_attach_pipeline(measurement_id, pipeline_id));
+
+        let mut bufs = vec![[0.0f64; 2500]; 2];
+        let mut channel_data = bufs
+            .iter_mut()
+            .map(|b| b.as_mut_ptr() as *mut core::ffi::c_void)
+            .collect::<Vec<_>>();
+        let mut num_samples = 2500;
+        assert!(qs_export_signals(
+            measurement_id,
+            0b11,
+            QsSampleType::F64 as u8,
+            QsLayout::Planar as u8,
+            core::ptr::null(),
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+        assert!(rms_error(&bufs[0], &clean) < 0.75 * rms_error(&bufs[1], &clean));
+
+        // A filter added after the denoise stage runs on the denoised samples
+        assert!(qs_pipeline_add_filter(
+            pipeline_id,
+            0b01,
+            QsFilterKind::LowPass as u8,
+            20.0,
+            0.707
+        ));
+        assert!(qs_attach_pipeline(measurement_id, pipeline_id));
+        let mut expected = noisy.iter().map(|v| *v as i16 as f64).collect::<Vec<_>>();
+        denoise(&QsWaveletConfig::default(), &mut expected).unwrap();
+        let low_pass = Biquad::design(QsFilterKind::LowPass, 250.0, 20.0, 0.707f32 as f64).unwrap();
+        cascade(&[low_pass], &mut [BiquadState::default()], &mut expected);
+        assert!(qs_export_signals(
+            measurement_id,
+            0b11,
+            QsSampleType::F64 as u8,
+            QsLayout::Planar as u8,
+            core::ptr::null(),
+            channel_data.as_mut_ptr(),
+            &mut num_samples,
+        ));
+        assert_eq!(bufs[0][..], expected[..]);
+
+        qs_drop_pipeline(pipeline_id);
+        qs_drop_measurement(measurement_id);
+    }
+}